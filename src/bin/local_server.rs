@@ -0,0 +1,140 @@
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use cat_gpt::slack_post_handler::dedup_store::InMemoryDedupStore;
+use cat_gpt::slack_post_handler::event_queue::LocalEventQueue;
+use cat_gpt::slack_post_handler::function_handler::function_handler;
+use cat_gpt::slack_post_handler::handle_queued_event::process_queued_job;
+use cat_gpt::slack_post_handler::handle_request::Parameters;
+use cat_gpt::slack_post_handler::parameter_provider::{FileParameterProvider, ParameterProvider};
+use cat_gpt::slack_post_handler::reply_store::InMemoryReplyStore;
+use cat_gpt::slack_post_handler::usage_ledger::{usage_store_from_env, UsageStore};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, Server};
+use lambda_http::Error;
+
+const DEFAULT_PORT: u16 = 3000;
+const DEFAULT_PARAMETERS_PATH: &str = "parameters.json";
+
+struct Options {
+    port: u16,
+    parameters_path: String,
+}
+
+// 引数 > 環境変数 > デフォルト値 の順に設定を決める
+// e.g. local_server --port 8080 --parameters ./parameters.json
+fn parse_options() -> Result<Options, Error> {
+    let mut port = match std::env::var("PORT") {
+        Ok(val) => val.parse()?,
+        Err(_) => DEFAULT_PORT,
+    };
+    let mut parameters_path = std::env::var("local_parameters_path")
+        .unwrap_or_else(|_| DEFAULT_PARAMETERS_PATH.to_string());
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--port" => port = args.next().ok_or("--port requires a value")?.parse()?,
+            "--parameters" => {
+                parameters_path = args.next().ok_or("--parameters requires a value")?
+            }
+            _ => return Err(format!("unknown argument: {}", arg).into()),
+        }
+    }
+
+    Ok(Options {
+        port,
+        parameters_path,
+    })
+}
+
+// hyperのリクエストをLambdaのリクエストに変換してfunction_handlerに渡す
+async fn serve(
+    req: Request<Body>,
+    parameters: Parameters,
+    event_queue: Arc<LocalEventQueue>,
+    dedup_store: Arc<InMemoryDedupStore>,
+    usage_store: Arc<dyn UsageStore>,
+) -> Result<Response<Body>, Error> {
+    let (parts, body) = req.into_parts();
+    let body_bytes = hyper::body::to_bytes(body).await?;
+    let body_str = String::from_utf8(body_bytes.to_vec())?;
+    let event = lambda_http::Request::from_parts(parts, lambda_http::Body::Text(body_str));
+
+    let (parts, body) = function_handler(
+        event,
+        parameters,
+        event_queue.as_ref(),
+        dedup_store.as_ref(),
+        usage_store.as_ref(),
+    )
+    .await?
+    .into_parts();
+    let body = match body {
+        lambda_http::Body::Empty => Body::empty(),
+        lambda_http::Body::Text(s) => Body::from(s),
+        lambda_http::Body::Binary(b) => Body::from(b),
+    };
+    Ok(Response::from_parts(parts, body))
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Error> {
+    tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::INFO)
+        .with_target(false)
+        .init();
+
+    let options = parse_options()?;
+    let parameters = FileParameterProvider::new(&options.parameters_path)
+        .get_parameters()
+        .await?;
+    let (event_queue, mut receiver) = LocalEventQueue::new();
+    // NOTE: usage_file_pathを指定すると、再起動しても使用量が残る
+    let usage_store: Arc<dyn UsageStore> = Arc::from(usage_store_from_env().await?);
+
+    // キューに積まれたイベントを同一プロセス内のworkerで処理する
+    let worker_parameters = parameters.clone();
+    let worker_usage_store = usage_store.clone();
+    let reply_store = Arc::new(InMemoryReplyStore::default());
+    tokio::spawn(async move {
+        while let Some(job) = receiver.recv().await {
+            let parameters = worker_parameters.clone();
+            let usage_store = worker_usage_store.clone();
+            let reply_store = reply_store.clone();
+            tokio::spawn(async move {
+                process_queued_job(job, parameters, usage_store.as_ref(), reply_store.as_ref())
+                    .await
+                    .unwrap_or_else(|e| {
+                        eprintln!("Error: {}", e);
+                    });
+            });
+        }
+    });
+
+    let event_queue = Arc::new(event_queue);
+    let dedup_store = Arc::new(InMemoryDedupStore::default());
+    let make_service = make_service_fn(move |_conn| {
+        let parameters = parameters.clone();
+        let event_queue = event_queue.clone();
+        let dedup_store = dedup_store.clone();
+        let usage_store = usage_store.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                serve(
+                    req,
+                    parameters.clone(),
+                    event_queue.clone(),
+                    dedup_store.clone(),
+                    usage_store.clone(),
+                )
+            }))
+        }
+    });
+
+    let addr = SocketAddr::from(([0, 0, 0, 0], options.port));
+    tracing::info!("listening on http://{}", addr);
+    Server::bind(&addr).serve(make_service).await?;
+    Ok(())
+}
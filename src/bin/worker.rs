@@ -0,0 +1,44 @@
+use cat_gpt::slack_post_handler::event_queue::QueuedJob;
+use cat_gpt::slack_post_handler::handle_queued_event::handle_queued_event;
+use cat_gpt::slack_post_handler::parameter_provider::{
+    parameter_provider_from_env, ParameterProvider,
+};
+use cat_gpt::slack_post_handler::reply_store::{reply_store_from_env, ReplyStore};
+use cat_gpt::slack_post_handler::usage_ledger::{usage_store_from_env, UsageStore};
+use lambda_runtime::{run, service_fn, Error, LambdaEvent};
+
+// ingress用のLambda関数から非同期に呼び出され、Slackイベントやスラッシュコマンドを処理する
+async fn function_handler(
+    event: LambdaEvent<QueuedJob>,
+    parameter_provider: &dyn ParameterProvider,
+    usage_store: &dyn UsageStore,
+    reply_store: &dyn ReplyStore,
+) -> Result<(), Error> {
+    handle_queued_event(event.payload, parameter_provider, usage_store, reply_store).await;
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Error> {
+    tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::INFO)
+        // disable printing the name of the module in every log line.
+        .with_target(false)
+        // disabling time is handy because CloudWatch will add the ingestion time.
+        .without_time()
+        .init();
+
+    let parameter_provider = parameter_provider_from_env()?;
+    let usage_store = usage_store_from_env().await?;
+    let reply_store = reply_store_from_env().await?;
+
+    run(service_fn(|event| {
+        function_handler(
+            event,
+            parameter_provider.as_ref(),
+            usage_store.as_ref(),
+            reply_store.as_ref(),
+        )
+    }))
+    .await
+}
@@ -1,9 +1,99 @@
 // URLs
-pub const CHAT_GPT_POST_URL: &str = "https://api.openai.com/v1/chat/completions";
+pub const OPENAI_API_BASE_URL: &str = "https://api.openai.com/v1";
 pub const SLACK_POST_URL: &str = "https://slack.com/api/chat.postMessage";
 pub const SLACK_UPDATE_URL: &str = "https://slack.com/api/chat.update";
+pub const SLACK_DELETE_URL: &str = "https://slack.com/api/chat.delete";
 pub const SLACK_GET_REPLIES_URL: &str = "https://slack.com/api/conversations.replies";
 pub const SLACK_GET_HISTORY_URL: &str = "https://slack.com/api/conversations.history";
+pub const SLACK_GET_MEMBERS_URL: &str = "https://slack.com/api/conversations.members";
+
+// Slackからのリクエストのtimestampとして許容する時刻のずれ(秒)
+pub const DEFAULT_SIGNATURE_TOLERANCE_SECS: i64 = 60 * 5;
+
+// Slackの1メッセージあたりの文字数
+// NOTE: 4000文字を超えると切り詰められるため余裕を持たせる
+pub const SLACK_MESSAGE_TEXT_LIMIT: usize = 3500;
+
+// Slack APIのレート制限時のリトライ設定
+pub const SLACK_MAX_RETRIES: u32 = 3;
+pub const SLACK_RETRY_BASE_WAIT: std::time::Duration = std::time::Duration::from_secs(1);
+pub const SLACK_RETRY_MAX_WAIT: std::time::Duration = std::time::Duration::from_secs(30);
+
+// ストリーミング中にSlackのメッセージを更新する間隔
+pub const SLACK_UPDATE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+pub const SLACK_UPDATE_MAX_INTERVAL: std::time::Duration = std::time::Duration::from_secs(10);
+
+// conversations.replies/historyの1ページあたりの取得件数
+pub const SLACK_PAGE_LIMIT: usize = 200;
+
+// conversations.repliesで取得するメッセージ数の上限
+// NOTE: 非常に長いスレッドでページを取得し続けないようにする
+pub const SLACK_MAX_REPLIES: usize = SLACK_PAGE_LIMIT * 5;
+
+// ChatGPTに送るコンテキストのトークン数の見積もり
+// NOTE: 返答用に残しておくトークン数。コンテキストウィンドウの半分を超える場合は半分にする
+pub const DEFAULT_COMPLETION_RESERVE_TOKENS: usize = 4096;
+// モデル名(前方一致)ごとのコンテキストウィンドウ
+// NOTE: tiktokenが知らないモデルや、tiktokenの値が古いモデルのみ
+pub const DEFAULT_CONTEXT_WINDOWS: [(&str, usize); 8] = [
+    ("gpt-4-turbo", 128_000),
+    ("gpt-4.1", 1_047_576),
+    ("gpt-5", 400_000),
+    ("o1", 200_000),
+    ("o1-mini", 128_000),
+    ("o3", 200_000),
+    ("o4-mini", 200_000),
+    ("claude", 200_000),
+];
+// NOTE: 不明なモデル(ローカルのモデルやAzureのデプロイ名など)の場合に仮定するコンテキストウィンドウ
+pub const DEFAULT_CONTEXT_WINDOW_TOKENS: usize = 32_768;
+// NOTE: 1メッセージごとに付くroleなどの分と、返答の先頭に付く分
+pub const MESSAGE_TOKEN_OVERHEAD: usize = 3;
+pub const REPLY_TOKEN_OVERHEAD: usize = 3;
+// NOTE: 画像は解像度によって変わるため、高解像度の512px四方1枚分で見積もる
+pub const IMAGE_TOKEN_ESTIMATE: usize = 765;
+// NOTE: これより短くしか残せない場合は、切り詰めずにメッセージごと捨てる
+pub const MIN_TRUNCATED_MESSAGE_TOKENS: usize = 64;
+pub const TRUNCATED_MESSAGE_MARKER: &str = "\n...(truncated)";
+
+// スレッドの要約
+// NOTE: 要約のために空けておくトークン数
+pub const SUMMARY_MAX_TOKENS: usize = 1024;
+// NOTE: 要約をキャッシュするスレッドの数
+pub const SUMMARY_CACHE_MAX_ENTRIES: usize = 1000;
+
+// toolの呼び出し
+// NOTE: toolの呼び出しを繰り返す回数の上限
+pub const MAX_TOOL_ROUNDS: usize = 5;
+pub const TOOL_RESULT_MAX_CHARS: usize = 4000;
+
+// Azure OpenAIのapi-versionのデフォルト値
+pub const AZURE_OPENAI_DEFAULT_API_VERSION: &str = "2024-10-21";
+
+// Anthropic Messages API
+pub const ANTHROPIC_API_BASE_URL: &str = "https://api.anthropic.com/v1";
+pub const ANTHROPIC_API_VERSION: &str = "2023-06-01";
+// NOTE: Anthropicではmax_tokensが必須のため、指定がない場合はこの値を使う
+pub const ANTHROPIC_DEFAULT_MAX_TOKENS: u32 = 4096;
+
+// 使用量の記録
+// NOTE: 日本時間の0時で日付を区切る
+pub const USAGE_DAY_OFFSET_SECS: i64 = 9 * 60 * 60;
+// NOTE: `/catgpt usage`で表示するユーザーの数
+pub const USAGE_REPORT_MAX_USERS: usize = 10;
+// 100万トークンあたりの料金(USD)。(モデル名の前方一致, 入力, 出力)
+// NOTE: 料金が変わった場合や他のモデルはパラメータのpricesで設定する
+pub const DEFAULT_MODEL_PRICES: [(&str, f64, f64); 6] = [
+    ("gpt-4o", 2.5, 10.0),
+    ("gpt-4o-mini", 0.15, 0.6),
+    ("gpt-4.1", 2.0, 8.0),
+    ("gpt-4.1-mini", 0.4, 1.6),
+    ("gpt-4.1-nano", 0.1, 0.4),
+    ("o4-mini", 1.1, 4.4),
+];
+
+// スレッド内で他のbotに続けて返答する回数の上限
+pub const DEFAULT_MAX_CONSECUTIVE_BOT_REPLIES: usize = 3;
 
 // ファイルタイプ
 pub const VALID_MIME_TYPES: [&str; 4] = ["image/png", "image/jpeg", "image/gif", "image/webp"];
@@ -17,6 +107,51 @@ pub const USAGE_LIMIT_MESSAGE: &str = "OpenAIの使用制限に達しました
 pub const INVALID_IMAGE_FORMAT: &str =
     "対応していないファイル形式ですにゃ。20MB以下のpng,jpeg,gif,webpのいずれかでお願いにゃ。";
 
+// スラッシュコマンドへの応答メッセージ
+pub const POLICY_DENIED_MESSAGE: &str = "ここでは返答できないことになっていますにゃ。めんご。";
+pub const SLASH_COMMAND_ACCEPTED_MESSAGE: &str =
+    "承りましたにゃ。チャンネルに返答するので少々お待ちくださいにゃ。";
+pub const SLASH_COMMAND_QUESTION_HEADER: &str = "さんからの質問ですにゃ。";
+pub const SLASH_COMMAND_HELP_MESSAGE: &str = "使い方ですにゃ。\n\
+• `/catgpt <質問>`: 質問への返答をチャンネルに投稿しますにゃ\n\
+• `/catgpt model`: 使用中のモデルを表示しますにゃ\n\
+• `/catgpt usage`: 今月の使用量と料金を表示しますにゃ\n\
+• `/catgpt help`: このヘルプを表示しますにゃ";
+
+// 組み込みのネコ型の人格の名前
+pub const DEFAULT_PERSONA_NAME: &str = "cat";
+
+// メッセージの先頭に書ける指定の使い方
+pub const MESSAGE_DIRECTIVES_HELP_MESSAGE: &str =
+    "メッセージの先頭にこんな指定が書けますにゃ。組み合わせもできますにゃ。\n\
+• `past10`: 過去のメッセージを10件まで参照しますにゃ\n\
+• `!fresh`: スレッドの履歴を参照せずに返答しますにゃ\n\
+• `!continue`: 長すぎて途中で止まった返答の続きを書きますにゃ\n\
+• `model:gpt-4o-mini`: 使うモデルを指定しますにゃ\n\
+• `temp:0.8`: temperatureを0〜2で指定しますにゃ\n\
+• `persona:reviewer`: 人格を切り替えますにゃ\n\
+• `!help`: この使い方を表示しますにゃ\n\
+`fresh`・`continue`・`help` は、その単語だけを送るときは `!` を省略できますにゃ";
+pub const UNKNOWN_DIRECTIVES_MESSAGE: &str =
+    "分からない指定がありましたにゃ。`help` で使い方を確認してにゃ: ";
+
+// 返答が途中で止まった場合に末尾に追記するメッセージ
+pub const LENGTH_LIMIT_NOTICE: &str =
+    "\n\n_返答が長すぎて途中で止まりましたにゃ。`continue` と返信すると続きを書きますにゃ。_";
+pub const CONTENT_FILTER_NOTICE: &str =
+    "\n\n_コンテンツフィルターにより返答が中断されましたにゃ。めんご。_";
+
+// 使用量の制限を超えた場合のメッセージ
+pub const QUOTA_REQUESTS_PER_MINUTE_MESSAGE: &str =
+    "ちょっと話しかけすぎですにゃ。少しだけ毛づくろいの時間をくださいにゃ。";
+pub const QUOTA_USER_TOKENS_PER_DAY_MESSAGE: &str =
+    "今日はもうたくさんおしゃべりしたので、お昼寝しますにゃ。また明日話しかけてにゃ。";
+pub const QUOTA_CHANNEL_TOKENS_PER_DAY_MESSAGE: &str =
+    "このチャンネルでは今日はもうたくさんおしゃべりしたので、お昼寝しますにゃ。また明日話しかけてにゃ。";
+
+// toolの実行中に表示するメッセージ
+pub const TOOL_RUNNING_MESSAGE: &str = ":hammer_and_wrench: 調べ物中ですにゃ...";
+
 // emoji
 pub const LOADING_EMOJI: &str = ":loading:";
 
@@ -40,3 +175,16 @@ If you use English, the ending of your word is \"meow\". \
 If your answer is specifically about programming, provide URL sources. \
 When you are done, type \":paw_prints:\". \
 Let's begin.";
+
+// スレッドの古いメッセージを要約する指示プロンプト
+pub const CHAT_GPT_SUMMARY_PROMPT: &str = "You summarize the earlier part of a Slack thread \
+between users and an AI assistant. \
+You are given the previous summary (if any) and the messages that follow it. \
+Write a concise summary that keeps facts, decisions, open questions, code snippets and names \
+that may be needed to continue the conversation. \
+Write in the language used in the thread. Do not exceed 300 words.";
+pub const SUMMARY_PREFIX: &str = "Summary of the earlier messages in this thread:\n";
+
+// 途中で止まった返答の続きを書かせる指示プロンプト
+pub const CHAT_GPT_CONTINUE_PROMPT: &str = "Your previous answer was cut off because it reached \
+the length limit. Continue it exactly where it stopped, without repeating what you have already written.";
@@ -1,18 +1,16 @@
-use crate::slack_post_handler::handle_request::handle_request;
-use lambda_http::{run, service_fn, Body, Error, Request, Response};
-pub mod constants;
-pub mod slack_post_handler;
+use cat_gpt::slack_post_handler::dedup_store::dedup_store_from_env;
+use cat_gpt::slack_post_handler::event_queue::LambdaEventQueue;
+use cat_gpt::slack_post_handler::function_handler::function_handler;
+use cat_gpt::slack_post_handler::handle_request::get_enviroment_variable;
+use cat_gpt::slack_post_handler::parameter_provider::parameter_provider_from_env;
+use cat_gpt::slack_post_handler::usage_ledger::usage_store_from_env;
+use lambda_http::{run, service_fn, Error, Request};
+use thiserror::Error;
 
-// slackからのリクエストを受け取る
-async fn function_handler(event: Request) -> Result<Response<Body>, Error> {
-    let response_body = handle_request(event).await;
-
-    let resp = Response::builder()
-        .status(200)
-        .header("content-type", "text/html")
-        .body(Body::from(response_body))
-        .map_err(Box::new)?;
-    Ok(resp)
+#[derive(Error, Debug)]
+enum MainError {
+    #[error("worker_function_name is not set")]
+    MissingWorkerFunctionName,
 }
 
 #[tokio::main]
@@ -25,5 +23,25 @@ async fn main() -> Result<(), Error> {
         .without_time()
         .init();
 
-    run(service_fn(function_handler)).await
+    // イベントの処理はworker用のLambda関数に任せる
+    let worker_function_name = get_enviroment_variable()?
+        .worker_function_name
+        .ok_or(MainError::MissingWorkerFunctionName)?;
+    let event_queue = LambdaEventQueue::new(&worker_function_name).await;
+    let parameter_provider = parameter_provider_from_env()?;
+    let dedup_store = dedup_store_from_env().await?;
+    let usage_store = usage_store_from_env().await?;
+
+    run(service_fn(|event: Request| async {
+        let parameters = parameter_provider.get_parameters().await?;
+        function_handler(
+            event,
+            parameters,
+            &event_queue,
+            dedup_store.as_ref(),
+            usage_store.as_ref(),
+        )
+        .await
+    }))
+    .await
 }
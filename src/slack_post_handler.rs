@@ -1,7 +1,29 @@
+pub mod access_policy;
+pub mod anthropic_provider;
 pub mod api_client;
+pub mod builtin_tools;
+pub mod channel_config;
 pub mod chat_gpt_query;
 pub mod chat_gpt_res_body;
+pub mod chat_provider;
+pub mod context_budget;
+pub mod dedup_store;
+pub mod event_queue;
+pub mod function_handler;
 pub mod handle_chat_gpt_response;
+pub mod handle_queued_event;
 pub mod handle_request;
+pub mod message_directives;
+pub mod message_splitter;
+pub mod parameter_provider;
+pub mod persona;
+pub mod quota;
+pub mod reply_store;
 pub mod slack_message;
+pub mod slash_command;
+pub mod sse_decoder;
+pub mod thread_summary;
+pub mod time;
+pub mod tool;
+pub mod usage_ledger;
 pub mod validate_slack_signature;
@@ -0,0 +1,190 @@
+use serde_derive::Deserialize;
+use thiserror::Error;
+
+use crate::constants::DEFAULT_MAX_CONSECUTIVE_BOT_REPLIES;
+
+use super::slack_message::SlackMessage;
+
+// パラメータの`policy`に書く、botが返答する範囲の設定
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct AccessPolicy {
+    // 返答するチャンネル。空の場合はすべてのチャンネルで返答する
+    // NOTE: DMには適用しない
+    #[serde(default)]
+    pub allowed_channels: Vec<String>,
+    // 返答しないチャンネル
+    #[serde(default)]
+    pub denied_channels: Vec<String>,
+    // 返答しないユーザーID、またはbot_id
+    #[serde(default)]
+    pub denied_users: Vec<String>,
+    // trueの場合はDMに返答しない
+    #[serde(default)]
+    pub block_direct_messages: bool,
+    // 返答する他のbotやアプリ連携のbot_idかapp_id。それ以外のbotには返答しない
+    #[serde(default)]
+    pub allowed_bots: Vec<String>,
+    // スレッド内で他のbotに続けて返答する回数の上限
+    pub max_consecutive_bot_replies: Option<usize>,
+}
+
+// 返答しない理由
+#[derive(Error, Debug, PartialEq)]
+pub enum PolicyDenial {
+    #[error("channel {0} is not in allowed_channels")]
+    ChannelNotAllowed(String),
+    #[error("channel {0} is in denied_channels")]
+    ChannelDenied(String),
+    #[error("user {0} is in denied_users")]
+    UserDenied(String),
+    #[error("direct messages are blocked")]
+    DirectMessageBlocked,
+    #[error("bot {0} is not in allowed_bots")]
+    BotNotAllowed(String),
+}
+
+impl AccessPolicy {
+    // 送信者(ユーザーIDやbot_id)とチャンネルが返答する範囲かどうか
+    pub fn check(
+        &self,
+        sender_ids: &[&str],
+        channel: &str,
+        is_direct_message: bool,
+    ) -> Result<(), PolicyDenial> {
+        if let Some(id) = sender_ids
+            .iter()
+            .find(|id| self.denied_users.iter().any(|u| u == *id))
+        {
+            return Err(PolicyDenial::UserDenied(id.to_string()));
+        }
+        if is_direct_message {
+            if self.block_direct_messages {
+                return Err(PolicyDenial::DirectMessageBlocked);
+            }
+            return Ok(());
+        }
+        if self.denied_channels.iter().any(|c| c == channel) {
+            return Err(PolicyDenial::ChannelDenied(channel.to_string()));
+        }
+        if !self.allowed_channels.is_empty() && !self.allowed_channels.iter().any(|c| c == channel)
+        {
+            return Err(PolicyDenial::ChannelNotAllowed(channel.to_string()));
+        }
+        Ok(())
+    }
+
+    pub fn check_message(&self, message: &SlackMessage) -> Result<(), PolicyDenial> {
+        // 他のbotやアプリ連携からのメッセージは、許可したもののみ返答する
+        // NOTE: bot同士で返答し合い続けるのを防ぐ
+        if message.is_from_bot() {
+            let is_allowed = [&message.bot_id, &message.app_id]
+                .into_iter()
+                .flatten()
+                .any(|id| self.allowed_bots.contains(id));
+            if !is_allowed {
+                let id = message.bot_id.clone().or(message.app_id.clone());
+                return Err(PolicyDenial::BotNotAllowed(id.unwrap_or_default()));
+            }
+        }
+        let sender_ids: Vec<&str> = std::iter::once(message.user.as_str())
+            .chain(message.bot_id.as_deref())
+            .collect();
+        self.check(
+            &sender_ids,
+            message.channel.as_deref().unwrap_or_default(),
+            message.is_direct_message(),
+        )
+    }
+
+    // スレッドの末尾で、botが他のbotに続けて返答した回数が上限に達しているかどうか
+    // NOTE: 人のメッセージがあれば数え直す
+    pub fn is_reply_loop(&self, messages: &[SlackMessage], bot_member_id: &str) -> bool {
+        let max_replies = self
+            .max_consecutive_bot_replies
+            .unwrap_or(DEFAULT_MAX_CONSECUTIVE_BOT_REPLIES);
+        let replies = messages
+            .iter()
+            .rev()
+            .take_while(|m| m.is_from(bot_member_id) || m.is_from_bot())
+            .filter(|m| m.is_from(bot_member_id))
+            .count();
+        replies >= max_replies
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check() {
+        let policy: AccessPolicy = serde_json::from_value(serde_json::json!({
+            "allowed_channels": ["C01", "C02"],
+            "denied_channels": ["C02"],
+            "denied_users": ["UGUEST", "B01"],
+            "block_direct_messages": true,
+        }))
+        .unwrap();
+
+        assert_eq!(policy.check(&["U01"], "C01", false), Ok(()));
+        assert_eq!(
+            policy.check(&["U01"], "C02", false),
+            Err(PolicyDenial::ChannelDenied("C02".into()))
+        );
+        assert_eq!(
+            policy.check(&["U01"], "C03", false),
+            Err(PolicyDenial::ChannelNotAllowed("C03".into()))
+        );
+        assert_eq!(
+            policy.check(&["U02", "B01"], "C01", false),
+            Err(PolicyDenial::UserDenied("B01".into()))
+        );
+        assert_eq!(
+            policy.check(&["U01"], "D01", true),
+            Err(PolicyDenial::DirectMessageBlocked)
+        );
+
+        // 許可していないbotには返答しない
+        let bot_message = |bot_id: &str| SlackMessage {
+            bot_id: Some(bot_id.into()),
+            channel: Some("C01".into()),
+            ..Default::default()
+        };
+        let policy: AccessPolicy =
+            serde_json::from_value(serde_json::json!({"allowed_bots": ["B02"]})).unwrap();
+        assert_eq!(
+            policy.check_message(&bot_message("B01")),
+            Err(PolicyDenial::BotNotAllowed("B01".into()))
+        );
+        assert_eq!(policy.check_message(&bot_message("B02")), Ok(()));
+
+        // 未設定の場合はすべて返答する
+        let policy = AccessPolicy::default();
+        assert_eq!(policy.check(&["U01"], "C03", false), Ok(()));
+        assert_eq!(policy.check(&["U01"], "D01", true), Ok(()));
+    }
+
+    #[test]
+    fn test_is_reply_loop() {
+        let message = |user: &str, bot_id: Option<&str>| SlackMessage {
+            user: user.into(),
+            bot_id: bot_id.map(|id| id.into()),
+            ..Default::default()
+        };
+        let policy: AccessPolicy =
+            serde_json::from_value(serde_json::json!({"max_consecutive_bot_replies": 2})).unwrap();
+        let mut messages = vec![
+            message("U01", None),
+            message("UBOT", Some("BBOT")),
+            message("UOTHER", Some("BOTHER")),
+            message("UBOT", Some("BBOT")),
+        ];
+        assert!(!policy.is_reply_loop(&messages[..3], "UBOT"));
+        messages.push(message("UOTHER", Some("BOTHER")));
+        assert!(policy.is_reply_loop(&messages, "UBOT"));
+
+        // 人が発言した場合は数え直す
+        messages.push(message("U01", None));
+        assert!(!policy.is_reply_loop(&messages, "UBOT"));
+    }
+}
@@ -0,0 +1,457 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use futures::{future, Stream, StreamExt};
+use reqwest::{header, Client, RequestBuilder, Response};
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
+use thiserror::Error;
+
+use crate::constants::{
+    ANTHROPIC_API_BASE_URL, ANTHROPIC_API_VERSION, ANTHROPIC_DEFAULT_MAX_TOKENS,
+};
+
+use super::chat_gpt_query::{ChatGptQuery, ChatGptQueryContentEnum, Role};
+use super::chat_gpt_res_body::{ChatGptChoice, ChatGptResBody, ChatGptUsage};
+use super::chat_provider::ChatProvider;
+use super::handle_chat_gpt_response::ChatGptResStream;
+use super::handle_request::ChatGptReqBody;
+use super::sse_decoder::sse_events;
+
+#[derive(Error, Debug)]
+pub enum AnthropicError {
+    #[error("Reading Stream Error: {0}")]
+    ReadingStream(String),
+    #[error("Invalid event: {0}, {1}")]
+    InvalidEvent(String, String),
+    #[error("Anthropic API error: {0}")]
+    ApiError(String),
+}
+
+// https://docs.anthropic.com/en/api/messages
+#[derive(Serialize, Debug, PartialEq)]
+struct AnthropicReqBody {
+    model: String,
+    max_tokens: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    system: Option<String>,
+    messages: Vec<AnthropicMessage>,
+    temperature: f32,
+    stream: bool,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+struct AnthropicMessage {
+    role: &'static str,
+    content: Vec<AnthropicContent>,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum AnthropicContent {
+    Text { text: String },
+    Image { source: ImageSource },
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+struct ImageSource {
+    #[serde(rename = "type")]
+    type_name: &'static str,
+    media_type: String,
+    data: String,
+}
+
+// ストリーミングで届くイベント
+#[derive(Deserialize, Debug)]
+struct AnthropicEvent {
+    #[serde(rename = "type")]
+    type_name: String,
+    delta: Option<AnthropicDelta>,
+    // message_startの場合
+    message: Option<AnthropicMessageStart>,
+    // message_deltaの場合
+    usage: Option<AnthropicUsage>,
+    error: Option<Value>,
+}
+
+#[derive(Deserialize, Debug)]
+struct AnthropicDelta {
+    text: Option<String>,
+    stop_reason: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct AnthropicMessageStart {
+    usage: Option<AnthropicUsage>,
+}
+
+#[derive(Deserialize, Debug)]
+struct AnthropicUsage {
+    #[serde(default)]
+    input_tokens: u64,
+    #[serde(default)]
+    output_tokens: u64,
+}
+
+// stop_reasonをChatGPTのfinish_reasonに合わせる
+fn to_finish_reason(stop_reason: &str) -> &str {
+    match stop_reason {
+        "end_turn" | "stop_sequence" => "stop",
+        "max_tokens" => "length",
+        "refusal" => "content_filter",
+        "tool_use" => "tool_calls",
+        other => other,
+    }
+}
+
+// 終了理由と使用量のみのchunk
+fn to_res_body(stop_reason: Option<&str>, usage: ChatGptUsage) -> ChatGptResBody {
+    ChatGptResBody {
+        choices: vec![ChatGptChoice {
+            finish_reason: stop_reason.map(|r| to_finish_reason(r).to_string()),
+            ..Default::default()
+        }],
+        usage: Some(usage),
+    }
+}
+
+// ストリーミングしない場合のレスポンス
+#[derive(Deserialize, Debug)]
+struct AnthropicResBody {
+    content: Vec<AnthropicResContent>,
+    usage: Option<AnthropicUsage>,
+}
+
+#[derive(Deserialize, Debug)]
+struct AnthropicResContent {
+    text: Option<String>,
+}
+
+// Anthropic Messages API
+// NOTE: toolの呼び出しには対応していないため、toolの定義は送らない
+pub struct AnthropicProvider {
+    base_url: String,
+    api_key: String,
+    max_tokens: u32,
+}
+
+impl AnthropicProvider {
+    pub fn new(api_key: &str, base_url: Option<&str>, max_tokens: Option<u32>) -> Self {
+        Self {
+            base_url: base_url.unwrap_or(ANTHROPIC_API_BASE_URL).to_string(),
+            api_key: api_key.to_string(),
+            max_tokens: max_tokens.unwrap_or(ANTHROPIC_DEFAULT_MAX_TOKENS),
+        }
+    }
+
+    // ChatGPTの形式のリクエストをAnthropicの形式に変換する
+    fn to_anthropic_request(&self, request_body: &ChatGptReqBody) -> AnthropicReqBody {
+        // system promptや要約はsystemにまとめる
+        let system: Vec<&str> = request_body
+            .messages()
+            .iter()
+            .filter(|m| matches!(m.role, Role::System))
+            .map(|m| m.text())
+            .collect();
+
+        let mut messages: Vec<AnthropicMessage> = vec![];
+        for query in request_body.messages() {
+            let role = match query.role {
+                Role::User => "user",
+                Role::Assistant => "assistant",
+                _ => continue,
+            };
+            let content = to_anthropic_contents(query);
+            if content.is_empty() {
+                continue;
+            }
+            // NOTE: 同じroleが続く場合は1つのメッセージにまとめる
+            match messages.last_mut() {
+                Some(last) if last.role == role => last.content.extend(content),
+                _ => messages.push(AnthropicMessage { role, content }),
+            }
+        }
+        // NOTE: 最初のメッセージはuserである必要がある
+        let first_user = messages
+            .iter()
+            .position(|m| m.role == "user")
+            .unwrap_or(messages.len());
+        messages.drain(..first_user);
+
+        AnthropicReqBody {
+            model: request_body.model().to_string(),
+            max_tokens: self.max_tokens,
+            system: (!system.is_empty()).then(|| system.join("\n\n")),
+            messages,
+            // NOTE: Anthropicのtemperatureは0〜1
+            temperature: request_body.temperature().clamp(0.0, 1.0),
+            stream: request_body.stream(),
+        }
+    }
+}
+
+fn to_anthropic_contents(query: &ChatGptQuery) -> Vec<AnthropicContent> {
+    let text_content = |text: &str| {
+        (!text.trim().is_empty()).then(|| AnthropicContent::Text {
+            text: text.to_string(),
+        })
+    };
+    match &query.content {
+        ChatGptQueryContentEnum::Text(text) => text_content(text).into_iter().collect(),
+        ChatGptQueryContentEnum::QueryContent(contents) => contents
+            .iter()
+            .filter_map(|c| match (c.text(), c.image_url()) {
+                (Some(text), _) => text_content(text),
+                (_, Some(url)) => {
+                    to_image_source(url).map(|source| AnthropicContent::Image { source })
+                }
+                _ => None,
+            })
+            .collect(),
+    }
+}
+
+// "data:image/png;base64,xxxx"をmedia_typeとdataに分ける
+fn to_image_source(url: &str) -> Option<ImageSource> {
+    let (header, data) = url.strip_prefix("data:")?.split_once(',')?;
+    let media_type = header.strip_suffix(";base64")?;
+    Some(ImageSource {
+        type_name: "base64",
+        media_type: media_type.to_string(),
+        data: data.to_string(),
+    })
+}
+
+// AnthropicのストリームをChatGptResBodyのストリームに変換する
+// NOTE: テキストの差分(content_block_delta)と終了理由、使用量を取り出し、message_stopを受け取った時点で終了する
+pub fn anthropic_res_stream<S, B, E>(byte_stream: S) -> ChatGptResStream
+where
+    S: Stream<Item = Result<B, E>> + Send + 'static,
+    B: AsRef<[u8]> + Send + 'static,
+    E: std::fmt::Display + Send + 'static,
+{
+    sse_events(byte_stream)
+        .map(|event| {
+            let event = event.map_err(|e| AnthropicError::ReadingStream(e.to_string()))?;
+            let json: AnthropicEvent = serde_json::from_str(&event.data)
+                .map_err(|e| AnthropicError::InvalidEvent(e.to_string(), event.data.clone()))?;
+            Ok(json)
+        })
+        .take_while(|event| {
+            future::ready(!matches!(event, Ok(event) if event.type_name == "message_stop"))
+        })
+        .filter_map(|event| {
+            future::ready(match event {
+                Ok(event) => match event.type_name.as_str() {
+                    "content_block_delta" => event
+                        .delta
+                        .and_then(|d| d.text)
+                        .map(|text| Ok(ChatGptResBody::from_text(&text))),
+                    // NOTE: 入力のトークン数はmessage_startで、出力のトークン数はmessage_deltaで累計が届くため、
+                    // 合算しても重複しないようにそれぞれ片方のみを取り出す
+                    "message_start" => Some(Ok(to_res_body(
+                        None,
+                        ChatGptUsage {
+                            prompt_tokens: event
+                                .message
+                                .and_then(|m| m.usage)
+                                .map_or(0, |u| u.input_tokens),
+                            completion_tokens: 0,
+                        },
+                    ))),
+                    "message_delta" => Some(Ok(to_res_body(
+                        event.delta.as_ref().and_then(|d| d.stop_reason.as_deref()),
+                        ChatGptUsage {
+                            prompt_tokens: 0,
+                            completion_tokens: event.usage.map_or(0, |u| u.output_tokens),
+                        },
+                    ))),
+                    "error" => Some(Err(AnthropicError::ApiError(
+                        event.error.unwrap_or_default().to_string(),
+                    )
+                    .into())),
+                    // content_block_start、pingなど
+                    _ => None,
+                },
+                Err(e) => Some(Err(e)),
+            })
+        })
+        .boxed()
+}
+
+#[async_trait]
+impl ChatProvider for AnthropicProvider {
+    fn build_request(&self, client: &Client, request_body: &ChatGptReqBody) -> RequestBuilder {
+        client
+            .post(format!("{}/messages", self.base_url.trim_end_matches('/')))
+            .header(header::CONTENT_TYPE, "application/json")
+            .header("x-api-key", &self.api_key)
+            .header("anthropic-version", ANTHROPIC_API_VERSION)
+            .json(&self.to_anthropic_request(request_body))
+    }
+
+    fn response_stream(&self, res: Response) -> ChatGptResStream {
+        anthropic_res_stream(res.bytes_stream())
+    }
+
+    async fn response_text(&self, res: Response) -> Result<(String, ChatGptUsage)> {
+        let json: AnthropicResBody = res.json().await?;
+        let usage = json
+            .usage
+            .map_or(ChatGptUsage::default(), |u| ChatGptUsage {
+                prompt_tokens: u.input_tokens,
+                completion_tokens: u.output_tokens,
+            });
+        Ok((
+            json.content.into_iter().filter_map(|c| c.text).collect(),
+            usage,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::slack_post_handler::handle_request::{create_request_body_with_env, Parameters};
+    use crate::slack_post_handler::slack_message::SlackMessage;
+    use futures::stream;
+
+    #[tokio::test]
+    async fn test_request_body_for_claude_persona() {
+        let parameters: Parameters = serde_json::from_value(serde_json::json!({
+            "bot_member_id": "UBOT",
+            "slack_auth_token": "xoxb",
+            "openai_secret_key": "sk",
+            "slack_signing_secret": "secret",
+            "providers": {"claude": {"type": "anthropic", "api_key": "sk-ant"}},
+            "personas": [
+                {"name": "claude", "system_prompt": "You are a cat.", "model": "claude-sonnet-4-5", "provider": "claude"},
+            ],
+        }))
+        .unwrap();
+        let env_vars = envy::from_iter([
+            ("gpt_model".to_string(), "gpt-4o".to_string()),
+            ("temperature".to_string(), "0.2".to_string()),
+            ("max_past_num".to_string(), "10".to_string()),
+        ])
+        .unwrap();
+        let trigger_message = SlackMessage {
+            text: "<@UBOT> persona:claude 猫の好きな食べ物は？".into(),
+            type_name: "message".into(),
+            user: "U01".into(),
+            channel: Some("C01".into()),
+            ts: "1627777777.000100".into(),
+            ..Default::default()
+        };
+
+        let (request_body, _) = create_request_body_with_env(
+            vec![trigger_message],
+            "1627777777.000100",
+            None,
+            &parameters,
+            env_vars,
+        )
+        .await
+        .unwrap();
+        assert_eq!(request_body.model(), "claude-sonnet-4-5");
+        assert_eq!(request_body.provider(), Some("claude"));
+
+        // 質問が切り詰められずにAnthropicのリクエストに含まれる
+        let anthropic =
+            AnthropicProvider::new("sk-ant", None, None).to_anthropic_request(&request_body);
+        assert_eq!(anthropic.system.as_deref(), Some("You are a cat."));
+        assert_eq!(
+            anthropic.messages,
+            vec![AnthropicMessage {
+                role: "user",
+                content: vec![AnthropicContent::Text {
+                    text: "猫の好きな食べ物は？".into()
+                }],
+            }]
+        );
+    }
+
+    #[test]
+    fn test_to_anthropic_request() {
+        let query_with_image: ChatGptQuery = serde_json::from_value(serde_json::json!({
+            "role": "user",
+            "content": {"QueryContent": [
+                {"type": "text", "text": "これは何？"},
+                {"type": "image_url", "image_url": {"url": "data:image/png;base64,AAAA"}},
+            ]},
+        }))
+        .unwrap();
+        let messages = vec![
+            ChatGptQuery::new_system_prompt("You are a cat."),
+            ChatGptQuery::new_summary("summary"),
+            ChatGptQuery::new_tool_calls("ignored".into(), vec![]),
+            ChatGptQuery::new_user_text("こんにちは".into()),
+            query_with_image,
+        ];
+        let request_body = ChatGptReqBody::new(messages, "claude-sonnet-4-5".into(), 1.5, true);
+
+        let anthropic =
+            AnthropicProvider::new("key", None, None).to_anthropic_request(&request_body);
+
+        assert_eq!(
+            anthropic.system.as_deref(),
+            Some("You are a cat.\n\nSummary of the earlier messages in this thread:\nsummary")
+        );
+        assert_eq!(anthropic.temperature, 1.0);
+        // 先頭のassistantは除き、続くuserのメッセージはまとめる
+        assert_eq!(
+            anthropic.messages,
+            vec![AnthropicMessage {
+                role: "user",
+                content: vec![
+                    AnthropicContent::Text {
+                        text: "こんにちは".into()
+                    },
+                    AnthropicContent::Text {
+                        text: "これは何？".into()
+                    },
+                    AnthropicContent::Image {
+                        source: ImageSource {
+                            type_name: "base64",
+                            media_type: "image/png".into(),
+                            data: "AAAA".into(),
+                        }
+                    },
+                ],
+            }]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_anthropic_res_stream() {
+        let input = "event: message_start\n\
+data: {\"type\":\"message_start\",\"message\":{\"id\":\"msg_1\",\"usage\":{\"input_tokens\":10,\"output_tokens\":1}}}\n\n\
+event: content_block_start\n\
+data: {\"type\":\"content_block_start\",\"index\":0,\"content_block\":{\"type\":\"text\",\"text\":\"\"}}\n\n\
+event: ping\n\
+data: {\"type\":\"ping\"}\n\n\
+event: content_block_delta\n\
+data: {\"type\":\"content_block_delta\",\"index\":0,\"delta\":{\"type\":\"text_delta\",\"text\":\"にゃ\"}}\n\n\
+event: content_block_delta\n\
+data: {\"type\":\"content_block_delta\",\"index\":0,\"delta\":{\"type\":\"text_delta\",\"text\":\"ーん\"}}\n\n\
+event: message_delta\n\
+data: {\"type\":\"message_delta\",\"delta\":{\"stop_reason\":\"max_tokens\"},\"usage\":{\"output_tokens\":2}}\n\n\
+event: message_stop\n\
+data: {\"type\":\"message_stop\"}\n\n";
+        let chunks = input.as_bytes().chunks(7).map(Ok::<_, std::io::Error>);
+        let jsons: Vec<ChatGptResBody> = anthropic_res_stream(stream::iter(chunks))
+            .map(|json| json.unwrap())
+            .collect()
+            .await;
+
+        let contents: Vec<String> = jsons.iter().map(|json| json.get_content()).collect();
+        assert_eq!(contents, vec!["", "にゃ", "ーん", ""]);
+        // max_tokensはlengthとして扱う
+        assert_eq!(jsons[3].get_finish_reason().as_deref(), Some("length"));
+        let mut usage = ChatGptUsage::default();
+        jsons
+            .iter()
+            .filter_map(|json| json.usage.as_ref())
+            .for_each(|u| usage.add(u));
+        assert_eq!(usage.total_tokens(), 12);
+    }
+}
@@ -1,11 +1,18 @@
-use super::handle_request::{ChatGptReqBody, Parameters, SlackHistoryResponse};
+use super::chat_gpt_res_body::ChatGptUsage;
+use super::chat_provider::{ChatProvider, OpenAiProvider, ProviderConfig};
+use super::handle_chat_gpt_response::ChatGptResStream;
+use super::handle_request::{
+    ChatGptReqBody, Parameters, SlackHistoryResponse, SlackResponseMetadata,
+};
 use super::slack_message::SlackMessage;
 use crate::constants::*;
 use anyhow::Result;
 use reqwest::StatusCode;
-use reqwest::{header, Client};
+use reqwest::{header, Client, RequestBuilder};
+use serde_derive::Deserialize;
 use serde_json::{json, Value};
 use std::collections::HashMap;
+use std::time::Duration;
 use thiserror::Error;
 
 #[derive(Debug, Clone)]
@@ -13,6 +20,7 @@ pub struct ApiClient {
     client: Client,
     slack_token: String,
     openai_token: String,
+    providers: HashMap<String, ProviderConfig>,
     channel: String,
 }
 
@@ -26,10 +34,18 @@ pub enum ApiClientError {
     SlackPostError(String),
     #[error("Slack update error: {0}")]
     SlackUpdateError(String),
+    #[error("Slack delete error: {0}")]
+    SlackDeleteError(String),
+    #[error("Slack rate limited at {0}, retry after {1:?}")]
+    SlackRateLimited(&'static str, Duration),
+    #[error("Request can not be retried at {0}")]
+    RequestNotRetryable(&'static str),
     #[error("OpenAI API usage limit.")]
     OpenaiUsageLimit(),
     #[error("OpenAI API error: {0}")]
     OpenaiError(String),
+    #[error("Unknown provider: {0}")]
+    UnknownProvider(String),
 }
 
 impl ApiClient {
@@ -38,10 +54,15 @@ impl ApiClient {
             client: Client::new(),
             slack_token: params.slack_auth_token.clone(),
             openai_token: params.openai_secret_key.clone(),
+            providers: params.providers.clone(),
             channel: channel.into(),
         }
     }
 
+    pub fn channel(&self) -> &str {
+        &self.channel
+    }
+
     // slack headers
     fn headers_for_slack(&self) -> header::HeaderMap {
         let mut headers = header::HeaderMap::new();
@@ -53,16 +74,6 @@ impl ApiClient {
         headers
     }
 
-    fn headers_for_openai(&self) -> header::HeaderMap {
-        let mut headers = header::HeaderMap::new();
-        headers.insert("Content-Type", "application/json".parse().unwrap());
-        headers.insert(
-            "Authorization",
-            format!("Bearer {}", self.openai_token).parse().unwrap(),
-        );
-        headers
-    }
-
     // slackにメッセージを投稿する
     pub async fn post_message(
         &self,
@@ -76,14 +87,12 @@ impl ApiClient {
         if let Some(thread_ts) = thread_ts {
             form.insert("thread_ts", thread_ts);
         }
-        let res = self
+        let request = self
             .client
             .post(SLACK_POST_URL)
             .headers(self.headers_for_slack())
-            .form(&form)
-            .send()
-            .await?;
-        let res_text = res.text().await?;
+            .form(&form);
+        let res_text = send_slack_request(request, "post_message", SLACK_MAX_RETRIES).await?;
         let res_json: Value =
             serde_json::from_str(&res_text).map_err(ApiClientError::ParseError)?;
         if res_json["ok"] != true {
@@ -92,8 +101,34 @@ impl ApiClient {
         Ok(res_json["ts"].as_str().unwrap().to_owned())
     }
 
+    // スラッシュコマンドのresponse_urlに、実行者にのみ表示されるメッセージを送る
+    pub async fn post_ephemeral_response(&self, response_url: &str, text: &str) -> Result<()> {
+        let request = self
+            .client
+            .post(response_url)
+            .json(&json!({"response_type": "ephemeral", "text": text}));
+        send_slack_request(request, "post_ephemeral_response", SLACK_MAX_RETRIES).await?;
+        Ok(())
+    }
+
     // slackのメッセージを更新する
     pub async fn update_message(&self, text: &str, ts: &str) -> Result<()> {
+        self.update_message_with_retries(text, ts, SLACK_MAX_RETRIES)
+            .await
+    }
+
+    // slackのメッセージを更新する。レート制限にかかった場合はリトライせずにエラーを返す
+    // NOTE: ストリーミング中の途中経過の更新に使う
+    pub async fn try_update_message(&self, text: &str, ts: &str) -> Result<()> {
+        self.update_message_with_retries(text, ts, 0).await
+    }
+
+    async fn update_message_with_retries(
+        &self,
+        text: &str,
+        ts: &str,
+        max_retries: u32,
+    ) -> Result<()> {
         if text.is_empty() {
             return Ok(());
         }
@@ -103,15 +138,12 @@ impl ApiClient {
         form.insert("channel", &self.channel);
         form.insert("text", &text_string);
         form.insert("ts", &ts_string);
-        // TODO: レート制限にかかった場合に対応する
-        let res = self
+        let request = self
             .client
             .post(SLACK_UPDATE_URL)
             .headers(self.headers_for_slack())
-            .form(&form)
-            .send()
-            .await?;
-        let res_text = res.text().await?;
+            .form(&form);
+        let res_text = send_slack_request(request, "update_message", max_retries).await?;
         let res_json: Value =
             serde_json::from_str(&res_text).map_err(ApiClientError::ParseError)?;
         if res_json["ok"] != true {
@@ -120,71 +152,187 @@ impl ApiClient {
         Ok(())
     }
 
-    // スレッド内のメッセージを取得する
-    pub async fn get_replies(&self, thread_ts: &str, limit: &str) -> Result<Vec<SlackMessage>> {
-        let query = &[
-            ("limit", limit),
-            ("channel", self.channel.as_str()),
-            ("ts", thread_ts),
-        ];
-
-        let client = reqwest::Client::new();
-        let res = client
-            .get(SLACK_GET_REPLIES_URL)
+    // slackのメッセージを削除する
+    pub async fn delete_message(&self, ts: &str) -> Result<()> {
+        let mut form = HashMap::new();
+        form.insert("channel", self.channel.as_str());
+        form.insert("ts", ts);
+        let request = self
+            .client
+            .post(SLACK_DELETE_URL)
             .headers(self.headers_for_slack())
-            .query(query)
-            .send()
-            .await?;
+            .form(&form);
+        let res_text = send_slack_request(request, "delete_message", SLACK_MAX_RETRIES).await?;
+        let res_json: Value =
+            serde_json::from_str(&res_text).map_err(ApiClientError::ParseError)?;
+        if res_json["ok"] != true {
+            return Err(ApiClientError::SlackDeleteError(res_text).into());
+        }
+        Ok(())
+    }
+
+    // スレッド内の最新limit件のメッセージを取得する
+    // NOTE: conversations.repliesは古い順に返すため、最後のページまで取得してから最新の分を残す
+    pub async fn get_replies(&self, thread_ts: &str, limit: usize) -> Result<Vec<SlackMessage>> {
+        let mut messages = Vec::new();
+        let mut cursor: Option<String> = None;
+        loop {
+            let page_limit = SLACK_PAGE_LIMIT.to_string();
+            let mut query = vec![
+                ("limit", page_limit.as_str()),
+                ("channel", self.channel.as_str()),
+                ("ts", thread_ts),
+            ];
+            if let Some(cursor) = cursor.as_deref() {
+                query.push(("cursor", cursor));
+            }
 
-        // エラーハンドリング
-        if !res.status().is_success() {
-            return Err(ApiClientError::StatusError(res.status(), "get_replies").into());
+            let request = self
+                .client
+                .get(SLACK_GET_REPLIES_URL)
+                .headers(self.headers_for_slack())
+                .query(&query);
+            let body = send_slack_request(request, "get_replies", SLACK_MAX_RETRIES).await?;
+            let json: SlackHistoryResponse =
+                serde_json::from_str(&body).map_err(ApiClientError::ParseError)?;
+
+            cursor = json.next_cursor();
+            messages.extend(json.messages);
+            // NOTE: 上限を超える分は取得しない。古い順に返るため、それより新しいメッセージは含まれない
+            if cursor.is_none() || messages.len() >= SLACK_MAX_REPLIES {
+                break;
+            }
         }
+        Ok(newest_messages(messages, limit))
+    }
 
-        let body = res.text().await?;
-        let json: SlackHistoryResponse =
-            serde_json::from_str(&body).map_err(ApiClientError::ParseError)?;
-        return Ok(json.messages);
+    // 指定したメッセージを1件取得する
+    // NOTE: スレッド内の返信はconversations.historyでは取得できないため、conversations.repliesを使う
+    // NOTE: conversations.repliesは範囲に関わらず親のメッセージを先頭に返すため、見つかるまでページを進める
+    pub async fn get_message(
+        &self,
+        channel: &str,
+        ts: &str,
+        thread_ts: Option<&str>,
+    ) -> Result<Option<SlackMessage>> {
+        let (url, query) = message_query(channel, ts, thread_ts);
+        let mut cursor: Option<String> = None;
+        loop {
+            let mut query = query.clone();
+            if let Some(cursor) = cursor.as_deref() {
+                query.push(("cursor", cursor));
+            }
+
+            let request = self
+                .client
+                .get(url)
+                .headers(self.headers_for_slack())
+                .query(&query);
+            let body = send_slack_request(request, "get_message", SLACK_MAX_RETRIES).await?;
+            let json: SlackHistoryResponse =
+                serde_json::from_str(&body).map_err(ApiClientError::ParseError)?;
+
+            cursor = json.next_cursor();
+            if let Some(message) = json.messages.into_iter().find(|m| m.ts == ts) {
+                return Ok(Some(message));
+            }
+            if cursor.is_none() {
+                return Ok(None);
+            }
+        }
     }
 
-    // チャンネル内のメッセージを取得する
-    pub async fn get_history(&self, limit: &str) -> Result<Vec<SlackMessage>> {
-        let query = &[("limit", limit), ("channel", self.channel.as_str())];
-        let res = self
-            .client
-            .get(SLACK_GET_HISTORY_URL)
-            .headers(self.headers_for_slack())
-            .query(query)
-            .send()
-            .await?;
+    // ユーザーがチャンネルのメンバーかどうか
+    pub async fn is_member(&self, channel: &str, user: &str) -> Result<bool> {
+        let mut cursor: Option<String> = None;
+        loop {
+            let page_limit = SLACK_PAGE_LIMIT.to_string();
+            let mut query = vec![("limit", page_limit.as_str()), ("channel", channel)];
+            if let Some(cursor) = cursor.as_deref() {
+                query.push(("cursor", cursor));
+            }
+
+            let request = self
+                .client
+                .get(SLACK_GET_MEMBERS_URL)
+                .headers(self.headers_for_slack())
+                .query(&query);
+            let body = send_slack_request(request, "is_member", SLACK_MAX_RETRIES).await?;
+            let json: SlackMembersResponse =
+                serde_json::from_str(&body).map_err(ApiClientError::ParseError)?;
 
-        // エラーハンドリング
-        if !res.status().is_success() {
-            return Err(ApiClientError::StatusError(res.status(), "get_history").into());
+            if json.members.iter().any(|m| m == user) {
+                return Ok(true);
+            }
+            cursor = json
+                .response_metadata
+                .and_then(|m| m.next_cursor)
+                .filter(|c| !c.is_empty());
+            if cursor.is_none() {
+                return Ok(false);
+            }
         }
+    }
 
-        let body = res.text().await?;
-        let json: SlackHistoryResponse =
-            serde_json::from_str(&body).map_err(ApiClientError::ParseError)?;
-        return Ok(json.messages);
+    // チャンネル内の最新limit件のメッセージを取得する
+    // NOTE: conversations.historyは新しい順に返すため、limit件集まるまでページを進める
+    pub async fn get_history(&self, limit: usize) -> Result<Vec<SlackMessage>> {
+        let mut messages = Vec::new();
+        let mut cursor: Option<String> = None;
+        while messages.len() < limit {
+            let page_limit = (limit - messages.len()).min(SLACK_PAGE_LIMIT).to_string();
+            let mut query = vec![
+                ("limit", page_limit.as_str()),
+                ("channel", self.channel.as_str()),
+            ];
+            if let Some(cursor) = cursor.as_deref() {
+                query.push(("cursor", cursor));
+            }
+
+            let request = self
+                .client
+                .get(SLACK_GET_HISTORY_URL)
+                .headers(self.headers_for_slack())
+                .query(&query);
+            let body = send_slack_request(request, "get_history", SLACK_MAX_RETRIES).await?;
+            let json: SlackHistoryResponse =
+                serde_json::from_str(&body).map_err(ApiClientError::ParseError)?;
+
+            cursor = json.next_cursor();
+            messages.extend(json.messages);
+            // NOTE: 上限を超える分は取得しない。古い順に返るため、それより新しいメッセージは含まれない
+            if cursor.is_none() || messages.len() >= SLACK_MAX_REPLIES {
+                break;
+            }
+        }
+        Ok(newest_messages(messages, limit))
     }
 
-    // ChatGPTにメッセージを投げて返答を取得する
+    // 送信先のprovider。名前を指定しない場合はOpenAI
+    fn chat_provider(&self, name: Option<&str>) -> Result<Box<dyn ChatProvider>> {
+        match name {
+            None => Ok(Box::new(OpenAiProvider::new(&self.openai_token))),
+            Some(name) => match self.providers.get(name) {
+                Some(config) => Ok(config.build(&self.openai_token)),
+                None => Err(ApiClientError::UnknownProvider(name.to_string()).into()),
+            },
+        }
+    }
+
+    // ChatGPTにメッセージを投げて返答のストリームを取得する
     pub async fn get_chat_gpt_response(
         &self,
-        request_body: ChatGptReqBody,
+        request_body: &ChatGptReqBody,
         ts: &str,
-    ) -> Result<reqwest::Response> {
-        let res = self
-            .client
-            .post(CHAT_GPT_POST_URL)
-            .headers(self.headers_for_openai())
-            .json(&request_body)
+    ) -> Result<ChatGptResStream> {
+        let provider = self.chat_provider(request_body.provider())?;
+        let res = provider
+            .build_request(&self.client, request_body)
             .send()
             .await?;
 
         match res.status().as_u16() {
-            200 => Ok(res),
+            200 => Ok(provider.response_stream(res)),
             429 => {
                 self.update_message(USAGE_LIMIT_MESSAGE, ts).await?;
                 Err(ApiClientError::OpenaiUsageLimit().into())
@@ -214,4 +362,260 @@ impl ApiClient {
             }
         }
     }
+
+    // ChatGPTにメッセージを投げて、ストリーミングせずに返答の全文を取得する
+    pub async fn get_chat_gpt_completion(
+        &self,
+        request_body: &ChatGptReqBody,
+    ) -> Result<(String, ChatGptUsage)> {
+        let provider = self.chat_provider(request_body.provider())?;
+        let res = provider
+            .build_request(&self.client, request_body)
+            .send()
+            .await?;
+
+        match res.status().as_u16() {
+            200 => provider.response_text(res).await,
+            429 => Err(ApiClientError::OpenaiUsageLimit().into()),
+            _ => {
+                let body = res.text().await?;
+                Err(ApiClientError::OpenaiError(body).into())
+            }
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct SlackMembersResponse {
+    #[serde(default)]
+    members: Vec<String>,
+    response_metadata: Option<SlackResponseMetadata>,
+}
+
+// 指定したメッセージを取得するAPIのURLとクエリ
+// NOTE: スレッド内の返信の場合は、thread_tsのスレッドからtsのメッセージを探す
+pub fn message_query<'a>(
+    channel: &'a str,
+    ts: &'a str,
+    thread_ts: Option<&'a str>,
+) -> (&'static str, Vec<(&'static str, &'a str)>) {
+    let mut query = vec![
+        ("channel", channel),
+        ("oldest", ts),
+        ("latest", ts),
+        ("inclusive", "true"),
+    ];
+    match thread_ts {
+        Some(thread_ts) => {
+            query.push(("ts", thread_ts));
+            (SLACK_GET_REPLIES_URL, query)
+        }
+        None => (SLACK_GET_HISTORY_URL, query),
+    }
+}
+
+// 時系列順に並べて最新のlimit件を返す
+pub fn newest_messages(messages: Vec<SlackMessage>, limit: usize) -> Vec<SlackMessage> {
+    let mut messages = messages;
+    messages.sort_by(|a, b| {
+        let a_ts = a.ts.parse::<f64>().unwrap_or(0.0);
+        let b_ts = b.ts.parse::<f64>().unwrap_or(0.0);
+        a_ts.total_cmp(&b_ts)
+    });
+    let skip = messages.len().saturating_sub(limit);
+    messages.into_iter().skip(skip).collect()
+}
+
+// レート制限でSlack APIへのリクエストが失敗した場合、待つべき時間を返す
+pub fn rate_limited_duration(error: &anyhow::Error) -> Option<Duration> {
+    match error.downcast_ref::<ApiClientError>() {
+        Some(ApiClientError::SlackRateLimited(_, retry_after)) => Some(*retry_after),
+        _ => None,
+    }
+}
+
+// Slack APIにリクエストし、レスポンスの本文を返す
+// レート制限(429かratelimitedエラー)の場合は、Retry-Afterの秒数待ってから最大max_retries回リトライする
+async fn send_slack_request(
+    request: RequestBuilder,
+    label: &'static str,
+    max_retries: u32,
+) -> Result<String> {
+    let mut attempt = 0;
+    loop {
+        let res = request
+            .try_clone()
+            .ok_or(ApiClientError::RequestNotRetryable(label))?
+            .send()
+            .await?;
+        let status = res.status();
+        let retry_after = res
+            .headers()
+            .get(header::RETRY_AFTER)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.trim().parse::<u64>().ok())
+            .map(Duration::from_secs);
+        let body = res.text().await?;
+
+        if !is_rate_limited(status, &body) {
+            // エラーハンドリング
+            if !status.is_success() {
+                return Err(ApiClientError::StatusError(status, label).into());
+            }
+            return Ok(body);
+        }
+
+        // Retry-Afterが無い場合は指数関数的に待つ時間を延ばす
+        let wait = retry_after.unwrap_or(SLACK_RETRY_BASE_WAIT * 2u32.pow(attempt));
+        if attempt >= max_retries || wait > SLACK_RETRY_MAX_WAIT {
+            return Err(ApiClientError::SlackRateLimited(label, wait).into());
+        }
+        #[cfg(debug_assertions)]
+        {
+            println!("Slack rate limited at {}, retry after {:?}", label, wait);
+        }
+        tokio::time::sleep(wait).await;
+        attempt += 1;
+    }
+}
+
+fn is_rate_limited(status: StatusCode, body: &str) -> bool {
+    if status == StatusCode::TOO_MANY_REQUESTS {
+        return true;
+    }
+    serde_json::from_str::<Value>(body).is_ok_and(|json| json["error"] == "ratelimited")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Response, Server};
+    use std::convert::Infallible;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    // 最初のfail_count回はレート制限を返すサーバーを起動する
+    fn start_rate_limited_server(fail_count: usize) -> (String, Arc<AtomicUsize>) {
+        let count = Arc::new(AtomicUsize::new(0));
+        let server_count = count.clone();
+        let make_service = make_service_fn(move |_conn| {
+            let count = server_count.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |_req| {
+                    let n = count.fetch_add(1, Ordering::SeqCst);
+                    async move {
+                        let res = if n < fail_count {
+                            Response::builder()
+                                .status(200)
+                                .header("Retry-After", "0")
+                                .body(Body::from(r#"{"ok":false,"error":"ratelimited"}"#))
+                        } else {
+                            Response::builder()
+                                .status(200)
+                                .body(Body::from(r#"{"ok":true,"ts":"1.0"}"#))
+                        };
+                        Ok::<_, Infallible>(res.unwrap())
+                    }
+                }))
+            }
+        });
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+        let url = format!("http://{}", server.local_addr());
+        tokio::spawn(server);
+        (url, count)
+    }
+
+    #[tokio::test]
+    async fn test_post_ephemeral_response() {
+        let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+        let make_service = make_service_fn(move |_conn| {
+            let sender = sender.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req: hyper::Request<Body>| {
+                    let sender = sender.clone();
+                    async move {
+                        let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+                        sender.send(body).unwrap();
+                        Ok::<_, Infallible>(Response::new(Body::from("ok")))
+                    }
+                }))
+            }
+        });
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+        let url = format!("http://{}", server.local_addr());
+        tokio::spawn(server);
+
+        let parameters: Parameters = serde_json::from_value(json!({
+            "bot_member_id": "UBOT",
+            "slack_auth_token": "xoxb",
+            "openai_secret_key": "sk",
+            "slack_signing_secret": "secret",
+        }))
+        .unwrap();
+        ApiClient::new(&parameters, "C01")
+            .post_ephemeral_response(&url, ERROR_MESSAGE)
+            .await
+            .unwrap();
+
+        let body: Value = serde_json::from_slice(&receiver.recv().await.unwrap()).unwrap();
+        assert_eq!(
+            body,
+            json!({"response_type": "ephemeral", "text": ERROR_MESSAGE})
+        );
+    }
+
+    #[test]
+    fn test_newest_messages() {
+        let json = r#"{
+            "ok": true,
+            "messages": [
+                {"type": "message", "user": "U1", "text": "3", "ts": "1627777779.000000"},
+                {"type": "message", "user": "U1", "text": "1", "ts": "1627777777.000000"},
+                {"type": "message", "user": "U1", "text": "2", "ts": "1627777778.000000"}
+            ],
+            "has_more": true,
+            "response_metadata": {"next_cursor": "bmV4dF90czoxNTEyMDg1ODYxMDAwNTQz"}
+        }"#;
+        let res: SlackHistoryResponse = serde_json::from_str(json).unwrap();
+        assert_eq!(
+            res.next_cursor().as_deref(),
+            Some("bmV4dF90czoxNTEyMDg1ODYxMDAwNTQz")
+        );
+
+        let texts: Vec<String> = newest_messages(res.messages, 2)
+            .into_iter()
+            .map(|m| m.text)
+            .collect();
+        assert_eq!(texts, vec!["2", "3"]);
+
+        // has_moreがfalseの場合は最後のページ
+        let json = r#"{
+            "ok": true,
+            "messages": [],
+            "has_more": false,
+            "response_metadata": {"next_cursor": "bmV4dF90czoxNTEyMDg1ODYxMDAwNTQz"}
+        }"#;
+        let res: SlackHistoryResponse = serde_json::from_str(json).unwrap();
+        assert_eq!(res.next_cursor(), None);
+    }
+
+    #[tokio::test]
+    async fn test_send_slack_request_retries_when_rate_limited() {
+        let (url, count) = start_rate_limited_server(2);
+        let request = Client::new().post(&url).form(&[("text", "にゃ")]);
+
+        let body = send_slack_request(request, "test", 3).await.unwrap();
+        assert_eq!(body, r#"{"ok":true,"ts":"1.0"}"#);
+        assert_eq!(count.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_send_slack_request_gives_up() {
+        let (url, _) = start_rate_limited_server(5);
+        let request = Client::new().post(&url);
+
+        let err = send_slack_request(request, "test", 1).await.unwrap_err();
+        assert_eq!(rate_limited_duration(&err), Some(Duration::from_secs(0)));
+    }
 }
@@ -0,0 +1,472 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use regex::Regex;
+use serde_json::{json, Value};
+use thiserror::Error;
+
+use super::access_policy::AccessPolicy;
+use super::api_client::ApiClient;
+use super::handle_request::get_enviroment_variable;
+use super::time::format_time;
+use super::tool::{Tool, ToolRegistry};
+
+#[derive(Error, Debug, PartialEq)]
+pub enum BuiltinToolError {
+    #[error("Missing argument: {0}")]
+    MissingArgument(&'static str),
+    #[error("Invalid expression: {0}")]
+    InvalidExpression(String),
+    #[error("Expression is nested too deeply: {0}")]
+    ExpressionTooDeep(String),
+    #[error("Invalid Slack permalink: {0}")]
+    InvalidPermalink(String),
+    #[error("Message not found: {0}")]
+    MessageNotFound(String),
+    #[error("Access denied to {0}: {1}")]
+    AccessDenied(String, String),
+}
+
+// 組み込みのtoolを登録したregistry
+// NOTE: requesterは返答のきっかけとなったユーザー。他のチャンネルのメッセージを読む権限の確認に使う
+pub fn builtin_tools(
+    api_client: &ApiClient,
+    requester: &str,
+    policy: &AccessPolicy,
+) -> ToolRegistry {
+    let mut registry = ToolRegistry::default();
+    registry.register(Box::new(CalculatorTool));
+    registry.register(Box::new(CurrentTimeTool));
+    registry.register(Box::new(SlackPermalinkTool {
+        api_client: api_client.clone(),
+        requester: requester.to_string(),
+        policy: policy.clone(),
+    }));
+    registry
+}
+
+// 環境変数でtoolが有効な場合は組み込みのtoolを、そうでない場合は空のregistryを返す
+pub fn tools_from_env(
+    api_client: &ApiClient,
+    requester: &str,
+    policy: &AccessPolicy,
+) -> Result<ToolRegistry> {
+    let env_vars = get_enviroment_variable()?;
+    if env_vars.use_tools.unwrap_or(false) {
+        Ok(builtin_tools(api_client, requester, policy))
+    } else {
+        Ok(ToolRegistry::default())
+    }
+}
+
+fn string_argument<'a>(arguments: &'a Value, name: &'static str) -> Result<&'a str> {
+    arguments[name]
+        .as_str()
+        .ok_or(BuiltinToolError::MissingArgument(name).into())
+}
+
+// 四則演算
+pub struct CalculatorTool;
+
+#[async_trait]
+impl Tool for CalculatorTool {
+    fn name(&self) -> &str {
+        "calculator"
+    }
+
+    fn description(&self) -> &str {
+        "Evaluate an arithmetic expression. Supports + - * / % ^, parentheses and sqrt()."
+    }
+
+    fn parameters(&self) -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "expression": {"type": "string", "description": "e.g. (1 + 2) * 3 ^ 2"},
+            },
+            "required": ["expression"],
+        })
+    }
+
+    async fn execute(&self, arguments: Value) -> Result<String> {
+        let expression = string_argument(&arguments, "expression")?;
+        Ok(evaluate(expression)?.to_string())
+    }
+}
+
+// 再帰下降で式を評価する
+// expr = term (("+" | "-") term)*
+// term = power (("*" | "/" | "%") power)*
+// power = unary ("^" power)?
+// unary = "-" unary | primary
+// primary = number | "(" expr ")" | "sqrt" "(" expr ")"
+// NOTE: スタックが溢れないよう、括弧や単項演算子、累乗の入れ子はMAX_EXPRESSION_DEPTHまでとする
+const MAX_EXPRESSION_DEPTH: usize = 64;
+
+struct Parser<'a> {
+    chars: std::iter::Peekable<std::str::Chars<'a>>,
+    expression: &'a str,
+    depth: usize,
+}
+
+pub fn evaluate(expression: &str) -> Result<f64, BuiltinToolError> {
+    let mut parser = Parser {
+        chars: expression.chars().peekable(),
+        expression,
+        depth: 0,
+    };
+    let value = parser.expr()?;
+    if parser.peek().is_some() {
+        return Err(parser.error());
+    }
+    if !value.is_finite() {
+        return Err(parser.error());
+    }
+    Ok(value)
+}
+
+impl Parser<'_> {
+    fn error(&self) -> BuiltinToolError {
+        BuiltinToolError::InvalidExpression(self.expression.to_string())
+    }
+
+    // 入れ子を1段深くしてfを評価する
+    fn nested(
+        &mut self,
+        f: impl FnOnce(&mut Self) -> Result<f64, BuiltinToolError>,
+    ) -> Result<f64, BuiltinToolError> {
+        if self.depth >= MAX_EXPRESSION_DEPTH {
+            return Err(BuiltinToolError::ExpressionTooDeep(
+                self.expression.to_string(),
+            ));
+        }
+        self.depth += 1;
+        let value = f(self);
+        self.depth -= 1;
+        value
+    }
+
+    fn peek(&mut self) -> Option<char> {
+        while self.chars.peek().is_some_and(|c| c.is_whitespace()) {
+            self.chars.next();
+        }
+        self.chars.peek().copied()
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), BuiltinToolError> {
+        match self.peek() {
+            Some(c) if c == expected => {
+                self.chars.next();
+                Ok(())
+            }
+            _ => Err(self.error()),
+        }
+    }
+
+    fn expr(&mut self) -> Result<f64, BuiltinToolError> {
+        let mut value = self.term()?;
+        while let Some(op @ ('+' | '-')) = self.peek() {
+            self.chars.next();
+            let rhs = self.term()?;
+            value = if op == '+' { value + rhs } else { value - rhs };
+        }
+        Ok(value)
+    }
+
+    fn term(&mut self) -> Result<f64, BuiltinToolError> {
+        let mut value = self.power()?;
+        while let Some(op @ ('*' | '/' | '%')) = self.peek() {
+            self.chars.next();
+            let rhs = self.power()?;
+            value = match op {
+                '*' => value * rhs,
+                '/' => value / rhs,
+                _ => value % rhs,
+            };
+        }
+        Ok(value)
+    }
+
+    fn power(&mut self) -> Result<f64, BuiltinToolError> {
+        let base = self.unary()?;
+        if self.peek() == Some('^') {
+            self.chars.next();
+            return Ok(base.powf(self.nested(Self::power)?));
+        }
+        Ok(base)
+    }
+
+    fn unary(&mut self) -> Result<f64, BuiltinToolError> {
+        if self.peek() == Some('-') {
+            self.chars.next();
+            return Ok(-self.nested(Self::unary)?);
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<f64, BuiltinToolError> {
+        match self.peek() {
+            Some('(') => {
+                self.chars.next();
+                let value = self.nested(Self::expr)?;
+                self.expect(')')?;
+                Ok(value)
+            }
+            Some(c) if c.is_ascii_digit() || c == '.' => {
+                let mut number = String::new();
+                while let Some(&c) = self.chars.peek() {
+                    if !(c.is_ascii_digit() || c == '.') {
+                        break;
+                    }
+                    number.push(c);
+                    self.chars.next();
+                }
+                number.parse::<f64>().map_err(|_| self.error())
+            }
+            Some(c) if c.is_ascii_alphabetic() => {
+                let mut name = String::new();
+                while let Some(&c) = self.chars.peek() {
+                    if !c.is_ascii_alphabetic() {
+                        break;
+                    }
+                    name.push(c);
+                    self.chars.next();
+                }
+                if name != "sqrt" {
+                    return Err(self.error());
+                }
+                self.expect('(')?;
+                let value = self.nested(Self::expr)?;
+                self.expect(')')?;
+                Ok(value.sqrt())
+            }
+            _ => Err(self.error()),
+        }
+    }
+}
+
+// 現在時刻
+pub struct CurrentTimeTool;
+
+#[async_trait]
+impl Tool for CurrentTimeTool {
+    fn name(&self) -> &str {
+        "current_time"
+    }
+
+    fn description(&self) -> &str {
+        "Get the current date and time. Japan Standard Time is utc_offset_hours = 9."
+    }
+
+    fn parameters(&self) -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "utc_offset_hours": {"type": "number", "description": "Defaults to 0 (UTC)."},
+            },
+        })
+    }
+
+    async fn execute(&self, arguments: Value) -> Result<String> {
+        let offset_hours = arguments["utc_offset_hours"].as_f64().unwrap_or(0.0);
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+        Ok(format_time(now, (offset_hours * 3600.0) as i64))
+    }
+}
+
+// Slackのメッセージのリンクから本文を取得する
+// NOTE: botはリンク先のチャンネルを読めても質問者が読めるとは限らないため、
+// 質問されたチャンネル以外は質問者がメンバーの場合のみ取得する
+pub struct SlackPermalinkTool {
+    api_client: ApiClient,
+    requester: String,
+    policy: AccessPolicy,
+}
+
+impl SlackPermalinkTool {
+    // リンク先のチャンネルを質問者に見せてよいかどうか
+    async fn check_access(&self, url: &str, channel: &str) -> Result<()> {
+        let denied = |reason: String| BuiltinToolError::AccessDenied(url.to_string(), reason);
+        // NOTE: DMのチャンネルIDはDから始まる
+        self.policy
+            .check(&[&self.requester], channel, channel.starts_with('D'))
+            .map_err(|reason| denied(reason.to_string()))?;
+        if channel == self.api_client.channel() {
+            return Ok(());
+        }
+        if self.requester.is_empty() || !self.api_client.is_member(channel, &self.requester).await?
+        {
+            return Err(denied(format!("{} is not a member", self.requester)).into());
+        }
+        Ok(())
+    }
+}
+
+// permalinkからchannel、ts、thread_tsを取り出す
+// e.g. https://xxx.slack.com/archives/C0000000000/p1627777777000100?thread_ts=1627777770.000200
+fn parse_permalink(url: &str) -> Result<(String, String, Option<String>), BuiltinToolError> {
+    let re =
+        Regex::new(r"/archives/([A-Z0-9]+)/p(\d{10})(\d{6})(?:\?.*thread_ts=([\d.]+))?").unwrap();
+    let captures = re
+        .captures(url)
+        .ok_or(BuiltinToolError::InvalidPermalink(url.to_string()))?;
+    Ok((
+        captures[1].to_string(),
+        format!("{}.{}", &captures[2], &captures[3]),
+        captures.get(4).map(|m| m.as_str().to_string()),
+    ))
+}
+
+#[async_trait]
+impl Tool for SlackPermalinkTool {
+    fn name(&self) -> &str {
+        "slack_permalink"
+    }
+
+    fn description(&self) -> &str {
+        "Fetch the text of a Slack message from its permalink URL."
+    }
+
+    fn parameters(&self) -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "url": {"type": "string", "description": "Permalink of the Slack message."},
+            },
+            "required": ["url"],
+        })
+    }
+
+    async fn execute(&self, arguments: Value) -> Result<String> {
+        let url = string_argument(&arguments, "url")?;
+        let (channel, ts, thread_ts) = parse_permalink(url)?;
+        self.check_access(url, &channel).await?;
+        let message = self
+            .api_client
+            .get_message(&channel, &ts, thread_ts.as_deref())
+            .await?
+            .ok_or(BuiltinToolError::MessageNotFound(url.to_string()))?;
+        Ok(json!({"user": message.user, "ts": message.ts, "text": message.text}).to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::constants::SLACK_GET_REPLIES_URL;
+    use crate::slack_post_handler::api_client::message_query;
+    use crate::slack_post_handler::handle_request::{Parameters, SlackHistoryResponse};
+
+    #[test]
+    fn test_evaluate() {
+        assert_eq!(evaluate("1 + 2 * 3"), Ok(7.0));
+        assert_eq!(evaluate("(1 + 2) * 3 ^ 2"), Ok(27.0));
+        assert_eq!(evaluate("2 ^ 3 ^ 2"), Ok(512.0));
+        assert_eq!(evaluate("-sqrt(16) / 8 % 3"), Ok(-0.5));
+        assert!(evaluate("1 +").is_err());
+        assert!(evaluate("1 / 0").is_err());
+        assert!(evaluate("exit()").is_err());
+    }
+
+    #[test]
+    fn test_evaluate_too_deep() {
+        let expression = "(".repeat(100_000);
+        assert_eq!(
+            evaluate(&expression),
+            Err(BuiltinToolError::ExpressionTooDeep(expression))
+        );
+        assert!(matches!(
+            evaluate(&"-".repeat(100_000)),
+            Err(BuiltinToolError::ExpressionTooDeep(_))
+        ));
+        assert!(matches!(
+            evaluate(&"2^".repeat(100_000)),
+            Err(BuiltinToolError::ExpressionTooDeep(_))
+        ));
+        // 上限までの入れ子は評価できる
+        let expression = format!("{}1{}", "(".repeat(63), ")".repeat(63));
+        assert_eq!(evaluate(&expression), Ok(1.0));
+    }
+
+    #[test]
+    fn test_parse_permalink() {
+        let url = "https://xxx.slack.com/archives/C0000000000/p1627777777000100?thread_ts=1627777770.000200&cid=C0000000000";
+        assert_eq!(
+            parse_permalink(url),
+            Ok((
+                "C0000000000".to_string(),
+                "1627777777.000100".to_string(),
+                Some("1627777770.000200".to_string())
+            ))
+        );
+        assert!(parse_permalink("https://example.com").is_err());
+    }
+
+    #[test]
+    fn test_reply_permalink() {
+        let url = "https://xxx.slack.com/archives/C01/p1627777777000100?thread_ts=1627777770.000200&cid=C01";
+        let (channel, ts, thread_ts) = parse_permalink(url).unwrap();
+        let (api_url, query) = message_query(&channel, &ts, thread_ts.as_deref());
+        assert_eq!(api_url, SLACK_GET_REPLIES_URL);
+        assert!(query.contains(&("ts", "1627777770.000200")));
+        // NOTE: limit=1では親のメッセージしか返らない
+        assert!(!query.iter().any(|(key, _)| *key == "limit"));
+
+        // 親のメッセージが先頭に返っても、返信の方を選ぶ
+        let json = r#"{
+            "ok": true,
+            "messages": [
+                {"type": "message", "user": "U1", "text": "parent", "ts": "1627777770.000200"},
+                {"type": "message", "user": "U2", "text": "reply", "ts": "1627777777.000100"}
+            ],
+            "has_more": false
+        }"#;
+        let res: SlackHistoryResponse = serde_json::from_str(json).unwrap();
+        let message = res.messages.into_iter().find(|m| m.ts == ts).unwrap();
+        assert_eq!(message.text, "reply");
+    }
+
+    fn permalink_tool(requester: &str, policy: AccessPolicy) -> SlackPermalinkTool {
+        let parameters: Parameters = serde_json::from_value(json!({
+            "bot_member_id": "UBOT",
+            "slack_auth_token": "xoxb",
+            "openai_secret_key": "sk",
+            "slack_signing_secret": "secret",
+        }))
+        .unwrap();
+        SlackPermalinkTool {
+            api_client: ApiClient::new(&parameters, "C01"),
+            requester: requester.into(),
+            policy,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_permalink_access_denied() {
+        let url = "https://xxx.slack.com/archives/C99/p1627777777000100";
+        let arguments = json!({ "url": url });
+
+        // リンク先のチャンネルにもAccessPolicyを適用する
+        let policy: AccessPolicy =
+            serde_json::from_value(json!({"denied_channels": ["C99"]})).unwrap();
+        let err = permalink_tool("U01", policy)
+            .execute(arguments.clone())
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<BuiltinToolError>(),
+            Some(BuiltinToolError::AccessDenied(_, _))
+        ));
+
+        // 質問者が分からない場合は、他のチャンネルのメッセージを取得しない
+        let err = permalink_tool("", AccessPolicy::default())
+            .execute(arguments)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<BuiltinToolError>(),
+            Some(BuiltinToolError::AccessDenied(_, _))
+        ));
+    }
+}
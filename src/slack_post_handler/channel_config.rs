@@ -0,0 +1,73 @@
+use serde_derive::Deserialize;
+
+use super::handle_request::{Env, Parameters};
+
+// チャンネルごとの設定。未設定の項目はEnvの値を使う
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct ChannelConfig {
+    pub model: Option<String>,
+    pub temperature: Option<f32>,
+    pub default_past_num: Option<i32>,
+    pub max_past_num: Option<i32>,
+    // デフォルトの人格の名前
+    pub persona: Option<String>,
+    // 使用するprovider名
+    pub provider: Option<String>,
+}
+
+impl ChannelConfig {
+    // Envにチャンネルの設定を上書きする
+    pub fn apply(&self, env_vars: Env) -> Env {
+        Env {
+            gpt_model: self.model.clone().unwrap_or(env_vars.gpt_model),
+            temperature: self.temperature.unwrap_or(env_vars.temperature),
+            default_past_num: self.default_past_num.or(env_vars.default_past_num),
+            max_past_num: self.max_past_num.unwrap_or(env_vars.max_past_num),
+            ..env_vars
+        }
+    }
+}
+
+// チャンネルの設定を探す
+pub fn find_channel_config<'a>(
+    parameters: &'a Parameters,
+    channel: Option<&str>,
+) -> Option<&'a ChannelConfig> {
+    channel.and_then(|c| parameters.channels.get(c))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apply() {
+        let env_vars: Env = envy::from_iter([
+            ("gpt_model".to_string(), "gpt-4o".to_string()),
+            ("temperature".to_string(), "0.2".to_string()),
+            ("default_past_num".to_string(), "6".to_string()),
+            ("max_past_num".to_string(), "10".to_string()),
+        ])
+        .unwrap();
+        let parameters: Parameters = serde_json::from_value(serde_json::json!({
+            "bot_member_id": "UBOT",
+            "slack_auth_token": "xoxb",
+            "openai_secret_key": "sk",
+            "slack_signing_secret": "secret",
+            "channels": {
+                "CDEV": {"model": "gpt-4.1", "default_past_num": 20, "max_past_num": 50},
+            },
+        }))
+        .unwrap();
+
+        let config = find_channel_config(&parameters, Some("CDEV")).unwrap();
+        let env_vars = config.apply(env_vars);
+        assert_eq!(env_vars.gpt_model, "gpt-4.1");
+        assert_eq!(env_vars.temperature, 0.2);
+        assert_eq!(env_vars.default_past_num, Some(20));
+        assert_eq!(env_vars.max_past_num, 50);
+
+        assert!(find_channel_config(&parameters, Some("CRANDOM")).is_none());
+        assert!(find_channel_config(&parameters, None).is_none());
+    }
+}
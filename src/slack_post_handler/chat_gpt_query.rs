@@ -5,9 +5,13 @@ use reqwest::{header::HeaderValue, Client};
 use serde::Serialize;
 use serde_derive::Deserialize;
 
-use crate::constants::CHAT_GPT_SYSTEM_PROMPT;
+use crate::constants::{
+    CHAT_GPT_CONTINUE_PROMPT, CHAT_GPT_SUMMARY_PROMPT, CONTENT_FILTER_NOTICE, LENGTH_LIMIT_NOTICE,
+    SUMMARY_PREFIX,
+};
 
 use super::slack_message::SlackMessage;
+use super::tool::ToolCall;
 
 #[derive(Deserialize, Serialize, Debug)]
 #[serde(rename_all = "snake_case")]
@@ -15,12 +19,19 @@ pub enum Role {
     Assistant,
     User,
     System,
+    Tool,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ChatGptQuery {
     pub role: Role,
     pub content: ChatGptQueryContentEnum,
+    // assistantが呼び出したtool
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool_calls: Option<Vec<ToolCall>>,
+    // toolの実行結果の場合、対応するtool_callのid
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool_call_id: Option<String>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -56,15 +67,113 @@ struct ImageUrl {
     url: String,
 }
 
+impl QueryContent {
+    pub fn text(&self) -> Option<&str> {
+        self.text.as_deref()
+    }
+
+    // "data:image/jpeg;base64,..."の形式の画像
+    pub fn image_url(&self) -> Option<&str> {
+        self.image_url.as_ref().map(|i| i.url.as_str())
+    }
+}
+
 impl ChatGptQuery {
-    // システムプロンプトを生成
-    pub fn new_system_prompt() -> Self {
+    fn new(role: Role, content: ChatGptQueryContentEnum) -> Self {
+        Self {
+            role,
+            content,
+            tool_calls: None,
+            tool_call_id: None,
+        }
+    }
+
+    // toolを呼び出したassistantのメッセージを生成
+    pub fn new_tool_calls(content: String, tool_calls: Vec<ToolCall>) -> Self {
         Self {
-            role: Role::System,
-            content: ChatGptQueryContentEnum::Text(CHAT_GPT_SYSTEM_PROMPT.to_string()),
+            tool_calls: Some(tool_calls),
+            ..Self::new(Role::Assistant, ChatGptQueryContentEnum::Text(content))
+        }
+    }
+
+    // toolの実行結果を生成
+    pub fn new_tool_result(tool_call_id: &str, result: String) -> Self {
+        Self {
+            tool_call_id: Some(tool_call_id.to_string()),
+            ..Self::new(Role::Tool, ChatGptQueryContentEnum::Text(result))
+        }
+    }
+
+    // テキスト部分
+    pub fn text(&self) -> &str {
+        match &self.content {
+            ChatGptQueryContentEnum::Text(text) => text,
+            ChatGptQueryContentEnum::QueryContent(contents) => contents
+                .iter()
+                .find_map(|c| c.text.as_deref())
+                .unwrap_or(""),
         }
     }
 
+    // テキスト部分を置き換える
+    pub fn set_text(&mut self, new_text: String) {
+        match &mut self.content {
+            ChatGptQueryContentEnum::Text(text) => *text = new_text,
+            ChatGptQueryContentEnum::QueryContent(contents) => {
+                if let Some(text) = contents.iter_mut().find_map(|c| c.text.as_mut()) {
+                    *text = new_text;
+                }
+            }
+        }
+    }
+
+    // 添付されている画像の数
+    pub fn image_count(&self) -> usize {
+        match &self.content {
+            ChatGptQueryContentEnum::Text(_) => 0,
+            ChatGptQueryContentEnum::QueryContent(contents) => {
+                contents.iter().filter(|c| c.image_url.is_some()).count()
+            }
+        }
+    }
+
+    // システムプロンプトを生成
+    pub fn new_system_prompt(system_prompt: &str) -> Self {
+        Self::new(
+            Role::System,
+            ChatGptQueryContentEnum::Text(system_prompt.to_string()),
+        )
+    }
+
+    // スレッドの古いメッセージの要約を生成
+    pub fn new_summary(summary: &str) -> Self {
+        Self::new(
+            Role::System,
+            ChatGptQueryContentEnum::Text(format!("{}{}", SUMMARY_PREFIX, summary)),
+        )
+    }
+
+    // 要約用の指示を生成
+    pub fn new_summary_prompt() -> Self {
+        Self::new(
+            Role::System,
+            ChatGptQueryContentEnum::Text(CHAT_GPT_SUMMARY_PROMPT.to_string()),
+        )
+    }
+
+    // 途中で止まった返答の続きを書かせる指示を生成
+    pub fn new_continue_prompt() -> Self {
+        Self::new(
+            Role::User,
+            ChatGptQueryContentEnum::Text(CHAT_GPT_CONTINUE_PROMPT.to_string()),
+        )
+    }
+
+    // ユーザーのメッセージを生成
+    pub fn new_user_text(text: String) -> Self {
+        Self::new(Role::User, ChatGptQueryContentEnum::Text(text))
+    }
+
     // SlackメッセージをChatGPTのクエリメッセージ形式に変換する
     pub async fn new_from_slack_messages(
         messages: Vec<SlackMessage>,
@@ -88,14 +197,20 @@ impl ChatGptQuery {
         bot_id: &str,
         slack_auth_token: &str,
     ) -> Result<Self> {
-        let role = if message.is_from(bot_id) {
-            Role::Assistant
+        let (role, text) = if message.is_from(bot_id) {
+            // 返答に追記した中断の知らせは会話に含めない
+            let text = message.pure_text();
+            let text = text
+                .strip_suffix(LENGTH_LIMIT_NOTICE)
+                .or_else(|| text.strip_suffix(CONTENT_FILTER_NOTICE))
+                .unwrap_or(&text)
+                .to_string();
+            (Role::Assistant, text)
         } else {
-            Role::User
+            (Role::User, message.pure_text())
         };
 
-        let text = message.pure_text();
-        let content = if message.files.is_some() {
+        let content = if let Some(files) = &message.files {
             // ファイルがある場合はテキストと画像を組み合わせる
             let text_contents = vec![QueryContent {
                 type_name: "text".into(),
@@ -103,7 +218,6 @@ impl ChatGptQuery {
                 image_url: None,
             }];
 
-            let files = message.files.as_ref().unwrap();
             let file_contents_futures = files.iter().map(|f| async {
                 let api_client = Client::new();
                 let file = api_client
@@ -135,7 +249,7 @@ impl ChatGptQuery {
 
             let combined_content = text_contents
                 .into_iter()
-                .chain(file_contents.into_iter())
+                .chain(file_contents)
                 .collect::<Vec<QueryContent>>();
             ChatGptQueryContentEnum::QueryContent(combined_content)
         } else {
@@ -143,9 +257,6 @@ impl ChatGptQuery {
             ChatGptQueryContentEnum::Text(text)
         };
 
-        Ok(Self {
-            role: role,
-            content: content,
-        })
+        Ok(Self::new(role, content))
     }
 }
@@ -8,27 +8,98 @@ pub struct ChatGptResBody {
     // created: i32,
     // system_fingerprint: String,
     pub choices: Vec<ChatGptChoice>,
+    // stream_options.include_usageを指定した場合、最後のchunkに含まれる
+    pub usage: Option<ChatGptUsage>,
 }
 
 #[derive(Deserialize, Debug, Clone, Default)]
 pub struct ChatGptChoice {
     // index: i32,
-    // finish_reason: Option<String>,
+    // 返答が終わった理由。"stop"、"length"、"content_filter"、"tool_calls"など
+    pub finish_reason: Option<String>,
     // logprobs: Option<Value>,
     pub delta: Option<ChatGptContent>,
+    // ストリーミングしない場合の返答
+    pub message: Option<ChatGptContent>,
 }
 
 #[derive(Deserialize, Debug, Clone, Default)]
 pub struct ChatGptContent {
-    pub content: String,
+    pub content: Option<String>,
+    // ストリーミングの場合は断片で届く
+    pub tool_calls: Option<Vec<ToolCallDelta>>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct ChatGptUsage {
+    #[serde(default)]
+    pub prompt_tokens: u64,
+    #[serde(default)]
+    pub completion_tokens: u64,
+}
+
+impl ChatGptUsage {
+    // toolの呼び出しで複数回リクエストした場合などに合算する
+    pub fn add(&mut self, other: &ChatGptUsage) {
+        self.prompt_tokens += other.prompt_tokens;
+        self.completion_tokens += other.completion_tokens;
+    }
+
+    pub fn total_tokens(&self) -> u64 {
+        self.prompt_tokens + self.completion_tokens
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct ToolCallDelta {
+    // 同じtool_callの断片は同じindexで届く
+    #[serde(default)]
+    pub index: usize,
+    pub id: Option<String>,
+    pub function: Option<FunctionCallDelta>,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct FunctionCallDelta {
+    pub name: Option<String>,
+    pub arguments: Option<String>,
 }
 
 impl ChatGptResBody {
+    // テキストの差分のみのchunk
+    pub fn from_text(text: &str) -> Self {
+        Self {
+            choices: vec![ChatGptChoice {
+                delta: Some(ChatGptContent {
+                    content: Some(text.to_string()),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }],
+            usage: None,
+        }
+    }
+
     pub fn get_content(&self) -> String {
         self.choices
             .iter()
-            .find_map(|choice| choice.delta.as_ref())
-            .map(|content| content.content.clone())
+            .find_map(|choice| choice.delta.as_ref().or(choice.message.as_ref()))
+            .and_then(|content| content.content.clone())
             .unwrap_or_else(|| "".to_string())
     }
+
+    pub fn get_finish_reason(&self) -> Option<String> {
+        self.choices
+            .iter()
+            .find_map(|choice| choice.finish_reason.clone())
+    }
+
+    pub fn get_tool_call_deltas(&self) -> Vec<ToolCallDelta> {
+        self.choices
+            .iter()
+            .filter_map(|choice| choice.delta.as_ref().or(choice.message.as_ref()))
+            .filter_map(|content| content.tool_calls.clone())
+            .flatten()
+            .collect()
+    }
 }
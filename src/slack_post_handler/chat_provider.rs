@@ -0,0 +1,284 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use reqwest::{header, Client, RequestBuilder, Response};
+use serde_derive::Deserialize;
+use serde_json::Value;
+
+use crate::constants::{AZURE_OPENAI_DEFAULT_API_VERSION, OPENAI_API_BASE_URL};
+
+use super::anthropic_provider::AnthropicProvider;
+use super::chat_gpt_res_body::{ChatGptResBody, ChatGptUsage};
+use super::handle_chat_gpt_response::{chat_gpt_res_stream, ChatGptResStream};
+use super::handle_request::ChatGptReqBody;
+
+// ChatGPT互換のAPIの提供元
+#[async_trait]
+pub trait ChatProvider: Send + Sync {
+    // 送信先と認証情報を設定したリクエスト
+    fn build_request(&self, client: &Client, request_body: &ChatGptReqBody) -> RequestBuilder;
+
+    // ストリーミングのレスポンスをChatGptResBodyのストリームに変換する
+    fn response_stream(&self, res: Response) -> ChatGptResStream {
+        chat_gpt_res_stream(res.bytes_stream())
+    }
+
+    // ストリーミングしない場合のレスポンスから返答の全文と使用量を取り出す
+    async fn response_text(&self, res: Response) -> Result<(String, ChatGptUsage)> {
+        let json: ChatGptResBody = res.json().await?;
+        Ok((json.get_content(), json.usage.unwrap_or_default()))
+    }
+}
+
+// パラメータの`providers`に書く設定
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ProviderConfig {
+    // api.openai.com。api_keyを省略した場合はopenai_secret_keyを使う
+    Openai {
+        api_key: Option<String>,
+    },
+    // Azure OpenAI Service
+    // NOTE: deploymentを省略した場合はモデル名をデプロイ名として使う
+    Azure {
+        endpoint: String,
+        api_key: String,
+        api_version: Option<String>,
+        deployment: Option<String>,
+        // falseの場合はstream_optionsを送らない(古いapi_versionなど、対応していない場合)
+        stream_usage: Option<bool>,
+    },
+    // llama.cppやOllamaなど、OpenAI互換のAPIを持つサーバー
+    OpenaiCompatible {
+        base_url: String,
+        api_key: Option<String>,
+        // falseの場合はstream_optionsを送らない
+        stream_usage: Option<bool>,
+    },
+    // Anthropic Messages API
+    // NOTE: toolの呼び出しには対応していない
+    Anthropic {
+        api_key: String,
+        base_url: Option<String>,
+        max_tokens: Option<u32>,
+    },
+}
+
+impl ProviderConfig {
+    pub fn build(&self, openai_secret_key: &str) -> Box<dyn ChatProvider> {
+        match self {
+            ProviderConfig::Openai { api_key } => Box::new(OpenAiProvider {
+                base_url: OPENAI_API_BASE_URL.to_string(),
+                api_key: Some(api_key.clone().unwrap_or(openai_secret_key.to_string())),
+                stream_usage: true,
+            }),
+            ProviderConfig::Azure {
+                endpoint,
+                api_key,
+                api_version,
+                deployment,
+                stream_usage,
+            } => Box::new(AzureOpenAiProvider {
+                endpoint: endpoint.clone(),
+                api_key: api_key.clone(),
+                api_version: api_version
+                    .clone()
+                    .unwrap_or(AZURE_OPENAI_DEFAULT_API_VERSION.to_string()),
+                deployment: deployment.clone(),
+                stream_usage: stream_usage.unwrap_or(true),
+            }),
+            ProviderConfig::OpenaiCompatible {
+                base_url,
+                api_key,
+                stream_usage,
+            } => Box::new(OpenAiProvider {
+                base_url: base_url.clone(),
+                api_key: api_key.clone(),
+                stream_usage: stream_usage.unwrap_or(true),
+            }),
+            ProviderConfig::Anthropic {
+                api_key,
+                base_url,
+                max_tokens,
+            } => Box::new(AnthropicProvider::new(
+                api_key,
+                base_url.as_deref(),
+                *max_tokens,
+            )),
+        }
+    }
+}
+
+// リクエストの本文。stream_usageがfalseの場合はstream_optionsを除く
+// NOTE: stream_optionsに対応していないAPIは、未知のパラメータとしてエラーを返す場合がある
+fn request_json(request_body: &ChatGptReqBody, stream_usage: bool) -> Value {
+    let mut json = serde_json::to_value(request_body).unwrap_or_default();
+    if !stream_usage {
+        if let Some(object) = json.as_object_mut() {
+            object.remove("stream_options");
+        }
+    }
+    json
+}
+
+// OpenAIと、OpenAI互換のAPI
+pub struct OpenAiProvider {
+    base_url: String,
+    api_key: Option<String>,
+    stream_usage: bool,
+}
+
+impl OpenAiProvider {
+    pub fn new(api_key: &str) -> Self {
+        Self {
+            base_url: OPENAI_API_BASE_URL.to_string(),
+            api_key: Some(api_key.to_string()),
+            stream_usage: true,
+        }
+    }
+}
+
+impl ChatProvider for OpenAiProvider {
+    fn build_request(&self, client: &Client, request_body: &ChatGptReqBody) -> RequestBuilder {
+        let url = format!("{}/chat/completions", self.base_url.trim_end_matches('/'));
+        let request = client
+            .post(url)
+            .header(header::CONTENT_TYPE, "application/json")
+            .json(&request_json(request_body, self.stream_usage));
+        match &self.api_key {
+            Some(api_key) => request.bearer_auth(api_key),
+            None => request,
+        }
+    }
+}
+
+// Azure OpenAI Service
+// NOTE: URLにデプロイ名とapi-versionを含め、api-keyヘッダーで認証する
+pub struct AzureOpenAiProvider {
+    endpoint: String,
+    api_key: String,
+    api_version: String,
+    deployment: Option<String>,
+    stream_usage: bool,
+}
+
+impl AzureOpenAiProvider {
+    fn url(&self, model: &str) -> String {
+        format!(
+            "{}/openai/deployments/{}/chat/completions?api-version={}",
+            self.endpoint.trim_end_matches('/'),
+            self.deployment.as_deref().unwrap_or(model),
+            self.api_version
+        )
+    }
+}
+
+impl ChatProvider for AzureOpenAiProvider {
+    fn build_request(&self, client: &Client, request_body: &ChatGptReqBody) -> RequestBuilder {
+        client
+            .post(self.url(request_body.model()))
+            .header(header::CONTENT_TYPE, "application/json")
+            .header("api-key", &self.api_key)
+            .json(&request_json(request_body, self.stream_usage))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_provider_config() {
+        let config: ProviderConfig = serde_json::from_value(serde_json::json!({
+            "type": "azure",
+            "endpoint": "https://example.openai.azure.com/",
+            "api_key": "key",
+        }))
+        .unwrap();
+        let ProviderConfig::Azure {
+            endpoint,
+            api_key,
+            api_version,
+            deployment,
+            stream_usage,
+        } = config
+        else {
+            panic!("unexpected config");
+        };
+        let provider = AzureOpenAiProvider {
+            endpoint,
+            api_key,
+            api_version: api_version.unwrap_or(AZURE_OPENAI_DEFAULT_API_VERSION.to_string()),
+            deployment,
+            stream_usage: stream_usage.unwrap_or(true),
+        };
+        assert_eq!(
+            provider.url("gpt-4o"),
+            format!(
+                "https://example.openai.azure.com/openai/deployments/gpt-4o/chat/completions?api-version={}",
+                AZURE_OPENAI_DEFAULT_API_VERSION
+            )
+        );
+
+        let config: ProviderConfig = serde_json::from_value(serde_json::json!({
+            "type": "openai_compatible",
+            "base_url": "http://localhost:11434/v1",
+        }))
+        .unwrap();
+        assert_eq!(
+            config,
+            ProviderConfig::OpenaiCompatible {
+                base_url: "http://localhost:11434/v1".into(),
+                api_key: None,
+                stream_usage: None,
+            }
+        );
+    }
+
+    #[test]
+    fn test_stream_usage_opt_out() {
+        let request_body = ChatGptReqBody::new(vec![], "llama3.1".into(), 0.5, true);
+        let body = |config: serde_json::Value| -> Value {
+            let config: ProviderConfig = serde_json::from_value(config).unwrap();
+            let request = config
+                .build("sk")
+                .build_request(&Client::new(), &request_body)
+                .build()
+                .unwrap();
+            serde_json::from_slice(request.body().unwrap().as_bytes().unwrap()).unwrap()
+        };
+
+        let json = body(serde_json::json!({
+            "type": "openai_compatible",
+            "base_url": "http://localhost:11434/v1",
+        }));
+        assert_eq!(json["stream_options"]["include_usage"], true);
+
+        let json = body(serde_json::json!({
+            "type": "openai_compatible",
+            "base_url": "http://localhost:11434/v1",
+            "stream_usage": false,
+        }));
+        assert_eq!(json.get("stream_options"), None);
+        assert_eq!(json["stream"], true);
+    }
+
+    #[tokio::test]
+    async fn test_response_text_with_usage() {
+        let res = Response::from(lambda_http::http::Response::new(
+            r#"{"choices":[{"message":{"role":"assistant","content":"にゃーん"}}],"usage":{"prompt_tokens":12,"completion_tokens":3}}"#,
+        ));
+        let (text, usage) = ProviderConfig::Openai { api_key: None }
+            .build("sk")
+            .response_text(res)
+            .await
+            .unwrap();
+        assert_eq!(text, "にゃーん");
+        assert_eq!(
+            usage,
+            ChatGptUsage {
+                prompt_tokens: 12,
+                completion_tokens: 3,
+            }
+        );
+    }
+}
@@ -0,0 +1,231 @@
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+use parking_lot::Mutex;
+use tiktoken_rs::tokenizer::{get_tokenizer, Tokenizer};
+use tiktoken_rs::{
+    cl100k_base_singleton, model::get_context_size, o200k_base_singleton, p50k_base_singleton,
+    p50k_edit_singleton, r50k_base_singleton, CoreBPE,
+};
+
+use crate::constants::{
+    DEFAULT_COMPLETION_RESERVE_TOKENS, DEFAULT_CONTEXT_WINDOWS, DEFAULT_CONTEXT_WINDOW_TOKENS,
+    IMAGE_TOKEN_ESTIMATE, MESSAGE_TOKEN_OVERHEAD, MIN_TRUNCATED_MESSAGE_TOKENS,
+    REPLY_TOKEN_OVERHEAD, TRUNCATED_MESSAGE_MARKER,
+};
+
+use super::chat_gpt_query::ChatGptQuery;
+
+pub struct FittedContext {
+    // system promptと、予算内に収まったメッセージ
+    pub messages: Vec<ChatGptQuery>,
+    // 予算に収まらず捨てた古いメッセージの数
+    pub dropped: usize,
+}
+
+// モデルのコンテキストウィンドウに収まるようにメッセージを選ぶ
+pub struct ContextBudget {
+    bpe: Arc<Mutex<CoreBPE>>,
+    // プロンプトに使えるトークン数(返答の分は除く)
+    prompt_tokens: usize,
+}
+
+impl ContextBudget {
+    pub fn new(
+        model: &str,
+        context_window_tokens: Option<usize>,
+        completion_reserve_tokens: Option<usize>,
+    ) -> Self {
+        let context_window =
+            context_window_tokens.unwrap_or_else(|| context_window_for_model(model));
+        // NOTE: 返答の分を空けすぎてメッセージが入らなくならないよう、半分までにする
+        let completion_reserve = completion_reserve_tokens
+            .unwrap_or(DEFAULT_COMPLETION_RESERVE_TOKENS)
+            .min(context_window / 2);
+        Self {
+            bpe: bpe_for_model(model),
+            prompt_tokens: context_window.saturating_sub(completion_reserve),
+        }
+    }
+
+    pub fn count_text(&self, text: &str) -> usize {
+        self.bpe.lock().encode_with_special_tokens(text).len()
+    }
+
+    // 1メッセージあたりのトークン数(見積もり)
+    pub fn count_query(&self, query: &ChatGptQuery) -> usize {
+        MESSAGE_TOKEN_OVERHEAD
+            + self.count_text(query.text())
+            + query.image_count() * IMAGE_TOKEN_ESTIMATE
+    }
+
+    // system promptの後ろに、新しいメッセージから順に予算内に収まるだけ詰める
+    // NOTE: messagesは時系列順であること。予算を超えるメッセージは切り詰めて、それより古いメッセージは捨てる
+    pub fn fit(
+        &self,
+        system_prompt: ChatGptQuery,
+        messages: Vec<ChatGptQuery>,
+    ) -> Vec<ChatGptQuery> {
+        self.fit_reserving(system_prompt, messages, 0).messages
+    }
+
+    // reserved_tokensの分を空けてfitする。捨てたメッセージの数も返す
+    pub fn fit_reserving(
+        &self,
+        system_prompt: ChatGptQuery,
+        messages: Vec<ChatGptQuery>,
+        reserved_tokens: usize,
+    ) -> FittedContext {
+        let mut remaining = self.prompt_tokens.saturating_sub(
+            REPLY_TOKEN_OVERHEAD + reserved_tokens + self.count_query(&system_prompt),
+        );
+
+        let total = messages.len();
+        let mut fitted = VecDeque::new();
+        for mut message in messages.into_iter().rev() {
+            let tokens = self.count_query(&message);
+            if tokens <= remaining {
+                remaining -= tokens;
+                fitted.push_front(message);
+                continue;
+            }
+
+            // 収まらない部分を切り詰める。最新のメッセージは必ず含める
+            let text_budget = remaining.saturating_sub(tokens - self.count_text(message.text()));
+            if text_budget >= MIN_TRUNCATED_MESSAGE_TOKENS || fitted.is_empty() {
+                let truncated = self.truncate_text(message.text(), text_budget);
+                message.set_text(truncated);
+                fitted.push_front(message);
+            }
+            break;
+        }
+
+        FittedContext {
+            dropped: total - fitted.len(),
+            messages: std::iter::once(system_prompt).chain(fitted).collect(),
+        }
+    }
+
+    // textをmax_tokens以内に切り詰め、末尾に省略した旨を付ける
+    pub fn truncate_text(&self, text: &str, max_tokens: usize) -> String {
+        let bpe = self.bpe.lock();
+        let marker_tokens = bpe
+            .encode_with_special_tokens(TRUNCATED_MESSAGE_MARKER)
+            .len();
+        let mut tokens = bpe.encode_with_special_tokens(text);
+        tokens.truncate(max_tokens.saturating_sub(marker_tokens));
+
+        // NOTE: マルチバイト文字の途中で切れた場合や、markerと繋げてトークン数が増えた場合は1トークンずつ戻す
+        while !tokens.is_empty() {
+            if let Ok(head) = bpe.decode(tokens.clone()) {
+                let truncated = format!("{}{}", head, TRUNCATED_MESSAGE_MARKER);
+                if bpe.encode_with_special_tokens(&truncated).len() <= max_tokens {
+                    return truncated;
+                }
+            }
+            tokens.pop();
+        }
+        TRUNCATED_MESSAGE_MARKER.to_string()
+    }
+}
+
+// モデルのコンテキストウィンドウのトークン数
+// NOTE: 組み込みの値(前方一致で最長のもの)、tiktokenの値の順に探し、どちらにも無い場合はデフォルト値を使う
+pub fn context_window_for_model(model: &str) -> usize {
+    let known = DEFAULT_CONTEXT_WINDOWS
+        .iter()
+        .filter(|(prefix, _)| model.starts_with(prefix))
+        .max_by_key(|(prefix, _)| prefix.len())
+        .map(|(_, tokens)| *tokens);
+    match known {
+        Some(tokens) => tokens,
+        None if get_tokenizer(model).is_some() => get_context_size(model),
+        None => DEFAULT_CONTEXT_WINDOW_TOKENS,
+    }
+}
+
+// モデルに対応するtokenizer。不明なモデルの場合は新しいモデルと同じo200k_baseを使う
+fn bpe_for_model(model: &str) -> Arc<Mutex<CoreBPE>> {
+    match get_tokenizer(model) {
+        Some(Tokenizer::Cl100kBase) => cl100k_base_singleton(),
+        Some(Tokenizer::P50kBase) => p50k_base_singleton(),
+        Some(Tokenizer::P50kEdit) => p50k_edit_singleton(),
+        Some(Tokenizer::R50kBase) | Some(Tokenizer::Gpt2) => r50k_base_singleton(),
+        Some(Tokenizer::O200kBase) | None => o200k_base_singleton(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::constants::CHAT_GPT_SYSTEM_PROMPT;
+
+    fn user_query(text: &str) -> ChatGptQuery {
+        ChatGptQuery::new_user_text(text.to_string())
+    }
+
+    #[test]
+    fn test_fit_keeps_newest_messages() {
+        let budget = ContextBudget::new("gpt-4o", Some(1000), Some(200));
+        let system_prompt = ChatGptQuery::new_system_prompt(CHAT_GPT_SYSTEM_PROMPT);
+        let system_tokens = budget.count_query(&system_prompt);
+        let messages: Vec<ChatGptQuery> = (0..100)
+            .map(|i| user_query(&format!("message {}", i)))
+            .collect();
+
+        let fitted = budget.fit(system_prompt, messages);
+
+        let total: usize = fitted.iter().map(|q| budget.count_query(q)).sum();
+        assert!(total + REPLY_TOKEN_OVERHEAD <= 800);
+        assert!(fitted.len() > 2 && fitted.len() < 101);
+        assert_eq!(budget.count_query(&fitted[0]), system_tokens);
+        assert_eq!(fitted.last().unwrap().text(), "message 99");
+    }
+
+    #[test]
+    fn test_fit_truncates_oversized_message() {
+        let budget = ContextBudget::new("gpt-4o", Some(1000), Some(200));
+        let messages = vec![user_query("old"), user_query(&"にゃーん ".repeat(2000))];
+
+        let fitted = budget.fit(
+            ChatGptQuery::new_system_prompt(CHAT_GPT_SYSTEM_PROMPT),
+            messages,
+        );
+
+        // 古いメッセージは捨てられ、最新のメッセージは切り詰められる
+        assert_eq!(fitted.len(), 2);
+        let latest = fitted[1].text();
+        assert!(latest.starts_with("にゃーん "));
+        assert!(latest.ends_with(TRUNCATED_MESSAGE_MARKER));
+        let total: usize = fitted.iter().map(|q| budget.count_query(q)).sum();
+        assert!(total + REPLY_TOKEN_OVERHEAD <= 800);
+    }
+
+    #[test]
+    fn test_unknown_model_keeps_latest_message() {
+        assert_eq!(context_window_for_model("gpt-4o-mini"), 128_000);
+        assert_eq!(context_window_for_model("o1-mini"), 128_000);
+        assert_eq!(context_window_for_model("claude-sonnet-4-5"), 200_000);
+        assert_eq!(
+            context_window_for_model("llama3.1"),
+            DEFAULT_CONTEXT_WINDOW_TOKENS
+        );
+
+        // 不明なモデルでも質問は切り詰めずに渡す
+        let budget = ContextBudget::new("llama3.1", None, None);
+        let fitted = budget.fit(
+            ChatGptQuery::new_system_prompt(CHAT_GPT_SYSTEM_PROMPT),
+            vec![user_query("old"), user_query("猫の好きな食べ物は？")],
+        );
+        assert_eq!(fitted.len(), 3);
+        assert_eq!(fitted[2].text(), "猫の好きな食べ物は？");
+
+        // 返答の分を空けすぎないよう、コンテキストウィンドウの半分はメッセージに使う
+        let budget = ContextBudget::new("llama3.1", Some(4096), None);
+        let fitted = budget.fit(
+            ChatGptQuery::new_system_prompt(CHAT_GPT_SYSTEM_PROMPT),
+            vec![user_query("猫の好きな食べ物は？")],
+        );
+        assert_eq!(fitted[1].text(), "猫の好きな食べ物は？");
+    }
+}
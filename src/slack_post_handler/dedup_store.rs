@@ -0,0 +1,157 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use aws_config::BehaviorVersion;
+use aws_sdk_dynamodb::error::SdkError;
+use aws_sdk_dynamodb::types::AttributeValue;
+use aws_sdk_dynamodb::Client;
+use thiserror::Error;
+
+use super::handle_request::get_enviroment_variable;
+
+// 処理済みイベントを保持する秒数(Slackのリトライは数分以内に行われる)
+const DEDUP_TTL_SECS: i64 = 60 * 60;
+
+#[derive(Error, Debug)]
+pub enum DedupStoreError {
+    #[error("DynamoDB error: {0}")]
+    DynamoDbError(String),
+}
+
+// 処理済みのSlackイベントを記録する
+#[async_trait]
+pub trait DedupStore: Send + Sync {
+    // 未処理のイベントの場合のみ記録してtrueを返す。処理済みの場合はfalseを返す
+    async fn try_record(&self, event_id: &str, event_time: i64) -> Result<bool>;
+    // 記録を取り消す(workerへの受け渡しに失敗した場合など)
+    async fn remove(&self, event_id: &str) -> Result<()>;
+}
+
+fn now_secs() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+// プロセス内のメモリに記録する(ローカル実行・テスト用)
+#[derive(Default)]
+pub struct InMemoryDedupStore {
+    events: Mutex<HashMap<String, i64>>,
+}
+
+#[async_trait]
+impl DedupStore for InMemoryDedupStore {
+    async fn try_record(&self, event_id: &str, event_time: i64) -> Result<bool> {
+        let mut events = self.events.lock().unwrap();
+        // 古いイベントは削除する
+        let expired_before = now_secs() - DEDUP_TTL_SECS;
+        events.retain(|_, recorded_at| *recorded_at > expired_before);
+
+        if events.contains_key(event_id) {
+            return Ok(false);
+        }
+        events.insert(event_id.into(), event_time.max(now_secs()));
+        Ok(true)
+    }
+
+    async fn remove(&self, event_id: &str) -> Result<()> {
+        self.events.lock().unwrap().remove(event_id);
+        Ok(())
+    }
+}
+
+// DynamoDBのテーブルに記録する
+// パーティションキーはevent_id(S)、TTL属性はexpires_at(N)
+// endpoint_urlを指定するとDynamoDB Localなどに接続できる
+pub struct DynamoDbDedupStore {
+    client: Client,
+    table_name: String,
+}
+
+impl DynamoDbDedupStore {
+    pub async fn new(table_name: &str, endpoint_url: Option<&str>) -> Self {
+        let shared_config = aws_config::load_defaults(BehaviorVersion::v2023_11_09()).await;
+        let mut config_builder = aws_sdk_dynamodb::config::Builder::from(&shared_config);
+        if let Some(endpoint_url) = endpoint_url {
+            config_builder = config_builder.endpoint_url(endpoint_url);
+        }
+        Self {
+            client: Client::from_conf(config_builder.build()),
+            table_name: table_name.into(),
+        }
+    }
+}
+
+#[async_trait]
+impl DedupStore for DynamoDbDedupStore {
+    async fn try_record(&self, event_id: &str, event_time: i64) -> Result<bool> {
+        let res = self
+            .client
+            .put_item()
+            .table_name(&self.table_name)
+            .item("event_id", AttributeValue::S(event_id.into()))
+            .item("event_time", AttributeValue::N(event_time.to_string()))
+            .item(
+                "expires_at",
+                AttributeValue::N((now_secs() + DEDUP_TTL_SECS).to_string()),
+            )
+            .condition_expression("attribute_not_exists(event_id)")
+            .send()
+            .await;
+
+        match res {
+            Ok(_) => Ok(true),
+            // 既に記録されている場合は条件付き書き込みが失敗する
+            Err(SdkError::ServiceError(e)) if e.err().is_conditional_check_failed_exception() => {
+                Ok(false)
+            }
+            Err(e) => Err(DedupStoreError::DynamoDbError(e.to_string()).into()),
+        }
+    }
+
+    async fn remove(&self, event_id: &str) -> Result<()> {
+        self.client
+            .delete_item()
+            .table_name(&self.table_name)
+            .key("event_id", AttributeValue::S(event_id.into()))
+            .send()
+            .await
+            .map_err(|e| DedupStoreError::DynamoDbError(e.to_string()))?;
+        Ok(())
+    }
+}
+
+// 環境変数dedup_table_nameがあればDynamoDB、なければメモリに記録する
+pub async fn dedup_store_from_env() -> Result<Box<dyn DedupStore>> {
+    let env_vars = get_enviroment_variable()?;
+    let store: Box<dyn DedupStore> = match env_vars.dedup_table_name {
+        Some(table_name) => Box::new(
+            DynamoDbDedupStore::new(&table_name, env_vars.dedup_table_endpoint.as_deref()).await,
+        ),
+        None => Box::<InMemoryDedupStore>::default(),
+    };
+    Ok(store)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_in_memory_dedup_store() {
+        let store = InMemoryDedupStore::default();
+        let event_time = now_secs();
+
+        assert!(store.try_record("Ev01", event_time).await.unwrap());
+        assert!(!store.try_record("Ev01", event_time).await.unwrap());
+        assert!(store.try_record("Ev02", event_time).await.unwrap());
+
+        // 取り消した場合は再度記録できる
+        store.remove("Ev01").await.unwrap();
+        assert!(store.try_record("Ev01", event_time).await.unwrap());
+    }
+}
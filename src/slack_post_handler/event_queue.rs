@@ -0,0 +1,113 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use aws_config::BehaviorVersion;
+use aws_sdk_lambda::{primitives::Blob, types::InvocationType, Client};
+use serde_derive::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::sync::mpsc;
+
+use super::handle_request::SlackEvent;
+use super::slash_command::SlashCommand;
+
+#[derive(Error, Debug)]
+pub enum EventQueueError {
+    #[error("Local queue is closed")]
+    LocalQueueClosed,
+    #[error("Failed to invoke worker: {0}")]
+    InvokeWorkerError(String),
+}
+
+// workerで処理する内容
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum QueuedJob {
+    SlackEvent(Box<SlackEvent>),
+    SlashCommand(SlashCommand),
+}
+
+// Slackイベントをworkerに渡すためのキュー
+#[async_trait]
+pub trait EventQueue: Send + Sync {
+    async fn enqueue(&self, job: QueuedJob) -> Result<()>;
+}
+
+// 同一プロセス内のチャンネルに積むキュー(ローカル実行・テスト用)
+pub struct LocalEventQueue {
+    sender: mpsc::UnboundedSender<QueuedJob>,
+}
+
+impl LocalEventQueue {
+    // キューと、worker側で読み出すためのreceiverを生成する
+    pub fn new() -> (Self, mpsc::UnboundedReceiver<QueuedJob>) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        (Self { sender }, receiver)
+    }
+}
+
+#[async_trait]
+impl EventQueue for LocalEventQueue {
+    async fn enqueue(&self, job: QueuedJob) -> Result<()> {
+        self.sender
+            .send(job)
+            .map_err(|_| EventQueueError::LocalQueueClosed)?;
+        Ok(())
+    }
+}
+
+// worker用のLambda関数を非同期(Event)で呼び出すキュー
+pub struct LambdaEventQueue {
+    client: Client,
+    function_name: String,
+}
+
+impl LambdaEventQueue {
+    pub async fn new(function_name: &str) -> Self {
+        let shared_config = aws_config::load_defaults(BehaviorVersion::v2023_11_09()).await;
+        Self {
+            client: Client::new(&shared_config),
+            function_name: function_name.into(),
+        }
+    }
+}
+
+#[async_trait]
+impl EventQueue for LambdaEventQueue {
+    async fn enqueue(&self, job: QueuedJob) -> Result<()> {
+        let payload = serde_json::to_vec(&job)?;
+        self.client
+            .invoke()
+            .function_name(&self.function_name)
+            .invocation_type(InvocationType::Event)
+            .payload(Blob::new(payload))
+            .send()
+            .await
+            .map_err(|e| EventQueueError::InvokeWorkerError(e.to_string()))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_local_event_queue() {
+        let (queue, mut receiver) = LocalEventQueue::new();
+        let slack_event: SlackEvent =
+            serde_json::from_str(r#"{"type": "event_callback", "event": null}"#).unwrap();
+        queue
+            .enqueue(QueuedJob::SlackEvent(Box::new(slack_event)))
+            .await
+            .unwrap();
+
+        // Lambdaのpayloadとしてシリアライズしても復元できる
+        let received = receiver.recv().await.unwrap();
+        let payload = serde_json::to_string(&received).unwrap();
+        match serde_json::from_str::<QueuedJob>(&payload).unwrap() {
+            QueuedJob::SlackEvent(slack_event) => {
+                assert_eq!(slack_event.type_name, "event_callback")
+            }
+            _ => panic!("unexpected job"),
+        }
+    }
+}
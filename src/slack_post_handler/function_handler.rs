@@ -0,0 +1,25 @@
+use lambda_http::{Body, Error, Request, Response};
+
+use super::dedup_store::DedupStore;
+use super::event_queue::EventQueue;
+use super::handle_request::{handle_request, Parameters};
+use super::usage_ledger::UsageStore;
+
+// slackからのリクエストを受け取る
+pub async fn function_handler(
+    event: Request,
+    parameters: Parameters,
+    event_queue: &dyn EventQueue,
+    dedup_store: &dyn DedupStore,
+    usage_store: &dyn UsageStore,
+) -> Result<Response<Body>, Error> {
+    let response_body =
+        handle_request(event, parameters, event_queue, dedup_store, usage_store).await;
+
+    let resp = Response::builder()
+        .status(200)
+        .header("content-type", "text/html")
+        .body(Body::from(response_body))
+        .map_err(Box::new)?;
+    Ok(resp)
+}
@@ -1,8 +1,22 @@
-use super::{api_client::ApiClient, chat_gpt_res_body::ChatGptResBody};
-use crate::constants::ERROR_FROM_OPEN_AI_MESSAGE;
+use super::api_client::rate_limited_duration;
+use super::chat_gpt_query::ChatGptQuery;
+use super::handle_request::ChatGptReqBody;
+use super::message_splitter::split_message;
+use super::sse_decoder::sse_events;
+use super::tool::{ToolCallAccumulator, ToolRegistry};
+use super::{
+    api_client::ApiClient,
+    chat_gpt_res_body::{ChatGptResBody, ChatGptUsage},
+};
+use crate::constants::{
+    CONTENT_FILTER_NOTICE, ERROR_FROM_OPEN_AI_MESSAGE, LENGTH_LIMIT_NOTICE, LOADING_EMOJI,
+    MAX_TOOL_ROUNDS, SLACK_MESSAGE_TEXT_LIMIT, SLACK_UPDATE_INTERVAL, SLACK_UPDATE_MAX_INTERVAL,
+    TOOL_RUNNING_MESSAGE,
+};
 use anyhow::Result;
-use futures::StreamExt;
-use reqwest::Response;
+use futures::stream::BoxStream;
+use futures::{future, Stream, StreamExt};
+use std::collections::VecDeque;
 use std::time::{Duration, Instant};
 use thiserror::Error;
 
@@ -10,231 +24,314 @@ use thiserror::Error;
 pub enum OpenAIError {
     #[error("Reading Stream Error: {0}")]
     ReadingStream(String),
+    #[error("Invalid chunk: {0}, {1}")]
+    InvalidChunk(String, String),
 }
 
+pub type ChatGptResStream = BoxStream<'static, Result<ChatGptResBody>>;
+
+// OpenAIのストリームをChatGptResBodyのストリームに変換する
+// NOTE: "data: [DONE]"を受け取った時点でストリームを終了する
+pub fn chat_gpt_res_stream<S, B, E>(byte_stream: S) -> ChatGptResStream
+where
+    S: Stream<Item = Result<B, E>> + Send + 'static,
+    B: AsRef<[u8]> + Send + 'static,
+    E: std::fmt::Display + Send + 'static,
+{
+    sse_events(byte_stream)
+        .take_while(|event| future::ready(!matches!(event, Ok(event) if event.data == "[DONE]")))
+        .map(|event| {
+            let event = event.map_err(|e| OpenAIError::ReadingStream(e.to_string()))?;
+            let json: ChatGptResBody = serde_json::from_str(&event.data)
+                .map_err(|e| OpenAIError::InvalidChunk(e.to_string(), event.data.clone()))?;
+            Ok(json)
+        })
+        .boxed()
+}
+
+// 返答が途中で止まった場合に末尾に追記する知らせ
+pub fn finish_reason_notice(finish_reason: &str) -> Option<&'static str> {
+    match finish_reason {
+        "length" => Some(LENGTH_LIMIT_NOTICE),
+        "content_filter" => Some(CONTENT_FILTER_NOTICE),
+        _ => None,
+    }
+}
+
+// ChatGPTの返答をストリーミングでSlackに投稿し、トークンの使用量をusageに加算する
+// reply_tsには返答を書き込むメッセージのtsを渡し、投稿し終えたすべてのメッセージのtsが返る
+// NOTE: toolの呼び出しがある場合は、実行結果を渡して最終的な返答が得られるまで繰り返す
+// NOTE: 途中でエラーになった場合も、それまでに受け取った使用量や投稿したtsを記録できるよう残す
 pub async fn handle_chat_gpt_response(
-    res: Response,
+    request_body: ChatGptReqBody,
     api_client: ApiClient,
-    bot_message_ts: &str,
+    reply_ts: &mut Vec<String>,
+    thread_ts: Option<&str>,
+    tools: &ToolRegistry,
+    usage: &mut ChatGptUsage,
 ) -> Result<()> {
-    let mut stream = res.bytes_stream();
-
-    let mut last_update = Instant::now() - Duration::from_secs(1);
-    let mut text = String::new();
-    let mut last_post_text = String::new();
-    // 途切れた文字列を保持する
-    let mut partial_str = String::new();
-    let mut partial_bytes: Vec<u8> = Vec::new();
-
-    while let Some(item) = stream.next().await {
-        match item {
-            Ok(chunk) => {
-                for line in chunk.split(|&c| c == b'\n') {
-                    match std::str::from_utf8(line) {
-                        Ok(p) => {
-                            match p.strip_prefix("data: ") {
-                                Some(p) => {
-                                    if p == "[DONE]" {
-                                        break;
-                                    }
-
-                                    let json: ChatGptResBody = match serde_json::from_str(p) {
-                                        Ok(val) => val,
-                                        Err(_) => {
-                                            // jsonに変換できない場合は途切れた文字列として一旦保持する
-                                            partial_str = "data: ".to_string() + p;
-                                            continue;
-                                        }
-                                    };
-
-                                    update_message_every_second(
-                                        json,
-                                        &mut text,
-                                        &api_client,
-                                        &mut last_update,
-                                        &mut last_post_text,
-                                        bot_message_ts,
-                                    )
-                                    .await?;
-                                }
-                                None => {
-                                    // 前回途切れた文字列に結合する
-                                    partial_str.push_str(p);
-
-                                    // 完全な文字列になったか確認する
-                                    let should_break = update_message_if_complite_string(
-                                        &mut partial_str,
-                                        &mut text,
-                                        &api_client,
-                                        &mut last_update,
-                                        &mut last_post_text,
-                                        bot_message_ts,
-                                    )
-                                    .await?;
-
-                                    if should_break {
-                                        break;
-                                    }
-                                }
-                            }
-                        }
-                        Err(_) => {
-                            // UTF-8に変換できない場合はpartial_bytesに追加する
-                            partial_bytes.extend_from_slice(line);
-
-                            // 完全な文字列になったか確認する
-                            let should_break = update_message_if_complite_bytes(
-                                &mut partial_bytes,
-                                &mut text,
-                                &api_client,
-                                &mut last_update,
-                                &mut last_post_text,
-                                bot_message_ts,
-                            )
-                            .await?;
-
-                            if should_break {
-                                break;
-                            }
-                        }
-                    }
-                }
+    let mut message = StreamingMessage::new(&api_client, reply_ts, thread_ts);
+    let result = stream_response(request_body, &mut message, tools, usage).await;
+    // NOTE: 途中で止まった場合に削除できなかった以前の返答の続きも、次に返答し直すときのために残す
+    *reply_ts = message
+        .reply_ts
+        .iter()
+        .chain(message.spare_ts.iter())
+        .cloned()
+        .collect();
+    result
+}
+
+async fn stream_response(
+    mut request_body: ChatGptReqBody,
+    message: &mut StreamingMessage<'_>,
+    tools: &ToolRegistry,
+    usage: &mut ChatGptUsage,
+) -> Result<()> {
+    let api_client = message.api_client;
+    let mut finish_reason = None;
+
+    for round in 0..=MAX_TOOL_ROUNDS {
+        // 上限に達した場合はtoolを呼び出さずに返答させる
+        if round == MAX_TOOL_ROUNDS {
+            request_body.disable_tool_calls();
+        }
+        let mut stream = api_client
+            .get_chat_gpt_response(&request_body, &message.current_ts)
+            .await?;
+        let mut content = String::new();
+        let mut tool_calls = ToolCallAccumulator::default();
+        while let Some(item) = stream.next().await {
+            let json = item?;
+            let text = json.get_content();
+            message.push(&text).await?;
+            content.push_str(&text);
+            tool_calls.push(json.get_tool_call_deltas());
+            if let Some(reason) = json.get_finish_reason() {
+                finish_reason = Some(reason);
             }
-            Err(e) => {
-                return Err(OpenAIError::ReadingStream(e.to_string()).into());
+            if let Some(chunk_usage) = &json.usage {
+                usage.add(chunk_usage);
             }
         }
-    }
 
-    // 未投稿の文がある場合は更新する
-    let text_to_post = if text == "" {
-        // 文が空の場合はエラー文を投稿する
-        ERROR_FROM_OPEN_AI_MESSAGE
-    } else {
-        text.as_str()
-    };
-    api_client
-        .update_message(text_to_post, bot_message_ts)
-        .await?;
-    Ok(())
-}
+        let tool_calls = tool_calls.finish();
+        if tool_calls.is_empty() {
+            break;
+        }
 
-async fn update_message_if_complite_string(
-    partial_str: &mut String,
-    text: &mut String,
-    api_client: &ApiClient,
-    last_update: &mut Instant,
-    last_post_text: &mut String,
-    bot_message_ts: &str,
-) -> Result<bool> {
-    match partial_str.strip_prefix("data: ") {
-        Some(ps) => {
-            if ps == "[DONE]" {
-                return Ok(true); // NOTE: trueの場合はbreakする
-            }
-            match serde_json::from_str(ps) {
-                Ok(val) => {
-                    let json: ChatGptResBody = val;
-
-                    update_message_every_second(
-                        json,
-                        text,
-                        &api_client,
-                        last_update,
-                        last_post_text,
-                        bot_message_ts,
-                    )
-                    .await?;
-
-                    *partial_str = String::new();
-                    return Ok(false);
-                }
-                Err(_) => {
-                    // jsonに変換できない場合は次のchunkを待つ
-                    return Ok(false);
-                }
+        // toolを実行し、呼び出しと結果を会話に追加する
+        let names: Vec<String> = tool_calls
+            .iter()
+            .map(|call| format!("`{}`", call.function.name))
+            .collect();
+        message
+            .show_status(&format!("{} ({})", TOOL_RUNNING_MESSAGE, names.join(", ")))
+            .await;
+        request_body.push_message(ChatGptQuery::new_tool_calls(content, tool_calls.clone()));
+        for call in &tool_calls {
+            #[cfg(debug_assertions)]
+            {
+                println!(
+                    "Tool call: {}({})",
+                    call.function.name, call.function.arguments
+                );
             }
+            let result = tools.execute(call).await;
+            request_body.push_message(ChatGptQuery::new_tool_result(&call.id, result));
         }
-        None => {
-            // "data: "から始まっていない場合は次のchunkを待つ
-            return Ok(false);
-        }
     }
+
+    #[cfg(debug_assertions)]
+    {
+        println!(
+            "Usage: model={}, prompt_tokens={}, completion_tokens={}, finish_reason={:?}",
+            request_body.model(),
+            usage.prompt_tokens,
+            usage.completion_tokens,
+            finish_reason
+        );
+    }
+    // 長さの上限やコンテンツフィルターで止まった場合は知らせる
+    if let Some(notice) = finish_reason.as_deref().and_then(finish_reason_notice) {
+        message.push(notice).await?;
+    }
+    message.finish().await
+}
+
+// ストリーミング中のSlackへの返答
+// NOTE: Slackのメッセージの文字数制限を超える場合は、続きを新しいメッセージに投稿する
+//       返答し直す場合は、以前の返答の続きのメッセージを使い回し、余った分は削除する
+struct StreamingMessage<'a> {
+    api_client: &'a ApiClient,
+    thread_ts: Option<String>,
+    // 投稿したメッセージのts。末尾が更新中のメッセージ
+    reply_ts: Vec<String>,
+    // 使い回せる以前の返答の続きのメッセージのts
+    spare_ts: VecDeque<String>,
+    // 更新中の末尾のメッセージ
+    current_ts: String,
+    current_text: String,
+    last_update: Instant,
+    // 途中経過を更新する間隔。レート制限にかかった場合は延ばす
+    update_interval: Duration,
+    is_empty: bool,
 }
 
-async fn update_message_if_complite_bytes(
-    partial_bytes: &mut Vec<u8>,
-    text: &mut String,
-    api_client: &ApiClient,
-    last_update: &mut Instant,
-    last_post_text: &mut String,
-    bot_message_ts: &str,
-) -> Result<bool> {
-    match std::str::from_utf8(&partial_bytes) {
-        Ok(ps) => {
-            match ps.strip_prefix("data: ") {
-                Some(ps) => {
-                    if ps == "[DONE]" {
-                        return Ok(true); // NOTE: trueの場合はbreakする
-                    }
-                    match serde_json::from_str(ps) {
-                        Ok(val) => {
-                            let json: ChatGptResBody = val;
-
-                            update_message_every_second(
-                                json,
-                                text,
-                                &api_client,
-                                last_update,
-                                last_post_text,
-                                bot_message_ts,
-                            )
-                            .await?;
-
-                            // 保持したbyte文字列をクリアする
-                            partial_bytes.clear();
-                            return Ok(false);
-                        }
-                        Err(_) => {
-                            // jsonに変換できない場合は次のchunkを待つ
-                            return Ok(false);
-                        }
-                    }
+impl<'a> StreamingMessage<'a> {
+    fn new(api_client: &'a ApiClient, reply_ts: &[String], thread_ts: Option<&str>) -> Self {
+        let mut spare_ts: VecDeque<String> = reply_ts.iter().cloned().collect();
+        let current_ts = spare_ts.pop_front().unwrap_or_default();
+        Self {
+            api_client,
+            thread_ts: thread_ts.map(|ts| ts.to_string()),
+            reply_ts: vec![current_ts.clone()],
+            spare_ts,
+            current_ts,
+            current_text: String::new(),
+            last_update: Instant::now() - SLACK_UPDATE_INTERVAL,
+            update_interval: SLACK_UPDATE_INTERVAL,
+            is_empty: true,
+        }
+    }
+
+    async fn push(&mut self, content: &str) -> Result<()> {
+        if content.is_empty() {
+            return Ok(());
+        }
+        self.is_empty = false;
+        self.current_text.push_str(content);
+
+        // 文字数制限を超えた分は新しいメッセージに投稿する
+        while let Some((head, rest)) = split_message(&self.current_text, SLACK_MESSAGE_TEXT_LIMIT) {
+            self.api_client
+                .update_message(&head, &self.current_ts)
+                .await?;
+            self.current_ts = match self.spare_ts.pop_front() {
+                Some(ts) => {
+                    self.api_client.update_message(LOADING_EMOJI, &ts).await?;
+                    ts
                 }
                 None => {
-                    // "data: "から始まっていない場合は次のchunkを待つ
-                    return Ok(false);
+                    self.api_client
+                        .post_message(
+                            self.api_client.channel(),
+                            LOADING_EMOJI,
+                            self.thread_ts.as_deref(),
+                        )
+                        .await?
+                }
+            };
+            self.reply_ts.push(self.current_ts.clone());
+            self.current_text = rest;
+            self.last_update = Instant::now() - self.update_interval;
+        }
+
+        // NOTE: update_interval(通常は1秒)に1回更新する
+        if self.last_update.elapsed() > self.update_interval {
+            self.last_update = Instant::now();
+            let res = self
+                .api_client
+                .try_update_message(&self.current_text, &self.current_ts)
+                .await;
+            if let Err(e) = res {
+                // レート制限の場合は返答を中断せず、更新の間隔を延ばす
+                let retry_after = rate_limited_duration(&e).ok_or(e)?;
+                self.update_interval = (self.update_interval * 2)
+                    .max(retry_after)
+                    .min(SLACK_UPDATE_MAX_INTERVAL);
+                #[cfg(debug_assertions)]
+                {
+                    println!(
+                        "Slack rate limited, update interval: {:?}",
+                        self.update_interval
+                    );
                 }
             }
         }
-        Err(_) => {
-            // UTF-8に変換できない場合は次のchunkを待つ
-            return Ok(false);
+        Ok(())
+    }
+
+    // 返答の末尾にtoolの実行状況などを一時的に表示する
+    async fn show_status(&mut self, status: &str) {
+        let text = format!("{}\n{}", self.current_text, status);
+        if let Err(e) = self
+            .api_client
+            .try_update_message(text.trim_start(), &self.current_ts)
+            .await
+        {
+            eprintln!("Error: {}", e);
+        }
+        self.last_update = Instant::now();
+    }
+
+    // 未投稿の文がある場合は更新し、使わなかった以前の返答の続きを削除する
+    async fn finish(&mut self) -> Result<()> {
+        let text_to_post = if self.is_empty {
+            // 文が空の場合はエラー文を投稿する
+            ERROR_FROM_OPEN_AI_MESSAGE
+        } else {
+            self.current_text.as_str()
+        };
+        self.api_client
+            .update_message(text_to_post, &self.current_ts)
+            .await?;
+        while let Some(ts) = self.spare_ts.pop_front() {
+            if let Err(e) = self.api_client.delete_message(&ts).await {
+                eprintln!("Error: {}", e);
+            }
         }
+        Ok(())
     }
 }
 
-async fn update_message_every_second(
-    json: ChatGptResBody,
-    text: &mut String,
-    api_client: &ApiClient,
-    last_update: &mut Instant,
-    last_post_text: &mut String,
-    bot_message_ts: &str,
-) -> Result<()> {
-    let content = json.get_content();
-    if content.len() == 0 {
-        return Ok(());
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::stream;
+
+    #[tokio::test]
+    async fn test_chat_gpt_res_stream() {
+        let input =
+            "data: {\"choices\":[{\"delta\":{\"role\":\"assistant\",\"content\":\"\"}}]}\n\n\
+data: {\"choices\":[{\"delta\":{\"content\":\"にゃ\"}}]}\n\n\
+data: {\"choices\":[{\"delta\":{}}]}\n\n\
+data: [DONE]\n\n\
+data: {\"choices\":[{\"delta\":{\"content\":\"ignored\"}}]}\n\n";
+        let bytes = input.as_bytes();
+        // 1byteずつ届いても同じ結果になる
+        let chunks = bytes.chunks(1).map(Ok::<_, std::io::Error>);
+        let contents: Vec<String> = chat_gpt_res_stream(stream::iter(chunks))
+            .map(|json| json.unwrap().get_content())
+            .collect()
+            .await;
+
+        assert_eq!(contents, vec!["", "にゃ", ""]);
     }
 
-    // textに追加
-    text.push_str(content.as_str());
+    #[tokio::test]
+    async fn test_finish_reason_and_usage() {
+        let input = "data: {\"choices\":[{\"delta\":{\"content\":\"にゃ\"},\"finish_reason\":null}]}\n\n\
+data: {\"choices\":[{\"delta\":{},\"finish_reason\":\"length\"}]}\n\n\
+data: {\"choices\":[],\"usage\":{\"prompt_tokens\":10,\"completion_tokens\":2,\"total_tokens\":12}}\n\n\
+data: [DONE]\n\n";
+        let chunks = input.as_bytes().chunks(16).map(Ok::<_, std::io::Error>);
+        let jsons: Vec<ChatGptResBody> = chat_gpt_res_stream(stream::iter(chunks))
+            .map(|json| json.unwrap())
+            .collect()
+            .await;
 
-    // NOTE: 1秒に1回更新する
-    if last_update.elapsed() > Duration::from_millis(1000) {
-        *last_update = Instant::now();
-        *last_post_text = text.to_string();
-        api_client.update_message(text, bot_message_ts).await?;
+        let finish_reasons: Vec<Option<String>> =
+            jsons.iter().map(|json| json.get_finish_reason()).collect();
+        assert_eq!(finish_reasons, vec![None, Some("length".into()), None]);
+        assert_eq!(
+            jsons[2].usage,
+            Some(ChatGptUsage {
+                prompt_tokens: 10,
+                completion_tokens: 2,
+            })
+        );
+        assert_eq!(finish_reason_notice("length"), Some(LENGTH_LIMIT_NOTICE));
+        assert_eq!(finish_reason_notice("stop"), None);
     }
-    Ok(())
 }
@@ -0,0 +1,47 @@
+use anyhow::Result;
+
+use super::event_queue::QueuedJob;
+use super::handle_request::{handle_slack_event, Parameters};
+use super::parameter_provider::ParameterProvider;
+use super::reply_store::ReplyStore;
+use super::slash_command::handle_slash_command;
+use super::usage_ledger::UsageStore;
+
+// キューから受け取った内容に応じて処理する
+pub async fn process_queued_job(
+    job: QueuedJob,
+    parameters: Parameters,
+    usage_store: &dyn UsageStore,
+    reply_store: &dyn ReplyStore,
+) -> Result<()> {
+    match job {
+        QueuedJob::SlackEvent(slack_event) => {
+            handle_slack_event(*slack_event, parameters, usage_store, reply_store).await
+        }
+        QueuedJob::SlashCommand(slash_command) => {
+            handle_slash_command(slash_command, parameters, usage_store).await
+        }
+    }
+}
+
+// キューから受け取った内容をworkerとして処理する
+pub async fn handle_queued_event(
+    job: QueuedJob,
+    parameter_provider: &dyn ParameterProvider,
+    usage_store: &dyn UsageStore,
+    reply_store: &dyn ReplyStore,
+) {
+    let parameters = match parameter_provider.get_parameters().await {
+        Ok(val) => val,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            return;
+        }
+    };
+
+    process_queued_job(job, parameters, usage_store, reply_store)
+        .await
+        .unwrap_or_else(|e| {
+            eprintln!("Error: {}", e);
+        });
+}
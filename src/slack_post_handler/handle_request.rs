@@ -1,27 +1,70 @@
+use std::collections::HashMap;
+
 use anyhow::Result;
-use aws_config::{BehaviorVersion, Region};
-use aws_sdk_ssm::Client;
-use lambda_http::{Body, Error, Request};
+use lambda_http::{Body, Request};
 use serde_derive::{Deserialize, Serialize};
 use thiserror::Error;
 
 use crate::constants::{
-    INVALID_IMAGE_FORMAT, LOADING_EMOJI, NO_CONTEXTS_MESSAGE, VALID_MIME_TYPES,
+    DEFAULT_SIGNATURE_TOLERANCE_SECS, INVALID_IMAGE_FORMAT, LOADING_EMOJI,
+    MESSAGE_DIRECTIVES_HELP_MESSAGE, NO_CONTEXTS_MESSAGE, SLACK_MAX_REPLIES, SUMMARY_MAX_TOKENS,
+    UNKNOWN_DIRECTIVES_MESSAGE, VALID_MIME_TYPES,
 };
-use crate::slack_post_handler::api_client::ApiClient;
+use crate::slack_post_handler::api_client::{newest_messages, ApiClient};
 use crate::slack_post_handler::slack_message::SlackMessage;
 
+use super::access_policy::AccessPolicy;
+use super::builtin_tools::tools_from_env;
+use super::channel_config::{find_channel_config, ChannelConfig};
 use super::chat_gpt_query::ChatGptQuery;
+use super::chat_gpt_res_body::ChatGptUsage;
+use super::chat_provider::ProviderConfig;
+use super::context_budget::ContextBudget;
+use super::dedup_store::DedupStore;
+use super::event_queue::{EventQueue, QueuedJob};
 use super::handle_chat_gpt_response::handle_chat_gpt_response;
+use super::message_directives::MessageDirectives;
+use super::persona::{find_persona, resolve_persona, Persona};
+use super::quota::{quota_exceeded_message, QuotaConfig};
+use super::reply_store::ReplyStore;
+use super::slash_command::handle_slash_command_request;
+use super::thread_summary::{summarize_thread, SummaryTarget};
+use super::tool::ToolDefinition;
+use super::usage_ledger::{record_usage, ModelPrice, UsageStore};
 use super::validate_slack_signature::validate_slack_signature;
 
 #[derive(Deserialize)]
 pub struct Env {
     pub gpt_model: String,
-    pub parameter_store_name: String,
+    pub parameter_source: Option<String>,
+    pub parameter_store_name: Option<String>,
+    pub parameter_store_region: Option<String>,
+    pub parameter_file_path: Option<String>,
+    pub parameter_cache_ttl_secs: Option<u64>,
     pub temperature: f32,
-    pub default_past_num: i32,
+    // 未設定の場合は、pastの指定が無ければmax_past_numまで取得してトークン数で絞る
+    pub default_past_num: Option<i32>,
     pub max_past_num: i32,
+    // 未設定の場合はgpt_modelから判断する
+    pub context_window_tokens: Option<usize>,
+    pub completion_reserve_tokens: Option<usize>,
+    // trueの場合、スレッドの古いメッセージを捨てずに要約して渡す
+    pub summarize_threads: Option<bool>,
+    pub worker_function_name: Option<String>,
+    pub dedup_table_name: Option<String>,
+    pub dedup_table_endpoint: Option<String>,
+    pub signature_tolerance_secs: Option<i64>,
+    // app_mentionイベントを購読している場合はtrue
+    pub use_app_mention: Option<bool>,
+    // trueの場合、ChatGPTから組み込みのtoolを呼び出せるようにする
+    pub use_tools: Option<bool>,
+    // 使用量の記録先
+    pub usage_table_name: Option<String>,
+    pub usage_table_endpoint: Option<String>,
+    pub usage_file_path: Option<String>,
+    // 返答のきっかけとなったメッセージと返答の対応の記録先
+    pub reply_table_name: Option<String>,
+    pub reply_table_endpoint: Option<String>,
 }
 
 #[derive(Deserialize, Clone)]
@@ -30,11 +73,62 @@ pub struct Parameters {
     pub slack_auth_token: String,
     pub openai_secret_key: String,
     slack_signing_secret: String,
+    // ローテーション中の新しいsecretなど、追加で受け付けるsecret
+    #[serde(default)]
+    slack_signing_secrets: Vec<String>,
+    // 組み込みのネコ型以外の人格
+    #[serde(default)]
+    pub personas: Vec<Persona>,
+    // チャンネルIDごとの設定
+    #[serde(default)]
+    pub channels: HashMap<String, ChannelConfig>,
+    // OpenAI以外のAPIの設定。人格やチャンネルの設定から名前で指定する
+    #[serde(default)]
+    pub providers: HashMap<String, ProviderConfig>,
+    // モデル名(前方一致)ごとの料金。組み込みの料金より優先する
+    #[serde(default)]
+    pub prices: HashMap<String, ModelPrice>,
+    // ユーザーやチャンネルごとの使用量の制限
+    #[serde(default)]
+    pub quotas: QuotaConfig,
+    // botが返答するチャンネルやユーザーの制限
+    #[serde(default)]
+    pub policy: AccessPolicy,
+}
+
+impl Parameters {
+    // 署名の検証に使うsecretの一覧
+    pub fn signing_secrets(&self) -> Vec<&str> {
+        std::iter::once(self.slack_signing_secret.as_str())
+            .chain(self.slack_signing_secrets.iter().map(|s| s.as_str()))
+            .collect()
+    }
 }
 
 #[derive(Deserialize)]
 pub struct SlackHistoryResponse {
     pub messages: Vec<SlackMessage>,
+    #[serde(default)]
+    pub has_more: bool,
+    pub response_metadata: Option<SlackResponseMetadata>,
+}
+
+#[derive(Deserialize)]
+pub struct SlackResponseMetadata {
+    pub next_cursor: Option<String>,
+}
+
+impl SlackHistoryResponse {
+    // 次のページのcursor。最後のページ(has_moreがfalse)の場合はNone
+    pub fn next_cursor(&self) -> Option<String> {
+        if !self.has_more {
+            return None;
+        }
+        self.response_metadata
+            .as_ref()
+            .and_then(|m| m.next_cursor.clone())
+            .filter(|c| !c.is_empty())
+    }
 }
 
 #[derive(Serialize, Debug)]
@@ -43,6 +137,16 @@ pub struct ChatGptReqBody {
     model: String,
     temperature: f32,
     stream: bool,
+    // ストリーミングの最後のchunkでトークンの使用量を受け取る
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stream_options: Option<StreamOptions>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tools: Vec<ToolDefinition>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tool_choice: Option<&'static str>,
+    // 送信先のprovider名。Noneの場合はOpenAI
+    #[serde(skip)]
+    provider: Option<String>,
     // max_tokens: i32,
     // top_p: f32,
     // n: i32,
@@ -51,12 +155,77 @@ pub struct ChatGptReqBody {
     // stop: Vec<String>,
 }
 
-#[derive(Deserialize, Debug)]
-struct SlackEvent {
+#[derive(Serialize, Debug)]
+struct StreamOptions {
+    include_usage: bool,
+}
+
+impl ChatGptReqBody {
+    pub fn new(messages: Vec<ChatGptQuery>, model: String, temperature: f32, stream: bool) -> Self {
+        Self {
+            messages,
+            model,
+            temperature,
+            stream,
+            stream_options: stream.then_some(StreamOptions {
+                include_usage: true,
+            }),
+            tools: vec![],
+            tool_choice: None,
+            provider: None,
+        }
+    }
+
+    pub fn model(&self) -> &str {
+        &self.model
+    }
+
+    pub fn messages(&self) -> &[ChatGptQuery] {
+        &self.messages
+    }
+
+    pub fn temperature(&self) -> f32 {
+        self.temperature
+    }
+
+    pub fn stream(&self) -> bool {
+        self.stream
+    }
+
+    pub fn provider(&self) -> Option<&str> {
+        self.provider.as_deref()
+    }
+
+    pub fn set_provider(&mut self, provider: Option<String>) {
+        self.provider = provider;
+    }
+
+    // toolを呼び出さずに返答させる
+    pub fn disable_tool_calls(&mut self) {
+        if !self.tools.is_empty() {
+            self.tool_choice = Some("none");
+        }
+    }
+
+    // 呼び出せるtoolを設定する
+    pub fn set_tools(&mut self, tools: Vec<ToolDefinition>) {
+        self.tools = tools;
+    }
+
+    // toolの呼び出しと実行結果などを会話に追加する
+    pub fn push_message(&mut self, message: ChatGptQuery) {
+        self.messages.push(message);
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SlackEvent {
     #[serde(rename = "type")]
-    type_name: String,
-    event: Option<SlackMessage>,
-    challenge: Option<String>,
+    pub type_name: String,
+    pub event: Option<SlackMessage>,
+    pub challenge: Option<String>,
+    pub event_id: Option<String>,
+    pub event_time: Option<i64>,
 }
 
 #[derive(Error, Debug)]
@@ -69,13 +238,83 @@ pub enum HandleRequestError {
     MissingChannel(String),
 }
 
-fn get_enviroment_variable() -> Result<Env> {
+impl Env {
+    // 取得するメッセージの数(最新のメッセージを含む)
+    pub fn past_limit(&self, trigger_message: &SlackMessage) -> i32 {
+        let default = self.default_past_num.unwrap_or(self.max_past_num);
+        trigger_message.get_limit(default, self.max_past_num)
+    }
+}
+
+pub fn get_enviroment_variable() -> Result<Env> {
     match envy::from_env::<Env>() {
         Ok(val) => Ok(val),
         Err(err) => Err(HandleRequestError::GetEnviromentVariableError(err.to_string()).into()),
     }
 }
 
+// チャンネルごとの設定を反映した環境変数
+pub fn get_channel_enviroment_variable(
+    parameters: &Parameters,
+    channel: Option<&str>,
+) -> Result<Env> {
+    Ok(apply_channel_config(
+        parameters,
+        channel,
+        get_enviroment_variable()?,
+    ))
+}
+
+fn apply_channel_config(parameters: &Parameters, channel: Option<&str>, env_vars: Env) -> Env {
+    match find_channel_config(parameters, channel) {
+        Some(config) => config.apply(env_vars),
+        None => env_vars,
+    }
+}
+
+// 返信が必要なメッセージかどうか
+fn reply_required(
+    trigger_message: &SlackMessage,
+    parameters: &Parameters,
+    env_vars: &Env,
+) -> Result<bool> {
+    let bot_member_id = &parameters.bot_member_id;
+    // 編集されたメッセージの場合は、本文が変わった場合のみ編集後のメッセージで判断する
+    let edited_message = trigger_message.edited_message();
+    let trigger_message = match &edited_message {
+        Some(edited_message) if trigger_message.is_text_edited() => edited_message,
+        Some(_) => return Ok(false),
+        None => trigger_message,
+    };
+    if !trigger_message.reply_required(bot_member_id) {
+        return Ok(false);
+    }
+
+    // app_mentionでも届くメッセージは二重に返信しないよう、messageイベントの方を無視する
+    // NOTE: 編集はmessageイベントでのみ届く
+    let use_app_mention = env_vars.use_app_mention.unwrap_or(false);
+    if use_app_mention
+        && edited_message.is_none()
+        && trigger_message.is_delivered_as_app_mention(bot_member_id)
+    {
+        return Ok(false);
+    }
+
+    // 返答しないチャンネルやユーザーの場合は、理由を記録して無視する
+    if let Err(reason) = parameters.policy.check_message(trigger_message) {
+        // NOTE: 本文は記録しない
+        println!(
+            "Denied by policy: {}, channel={}, user={}, ts={}",
+            reason,
+            trigger_message.channel.as_deref().unwrap_or_default(),
+            trigger_message.user,
+            trigger_message.ts
+        );
+        return Ok(false);
+    }
+    Ok(true)
+}
+
 // メッセージを時系列順にソートする
 fn order_by_ts(messages: Vec<SlackMessage>) -> Vec<SlackMessage> {
     let order_by_ts = |a: &SlackMessage, b: &SlackMessage| {
@@ -85,7 +324,7 @@ fn order_by_ts(messages: Vec<SlackMessage>) -> Vec<SlackMessage> {
     };
     let mut sorted_messages = messages;
     sorted_messages.sort_by(order_by_ts);
-    return sorted_messages;
+    sorted_messages
 }
 
 // 最新メッセージ以外のメッセージの画像を空にする
@@ -108,36 +347,46 @@ fn delete_old_files(messages: Vec<SlackMessage>, latest_ts: &str) -> Vec<SlackMe
         .collect()
 }
 
+// スレッドの古いメッセージを要約するかどうか
+fn summary_enabled(trigger_message: &SlackMessage, env_vars: &Env) -> bool {
+    env_vars.summarize_threads.unwrap_or(false) && trigger_message.is_in_thread()
+}
+
 async fn fetch_contexts(
     trigger_message: &SlackMessage,
     parameters: &Parameters,
 ) -> Result<Vec<SlackMessage>> {
     let bot_member_id = &parameters.bot_member_id;
     let is_in_thread = trigger_message.is_in_thread();
-    let is_mention_to_bot = trigger_message.is_mention_to(&bot_member_id);
+    let is_mention_to_bot =
+        trigger_message.is_app_mention() || trigger_message.is_mention_to(bot_member_id);
     let message_channel = trigger_message.channel.clone().unwrap();
     let thread_ts = trigger_message.thread_ts.clone().unwrap_or("".into());
-    let env_vars = get_enviroment_variable()?;
-    let limit = trigger_message.get_limit(env_vars.default_past_num, env_vars.max_past_num);
+    let env_vars = get_channel_enviroment_variable(parameters, Some(&message_channel))?;
+    let limit = env_vars.past_limit(trigger_message);
+    // 要約する場合は、スレッド内のメッセージを取得できるだけ取得する
+    let replies_limit = if summary_enabled(trigger_message, &env_vars) {
+        SLACK_MAX_REPLIES
+    } else {
+        limit as usize
+    };
 
-    // 1つのみ取得する場合は、trigger_messageを返す
-    if limit < 2 {
+    // 1つのみ取得する場合やfreshの指定がある場合は、trigger_messageを返す
+    if limit < 2 || trigger_message.directives().fresh {
         return Ok(vec![trigger_message.clone()]);
     }
 
     if trigger_message.is_direct_message() {
-        let api_client = ApiClient::new(&parameters, &message_channel);
+        let api_client = ApiClient::new(parameters, &message_channel);
 
         // DMかつスレッド内の場合、スレッド内のメッセージを返す
         if is_in_thread {
-            let messages_in_thread = api_client
-                .get_replies(&thread_ts, &limit.to_string())
-                .await?;
-            return Ok(messages_in_thread);
+            let messages_in_thread = api_client.get_replies(&thread_ts, replies_limit).await?;
+            return Ok(with_trigger_message(messages_in_thread, trigger_message));
         }
 
         // DMかつスレッド外の場合、DM内のメッセージを返す
-        let messages = api_client.get_history(&limit.to_string()).await?;
+        let messages = api_client.get_history(limit as usize).await?;
         return Ok(messages);
     }
 
@@ -152,28 +401,79 @@ async fn fetch_contexts(
             return Ok(vec![]);
         }
 
-        let api_client = ApiClient::new(&parameters, &message_channel);
+        let api_client = ApiClient::new(parameters, &message_channel);
+        // NOTE: botの発言の有無はスレッド全体で判断するため、上限まで取得してから件数を絞る
         let messages_in_thread = api_client
-            .get_replies(&thread_ts, &limit.to_string())
+            .get_replies(&thread_ts, SLACK_MAX_REPLIES)
             .await?;
 
         // botへのmentionか、botが発言しているスレッドの場合はメッセージを返す
-        if is_mention_to_bot || messages_in_thread.iter().any(|m| m.is_from(&bot_member_id)) {
-            return Ok(messages_in_thread);
+        if is_mention_to_bot || messages_in_thread.iter().any(|m| m.is_from(bot_member_id)) {
+            let messages = newest_messages(messages_in_thread, replies_limit);
+            return Ok(with_trigger_message(messages, trigger_message));
+        }
+    }
+    Ok(vec![])
+}
+
+// 解釈できなかった指定。存在しない人格の指定も含める
+// NOTE: 存在しない人格の場合は、チャンネルのデフォルトか組み込みの人格で返答する
+fn unknown_directives(directives: &MessageDirectives, parameters: &Parameters) -> Vec<String> {
+    let mut unknown = directives.unknown.clone();
+    if let Some(name) = &directives.persona {
+        if find_persona(parameters, name).is_none() {
+            unknown.push(format!("persona:{}", name));
         }
     }
-    return Ok(vec![]);
+    unknown
 }
 
-async fn create_request_body_for_chat_gpt(
+// 取得したスレッドのメッセージにtrigger_messageが含まれない場合は末尾に加える
+// NOTE: 取得の上限を超える長いスレッドでは、新しいメッセージを取得できないため
+fn with_trigger_message(
+    mut messages: Vec<SlackMessage>,
+    trigger_message: &SlackMessage,
+) -> Vec<SlackMessage> {
+    if !messages.iter().any(|m| m.ts == trigger_message.ts) {
+        messages.push(trigger_message.clone());
+    }
+    messages
+}
+
+// 返答に使うメッセージを取得する
+// 他のbotと返答し合い続けている場合や、botが参加していないスレッドでのhelpの指定の場合はNone
+async fn fetch_request_contexts(
     trigger_message: &SlackMessage,
     parameters: &Parameters,
-) -> Result<ChatGptReqBody> {
-    let bot_member_id = parameters.bot_member_id.clone();
-    let contexts = fetch_contexts(trigger_message, parameters).await?;
-    if contexts.len() == 0 {
+) -> Result<Option<Vec<SlackMessage>>> {
+    // NOTE: 編集されたメッセージに返答し直す場合などのため、trigger_messageより後のメッセージは含めない
+    let trigger_ts = trigger_message.ts.parse::<f64>().unwrap_or(f64::MAX);
+    let contexts: Vec<SlackMessage> = fetch_contexts(trigger_message, parameters)
+        .await?
+        .into_iter()
+        .filter(|m| m.ts.parse::<f64>().unwrap_or(0.0) <= trigger_ts)
+        .collect();
+    // 他のbotと返答し合い続けている場合は、記録して返答しない
+    if trigger_message.is_from_bot()
+        && parameters
+            .policy
+            .is_reply_loop(&order_by_ts(contexts.clone()), &parameters.bot_member_id)
+    {
+        println!(
+            "Skip reply loop with bot: channel={}, bot={}, ts={}",
+            trigger_message.channel.as_deref().unwrap_or_default(),
+            trigger_message.sender_id(),
+            trigger_message.ts
+        );
+        return Ok(None);
+    }
+    if contexts.is_empty() {
+        // NOTE: helpの指定の場合は、botが参加していないスレッドには返さない
+        if trigger_message.directives().help {
+            return Ok(None);
+        }
         // NOTE: contextsが空の場合はエラーを投稿する
-        ApiClient::new(&parameters, &trigger_message.channel.clone().unwrap())
+        ApiClient::new(parameters, &trigger_message.channel.clone().unwrap())
             .post_message(
                 trigger_message.channel.clone().unwrap().as_str(),
                 NO_CONTEXTS_MESSAGE,
@@ -182,40 +482,230 @@ async fn create_request_body_for_chat_gpt(
             .await?;
         return Err(HandleRequestError::ContextsIsEmpty.into());
     }
+    Ok(Some(contexts))
+}
 
-    // 最新メッセージ以外のメッセージの画像を空にする
-    let contexts_with_new_files_only = delete_old_files(contexts, &trigger_message.ts);
+// 返答の前に返信するメッセージと、返答する場合のリクエスト
+#[derive(Default)]
+struct PreparedReply {
+    // 使用量の制限、使い方、分からない指定を知らせるメッセージ
+    notice: Option<String>,
+    // ChatGPTへのリクエストと、要約に使った使用量
+    request: Option<(ChatGptReqBody, ChatGptUsage)>,
+}
+
+// 使用量の制限、指定への返信、リクエストの作成の順に返答を準備する
+// NOTE: 要約など料金のかかる処理や指定への返信より前に、使用量の制限を確認する
+async fn prepare_reply(
+    trigger_message: &SlackMessage,
+    contexts: Vec<SlackMessage>,
+    parameters: &Parameters,
+    env_vars: Env,
+    usage_store: &dyn UsageStore,
+) -> Result<PreparedReply> {
+    let channel = trigger_message.channel.clone().unwrap_or_default();
+    // 使用量の制限は、botの場合はbot_idかapp_idごとに行う
+    if let Some(message) = quota_exceeded_message(
+        parameters,
+        trigger_message.sender_id(),
+        &channel,
+        usage_store,
+    )
+    .await
+    {
+        return Ok(PreparedReply {
+            notice: Some(message),
+            ..Default::default()
+        });
+    }
+
+    // helpの指定がある場合は使い方を返す
+    let directives = trigger_message.directives();
+    if directives.help {
+        return Ok(PreparedReply {
+            notice: Some(MESSAGE_DIRECTIVES_HELP_MESSAGE.to_string()),
+            ..Default::default()
+        });
+    }
+    // 解釈できなかった指定がある場合は知らせてから返答する
+    let unknown_directives = unknown_directives(&directives, parameters);
+    let notice = (!unknown_directives.is_empty()).then(|| {
+        let unknown = unknown_directives
+            .iter()
+            .map(|d| format!("`{}`", d))
+            .collect::<Vec<String>>()
+            .join(", ");
+        format!("{}{}", UNKNOWN_DIRECTIVES_MESSAGE, unknown)
+    });
+
+    // 件数の上限を超えた古いメッセージは要約に回す
+    let env_vars = apply_channel_config(parameters, Some(&channel), env_vars);
+    let (contexts, summary_target) = if summary_enabled(trigger_message, &env_vars) {
+        let limit = env_vars.past_limit(trigger_message);
+        let mut contexts = order_by_ts(contexts);
+        let older_messages = contexts
+            .drain(..contexts.len().saturating_sub(limit as usize))
+            .collect();
+        let summary_target = SummaryTarget {
+            channel: channel.clone(),
+            thread_ts: trigger_message.thread_ts.clone().unwrap_or_default(),
+            older_messages,
+        };
+        (contexts, Some(summary_target))
+    } else {
+        (contexts, None)
+    };
 
-    // system prompt
-    let mut messages = vec![ChatGptQuery::new_system_prompt()];
+    let request = create_request_body_with_env(
+        contexts,
+        &trigger_message.ts,
+        summary_target,
+        parameters,
+        env_vars,
+    )
+    .await?;
+    Ok(PreparedReply {
+        notice,
+        request: Some(request),
+    })
+}
 
-    let parsed_messages = ChatGptQuery::new_from_slack_messages(
-        order_by_ts(contexts_with_new_files_only),
+// 取得したメッセージからChatGPTへのリクエストを作成する
+// NOTE: 要約した場合は、要約に使った使用量も返す
+pub async fn create_request_body_from_contexts(
+    contexts: Vec<SlackMessage>,
+    latest_ts: &str,
+    summary_target: Option<SummaryTarget>,
+    parameters: &Parameters,
+) -> Result<(ChatGptReqBody, ChatGptUsage)> {
+    create_request_body_with_env(
+        contexts,
+        latest_ts,
+        summary_target,
+        parameters,
+        get_enviroment_variable()?,
+    )
+    .await
+}
+
+// 環境変数の代わりにenv_varsを使ってリクエストを作成する
+pub async fn create_request_body_with_env(
+    contexts: Vec<SlackMessage>,
+    latest_ts: &str,
+    summary_target: Option<SummaryTarget>,
+    parameters: &Parameters,
+    env_vars: Env,
+) -> Result<(ChatGptReqBody, ChatGptUsage)> {
+    let bot_member_id = parameters.bot_member_id.clone();
+
+    // 最新メッセージ以外のメッセージの画像を空にする
+    let contexts_with_new_files_only = delete_old_files(order_by_ts(contexts), latest_ts);
+    let latest_message = contexts_with_new_files_only
+        .iter()
+        .find(|m| m.ts == latest_ts);
+    let channel = latest_message.and_then(|m| m.channel.clone());
+    let latest_directives = latest_message.map(|m| m.directives()).unwrap_or_default();
+    // 人格を決める
+    let persona = resolve_persona(
+        parameters,
+        channel.as_deref(),
+        &contexts_with_new_files_only,
+    );
+    // 予算に収まらなかったメッセージを要約するため、変換前のメッセージを残しておく
+    let ordered_contexts = summary_target
+        .as_ref()
+        .map(|_| contexts_with_new_files_only.clone());
+
+    let mut parsed_messages = ChatGptQuery::new_from_slack_messages(
+        contexts_with_new_files_only,
         &bot_member_id,
         &parameters.slack_auth_token,
     )
     .await;
+    // continueだけのメッセージの場合は、最新のメッセージを続きを書かせる指示に置き換える
+    // NOTE: 本文がある場合は質問を消さないよう、通常のメッセージとして扱う
+    if latest_directives.continue_generation && latest_directives.body.is_empty() {
+        parsed_messages.pop();
+        parsed_messages.push(ChatGptQuery::new_continue_prompt());
+    }
 
     #[cfg(debug_assertions)]
     {
         println!("parsed_messages: {:?}", parsed_messages);
     }
 
-    // system promptの後にmessagesを追加する
-    messages.extend(parsed_messages);
+    // system promptの後に、トークン数の上限まで新しい順にmessagesを追加する
+    // 最新のメッセージでの指定、人格、チャンネル、環境変数の順に優先する
+    let env_vars = apply_channel_config(parameters, channel.as_deref(), env_vars);
+    let model = latest_directives
+        .model
+        .unwrap_or_else(|| persona.model(&env_vars));
+    let temperature = latest_directives
+        .temperature
+        .unwrap_or_else(|| persona.temperature(&env_vars));
+    let provider = persona.provider.clone().or_else(|| {
+        find_channel_config(parameters, channel.as_deref()).and_then(|c| c.provider.clone())
+    });
+    let budget = ContextBudget::new(
+        &model,
+        persona.context_window.or(env_vars.context_window_tokens),
+        env_vars.completion_reserve_tokens,
+    );
+    let mut summary_usage = ChatGptUsage::default();
+    let messages = match (summary_target, ordered_contexts) {
+        (Some(summary_target), Some(ordered_contexts)) => {
+            // 要約の分を空けて詰め、収まらなかったメッセージは要約して system promptの後に追加する
+            let fitted = budget.fit_reserving(
+                ChatGptQuery::new_system_prompt(&persona.system_prompt),
+                parsed_messages,
+                SUMMARY_MAX_TOKENS,
+            );
+            let dropped_messages: Vec<SlackMessage> = summary_target
+                .older_messages
+                .into_iter()
+                .chain(ordered_contexts.into_iter().take(fitted.dropped))
+                .collect();
+            let summary = summarize_thread(
+                &summary_target.channel,
+                &summary_target.thread_ts,
+                &dropped_messages,
+                parameters,
+                &budget,
+                &model,
+                provider.as_deref(),
+            )
+            .await
+            .unwrap_or_else(|e| {
+                // 要約に失敗した場合は要約なしで返答する
+                eprintln!("Error: {}", e);
+                None
+            });
 
-    let env_vars = get_enviroment_variable()?;
-    let response = ChatGptReqBody {
-        messages: messages,
-        model: env_vars.gpt_model,
-        temperature: env_vars.temperature,
-        stream: true,
+            let mut messages = fitted.messages;
+            if let Some((summary, usage)) = summary {
+                messages.insert(1, ChatGptQuery::new_summary(&summary));
+                summary_usage = usage;
+            }
+            messages
+        }
+        _ => budget.fit(
+            ChatGptQuery::new_system_prompt(&persona.system_prompt),
+            parsed_messages,
+        ),
     };
-    return Ok(response);
+
+    let mut request_body = ChatGptReqBody::new(messages, model, temperature, true);
+    request_body.set_provider(provider);
+    Ok((request_body, summary_usage))
 }
 
 // Slackイベントに応じて処理
-async fn handle_slack_event(slack_event: SlackEvent, parameters: Parameters) -> Result<()> {
+pub async fn handle_slack_event(
+    slack_event: SlackEvent,
+    parameters: Parameters,
+    usage_store: &dyn UsageStore,
+    reply_store: &dyn ReplyStore,
+) -> Result<()> {
     // println!("slack_event: {:?}", slack_event);
 
     // event_callback以外は無視する
@@ -224,11 +714,31 @@ async fn handle_slack_event(slack_event: SlackEvent, parameters: Parameters) ->
     }
 
     let trigger_message = slack_event.event.unwrap();
+    let env_vars = get_enviroment_variable()?;
     // 反応不要のメッセージの場合は終了
-    if !trigger_message.reply_required(&parameters.bot_member_id) {
+    if !reply_required(&trigger_message, &parameters, &env_vars)? {
         return Ok(());
     }
 
+    // 編集されたメッセージの場合は、以前の返答を書き換える
+    // NOTE: 返答していないメッセージの編集は無視する
+    let (trigger_message, previous_reply_ts) = match trigger_message.edited_message() {
+        Some(edited_message) => {
+            let channel = edited_message.channel.clone().unwrap_or_default();
+            match reply_store.find(&channel, &edited_message.ts).await? {
+                Some(reply_ts) if !reply_ts.is_empty() => (edited_message, Some(reply_ts)),
+                _ => {
+                    #[cfg(debug_assertions)]
+                    {
+                        println!("Skip edited message without reply: {}", edited_message);
+                    }
+                    return Ok(());
+                }
+            }
+        }
+        None => (trigger_message, None),
+    };
+
     let channel = match trigger_message.channel.clone() {
         Some(val) => val,
         None => {
@@ -237,101 +747,538 @@ async fn handle_slack_event(slack_event: SlackEvent, parameters: Parameters) ->
     };
     let thread_ts = trigger_message.new_message_thread_ts();
 
-    let request_body = create_request_body_for_chat_gpt(&trigger_message, &parameters).await?;
-
     let api_client = ApiClient::new(&parameters, &channel);
 
-    // Slackに初期値を投稿する
+    // 返答に使うメッセージを取得する。返答しない場合は終了する
+    let Some(contexts) = fetch_request_contexts(&trigger_message, &parameters).await? else {
+        return Ok(());
+    };
+    let prepared = prepare_reply(
+        &trigger_message,
+        contexts,
+        &parameters,
+        env_vars,
+        usage_store,
+    )
+    .await?;
+    if let Some(notice) = &prepared.notice {
+        api_client
+            .post_message(&channel, notice, thread_ts.as_deref())
+            .await?;
+    }
+    let Some((request_body, summary_usage)) = prepared.request else {
+        return Ok(());
+    };
+    // 使用量の記録は、botの場合はbot_idかapp_idごとに行う
+    let sender_id = trigger_message.sender_id();
+    // 要約した場合は、返答とは別のリクエストとして使用量を記録する
+    if summary_usage.total_tokens() > 0 {
+        record_usage(
+            usage_store,
+            sender_id,
+            &channel,
+            request_body.model(),
+            &summary_usage,
+        )
+        .await;
+    }
+
+    // Slackに初期値を投稿する。編集の場合は以前の返答を初期値に戻す
     // NOTE: fetch_contextsの後でないと無視する場合が排除できないためここで実行
-    let bot_message_ts = api_client
-        .post_message(&channel, LOADING_EMOJI, thread_ts.as_deref())
-        .await?;
+    let mut reply_ts = match previous_reply_ts {
+        Some(reply_ts) => {
+            // 以前の返答を生成中の場合は、書き換えが重ならないよう編集を無視する
+            if !reply_store
+                .begin(&channel, &trigger_message.ts, &reply_ts)
+                .await?
+            {
+                #[cfg(debug_assertions)]
+                {
+                    println!("Skip edited message while replying: {}", trigger_message);
+                }
+                return Ok(());
+            }
+            api_client
+                .update_message(LOADING_EMOJI, &reply_ts[0])
+                .await?;
+            reply_ts
+        }
+        None => {
+            let bot_message_ts = api_client
+                .post_message(&channel, LOADING_EMOJI, thread_ts.as_deref())
+                .await?;
+            let reply_ts = vec![bot_message_ts];
+            // 編集された場合に書き換えられるよう、返答との対応を記録する
+            reply_store
+                .begin(&channel, &trigger_message.ts, &reply_ts)
+                .await
+                .unwrap_or_else(|e| {
+                    eprintln!("Error: {}", e);
+                    false
+                });
+            reply_ts
+        }
+    };
 
     // 画像バリデーション
     if let Some(files) = &trigger_message.files {
         for file in files {
-            if !VALID_MIME_TYPES
-                .iter()
-                .any(|&i| i == file.mimetype.as_str())
-            {
+            if !VALID_MIME_TYPES.contains(&file.mimetype.as_str()) {
                 api_client
-                    .update_message(INVALID_IMAGE_FORMAT, bot_message_ts.as_str())
+                    .update_message(INVALID_IMAGE_FORMAT, &reply_ts[0])
                     .await?;
+                finish_reply(reply_store, &channel, &trigger_message.ts, &reply_ts).await;
                 return Ok(());
             }
         }
     }
 
-    // ChatGPTからのresponseを取得
-    let res = api_client
-        .get_chat_gpt_response(request_body, &bot_message_ts)
-        .await?;
-
-    // ストリームを処理
-    handle_chat_gpt_response(res, api_client, bot_message_ts.as_str()).await
+    // ChatGPTからのresponseを取得し、ストリームを処理
+    let tools = tools_from_env(&api_client, &trigger_message.user, &parameters.policy)?;
+    let mut request_body = request_body;
+    request_body.set_tools(tools.definitions());
+    let model = request_body.model().to_string();
+    // NOTE: エラーで中断した場合も、それまでの使用量を記録する
+    let mut usage = ChatGptUsage::default();
+    let result = handle_chat_gpt_response(
+        request_body,
+        api_client,
+        &mut reply_ts,
+        thread_ts.as_deref(),
+        &tools,
+        &mut usage,
+    )
+    .await;
+    record_usage(usage_store, sender_id, &channel, &model, &usage).await;
+    finish_reply(reply_store, &channel, &trigger_message.ts, &reply_ts).await;
+    result
 }
 
-// ParameterStoreのパラメータを取得する
-async fn get_parameters() -> Result<Parameters, Error> {
-    let shared_config = aws_config::defaults(BehaviorVersion::v2023_11_09())
-        .region(Region::new("ap-northeast-1"))
-        .load()
-        .await;
-    let client = Client::new(&shared_config);
-
-    let resp = client
-        .get_parameter()
-        .with_decryption(true)
-        .name(get_enviroment_variable()?.parameter_store_name)
-        .send()
-        .await
-        .expect("cannot get parameter");
-
-    let parameters: Parameters = serde_json::from_str(&resp.parameter().unwrap().value().unwrap())
-        .expect("cannot parse parameter");
+// 返答の生成を終え、投稿したすべての返答のtsを記録する
+// NOTE: 記録に失敗しても返答には影響させない
+async fn finish_reply(
+    reply_store: &dyn ReplyStore,
+    channel: &str,
+    trigger_ts: &str,
+    reply_ts: &[String],
+) {
+    if let Err(e) = reply_store.finish(channel, trigger_ts, reply_ts).await {
+        eprintln!("Error: {}", e);
+    }
+}
 
-    Ok(parameters)
+pub async fn handle_request(
+    event: Request,
+    parameters: Parameters,
+    event_queue: &dyn EventQueue,
+    dedup_store: &dyn DedupStore,
+    usage_store: &dyn UsageStore,
+) -> String {
+    let env_vars = match get_enviroment_variable() {
+        Ok(val) => val,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            return "NG".to_string();
+        }
+    };
+    handle_request_with_env(
+        event,
+        parameters,
+        &env_vars,
+        event_queue,
+        dedup_store,
+        usage_store,
+    )
+    .await
 }
 
-pub async fn handle_request(event: Request) -> String {
+async fn handle_request_with_env(
+    event: Request,
+    parameters: Parameters,
+    env_vars: &Env,
+    event_queue: &dyn EventQueue,
+    dedup_store: &dyn DedupStore,
+    usage_store: &dyn UsageStore,
+) -> String {
     // println!("event: {:?}", event);
+    // NOTE: Lambda関数URLではform形式のbodyがbase64エンコードされ、デコード済みのBinaryで届く
     let body_str = match event.body() {
-        Body::Text(s) => s,
-        _ => "",
+        Body::Text(s) => s.as_str(),
+        Body::Binary(b) => match std::str::from_utf8(b) {
+            Ok(s) => s,
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                return "NG".to_string();
+            }
+        },
+        Body::Empty => "",
     };
-    let parameters = get_parameters().await.unwrap();
 
     // signatureの検証
-    if !validate_slack_signature(
+    let tolerance_secs = env_vars
+        .signature_tolerance_secs
+        .unwrap_or(DEFAULT_SIGNATURE_TOLERANCE_SECS);
+    if let Err(e) = validate_slack_signature(
         event.headers(),
         body_str,
-        parameters.slack_signing_secret.as_str(),
+        &parameters.signing_secrets(),
+        tolerance_secs,
     ) {
+        eprintln!("Invalid request: {}", e);
         return "NG".to_string();
     }
 
-    // retryの場合は、OKを返して処理を終了する
-    if event.headers().get("x-slack-retry-num").is_some() {
-        return "OK".to_string();
+    // スラッシュコマンドはform形式で送られてくる
+    let is_slash_command = event
+        .headers()
+        .get("content-type")
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("application/x-www-form-urlencoded"));
+    if is_slash_command {
+        return handle_slash_command_request(body_str, &parameters, event_queue, usage_store).await;
     }
-    let json: Result<SlackEvent, _> = serde_json::from_str(&body_str);
+
+    let json: Result<SlackEvent, _> = serde_json::from_str(body_str);
     let slack_event = match json {
         Ok(j) => j,
         Err(_) => return "NG".to_string(),
     };
 
     // Slack appの登録(初回のみ)
-    if slack_event.challenge.is_some() {
-        return slack_event.challenge.unwrap();
+    if let Some(challenge) = slack_event.challenge {
+        return challenge;
+    }
+
+    // event_callback以外と反応不要のメッセージはworkerに渡さない
+    let is_reply_required = match (slack_event.type_name.as_str(), &slack_event.event) {
+        ("event_callback", Some(message)) => match reply_required(message, &parameters, env_vars) {
+            Ok(val) => val,
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                return "NG".to_string();
+            }
+        },
+        _ => false,
+    };
+    if !is_reply_required {
+        return "OK".to_string();
+    }
+
+    // 処理済みのイベント(リトライによる重複)の場合は、OKを返して処理を終了する
+    let event_id = slack_event.event_id.clone();
+    if let Some(event_id) = &event_id {
+        let event_time = slack_event.event_time.unwrap_or(0);
+        match dedup_store.try_record(event_id, event_time).await {
+            Ok(true) => {}
+            Ok(false) => {
+                #[cfg(debug_assertions)]
+                {
+                    println!("Skip duplicated event: {}", event_id);
+                }
+                return "OK".to_string();
+            }
+            Err(e) => {
+                // 記録に失敗した場合も返信できるよう処理は続行する
+                eprintln!("Error: {}", e);
+            }
+        }
     }
 
-    // TODO: responseを返しつつ別のlambda関数で非同期に処理する
-    // task::spawn(async move { handle_slack_event(slack_event, parameters).await });
-    handle_slack_event(slack_event, parameters)
+    // workerに処理を渡し、Slackには即座にOKを返す
+    if let Err(e) = event_queue
+        .enqueue(QueuedJob::SlackEvent(Box::new(slack_event)))
         .await
-        .unwrap_or_else(|e| {
-            eprintln!("Error: {}", e);
-        });
+    {
+        eprintln!("Error: {}", e);
+        // リトライ時に再度処理できるよう記録を取り消す
+        if let Some(event_id) = &event_id {
+            dedup_store.remove(event_id).await.unwrap_or_else(|e| {
+                eprintln!("Error: {}", e);
+            });
+        }
+        return "NG".to_string();
+    }
 
     "OK".to_string()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::constants::{CHAT_GPT_CONTINUE_PROMPT, SLASH_COMMAND_HELP_MESSAGE};
+    use crate::slack_post_handler::dedup_store::InMemoryDedupStore;
+    use crate::slack_post_handler::event_queue::LocalEventQueue;
+    use crate::slack_post_handler::usage_ledger::InMemoryUsageStore;
+    use hmac::{Hmac, Mac};
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::Server;
+    use sha2::Sha256;
+    use std::convert::Infallible;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    const SIGNING_SECRET: &str = "1234567890abcdef1234567890abcdef";
+
+    // Envの必須項目のみを設定したEnv
+    // NOTE: 並列に実行される他のテストと競合しないよう、プロセスの環境変数は変更しない
+    fn test_env() -> Env {
+        envy::from_iter([
+            ("gpt_model".to_string(), "gpt-4o".to_string()),
+            ("temperature".to_string(), "0.2".to_string()),
+            ("default_past_num".to_string(), "6".to_string()),
+            ("max_past_num".to_string(), "10".to_string()),
+        ])
+        .unwrap()
+    }
+
+    fn test_parameters() -> Parameters {
+        serde_json::from_value(serde_json::json!({
+            "bot_member_id": "UBOT",
+            "slack_auth_token": "xoxb",
+            "openai_secret_key": "sk",
+            "slack_signing_secret": SIGNING_SECRET,
+        }))
+        .unwrap()
+    }
+
+    // Slackの署名付きリクエストを生成する
+    fn signed_request(body: &str) -> Request {
+        signed_request_with_body(body, Body::Text(body.into()), "application/json")
+    }
+
+    fn signed_request_with_body(body: &str, raw_body: Body, content_type: &str) -> Request {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            .to_string();
+        let mut mac = Hmac::<Sha256>::new_from_slice(SIGNING_SECRET.as_bytes()).unwrap();
+        mac.update(format!("v0:{}:{}", timestamp, body).as_bytes());
+        let signature = format!("v0={}", hex::encode(mac.finalize().into_bytes()));
+
+        lambda_http::http::Request::builder()
+            .header("X-Slack-Signature", signature)
+            .header("X-Slack-Request-Timestamp", timestamp)
+            .header("content-type", content_type)
+            .body(raw_body)
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_handle_request_binary_slash_command() {
+        let body = "command=%2Fcatgpt&text=help&user_id=U01&channel_id=C01";
+        let (event_queue, _receiver) = LocalEventQueue::new();
+
+        let res = handle_request_with_env(
+            signed_request_with_body(
+                body,
+                Body::Binary(body.as_bytes().to_vec()),
+                "application/x-www-form-urlencoded",
+            ),
+            test_parameters(),
+            &test_env(),
+            &event_queue,
+            &InMemoryDedupStore::default(),
+            &InMemoryUsageStore::default(),
+        )
+        .await;
+        assert_eq!(res, SLASH_COMMAND_HELP_MESSAGE);
+    }
+
+    #[test]
+    fn test_unknown_directives_include_unknown_persona() {
+        let parameters: Parameters = serde_json::from_value(serde_json::json!({
+            "bot_member_id": "UBOT",
+            "slack_auth_token": "xoxb",
+            "openai_secret_key": "sk",
+            "slack_signing_secret": SIGNING_SECRET,
+            "personas": [{"name": "reviewer", "system_prompt": "You review code."}],
+        }))
+        .unwrap();
+
+        let directives = MessageDirectives::parse("<@UBOT> temp:3 persona:foo こんにちは");
+        assert_eq!(
+            unknown_directives(&directives, &parameters),
+            vec!["temp:3", "persona:foo"]
+        );
+        let directives = MessageDirectives::parse("<@UBOT> persona:reviewer こんにちは");
+        assert!(unknown_directives(&directives, &parameters).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_continue_only_replaces_bare_message() {
+        let message = |text: &str| SlackMessage {
+            text: text.into(),
+            type_name: "message".into(),
+            user: "U01".into(),
+            channel: Some("C01".into()),
+            ts: "1627777777.000100".into(),
+            ..Default::default()
+        };
+        let last_content = |request_body: &ChatGptReqBody| {
+            let json = serde_json::json!(request_body);
+            json["messages"].as_array().unwrap().last().unwrap()["content"].clone()
+        };
+
+        let (request_body, _) = create_request_body_with_env(
+            vec![message("<@UBOT> continue")],
+            "1627777777.000100",
+            None,
+            &test_parameters(),
+            test_env(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(last_content(&request_body), CHAT_GPT_CONTINUE_PROMPT);
+
+        // 本文がある場合は質問を残す
+        let (request_body, _) = create_request_body_with_env(
+            vec![message("<@UBOT> !continue 犬の話も書いて")],
+            "1627777777.000100",
+            None,
+            &test_parameters(),
+            test_env(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(last_content(&request_body), "犬の話も書いて");
+    }
+
+    // ChatGPT互換のAPIへのリクエストを数えるサーバーを起動する
+    fn start_completion_server() -> (String, Arc<AtomicUsize>) {
+        let count = Arc::new(AtomicUsize::new(0));
+        let server_count = count.clone();
+        let make_service = make_service_fn(move |_conn| {
+            let count = server_count.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |_req| {
+                    count.fetch_add(1, Ordering::SeqCst);
+                    async move {
+                        Ok::<_, Infallible>(hyper::Response::new(hyper::Body::from(
+                            r#"{"choices":[{"message":{"role":"assistant","content":"要約"}}],"usage":{"prompt_tokens":10,"completion_tokens":2}}"#,
+                        )))
+                    }
+                }))
+            }
+        });
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+        let url = format!("http://{}", server.local_addr());
+        tokio::spawn(server);
+        (url, count)
+    }
+
+    #[tokio::test]
+    async fn test_prepare_reply_checks_quota_before_summary() {
+        let (url, count) = start_completion_server();
+        let parameters = |requests_per_minute: usize| -> Parameters {
+            serde_json::from_value(serde_json::json!({
+                "bot_member_id": "UBOT",
+                "slack_auth_token": "xoxb",
+                "openai_secret_key": "sk",
+                "slack_signing_secret": SIGNING_SECRET,
+                "providers": {"local": {"type": "openai_compatible", "base_url": url}},
+                "channels": {"C01": {"provider": "local"}},
+                "quotas": {"requests_per_minute": requests_per_minute},
+            }))
+            .unwrap()
+        };
+        let env = || -> Env {
+            envy::from_iter([
+                ("gpt_model".to_string(), "gpt-4o".to_string()),
+                ("temperature".to_string(), "0.2".to_string()),
+                ("default_past_num".to_string(), "1".to_string()),
+                ("max_past_num".to_string(), "1".to_string()),
+                ("summarize_threads".to_string(), "true".to_string()),
+            ])
+            .unwrap()
+        };
+        // 件数の上限を超えた古いメッセージがあるスレッド
+        let thread = |thread_ts: &str| -> Vec<SlackMessage> {
+            (1..=4)
+                .map(|i| SlackMessage {
+                    text: format!("<@UBOT> 質問{}", i),
+                    type_name: "message".into(),
+                    user: "U01".into(),
+                    channel: Some("C01".into()),
+                    ts: format!("{}{}", &thread_ts[..thread_ts.len() - 1], i),
+                    thread_ts: Some(thread_ts.into()),
+                    ..Default::default()
+                })
+                .collect()
+        };
+        let usage_store = InMemoryUsageStore::default();
+
+        // 制限を超えている場合は要約せずに知らせる
+        let contexts = thread("1627777777.000100");
+        let trigger_message = contexts.last().unwrap().clone();
+        let prepared = prepare_reply(
+            &trigger_message,
+            contexts,
+            &parameters(0),
+            env(),
+            &usage_store,
+        )
+        .await
+        .unwrap();
+        assert!(prepared.notice.is_some());
+        assert!(prepared.request.is_none());
+        assert_eq!(count.load(Ordering::SeqCst), 0);
+
+        // 制限内の場合は古いメッセージを要約する
+        let contexts = thread("1627777888.000100");
+        let trigger_message = contexts.last().unwrap().clone();
+        let prepared = prepare_reply(
+            &trigger_message,
+            contexts,
+            &parameters(10),
+            env(),
+            &usage_store,
+        )
+        .await
+        .unwrap();
+        assert!(prepared.notice.is_none());
+        let (_, summary_usage) = prepared.request.unwrap();
+        assert_eq!(summary_usage.total_tokens(), 12);
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_handle_request_skips_duplicated_event() {
+        let body = serde_json::json!({
+            "type": "event_callback",
+            "event_id": "Ev01",
+            "event_time": 1627777777,
+            "event": {
+                "type": "message",
+                "text": "こんにちは",
+                "user": "U01J9QZQZ9Z",
+                "channel": "D024BE91L",
+                "channel_type": "im",
+                "ts": "1627777777.000000",
+            },
+        })
+        .to_string();
+        let env_vars = test_env();
+        let (event_queue, mut receiver) = LocalEventQueue::new();
+        let dedup_store = InMemoryDedupStore::default();
+        let usage_store = InMemoryUsageStore::default();
+
+        // 初回とリトライ
+        for _ in 0..2 {
+            let res = handle_request_with_env(
+                signed_request(&body),
+                test_parameters(),
+                &env_vars,
+                &event_queue,
+                &dedup_store,
+                &usage_store,
+            )
+            .await;
+            assert_eq!(res, "OK");
+        }
+
+        assert!(receiver.try_recv().is_ok());
+        assert!(receiver.try_recv().is_err());
+    }
+}
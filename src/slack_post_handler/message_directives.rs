@@ -0,0 +1,207 @@
+use regex::Regex;
+
+// key:valueの形式の指定
+const KNOWN_KEYS: [&str; 3] = ["model", "temp", "persona"];
+
+// メッセージの先頭に書かれた指定
+// e.g. "past10 model:gpt-4o-mini temp:0.8 !fresh こんにちは"
+#[derive(Debug, Default, PartialEq)]
+pub struct MessageDirectives {
+    // past(数字): 参照する過去のメッセージの数
+    pub past: Option<i32>,
+    // model:(名前): 使用するモデル
+    pub model: Option<String>,
+    // temp:(数字): temperature
+    pub temperature: Option<f32>,
+    // persona:(名前): 人格
+    pub persona: Option<String>,
+    // !fresh: スレッドの履歴を参照しない
+    pub fresh: bool,
+    // !help: 使い方を表示する
+    pub help: bool,
+    // !continue: 長さの上限で途中で止まった返答の続きを書く
+    pub continue_generation: bool,
+    // 解釈できなかった指定
+    pub unknown: Vec<String>,
+    // メンション文字列と指定を除いた本文
+    pub body: String,
+}
+
+impl MessageDirectives {
+    // 先頭のメンション文字列の後に続く指定を、本文が始まるまで順に読む
+    pub fn parse(text: &str) -> Self {
+        // メンション文字列
+        let mention_re = Regex::new(r"^(<[^>]+>\s*)+").unwrap();
+        // NOTE: pastは本文と続けて書かれる場合がある(e.g. "past10こんにちは")
+        let past_re = Regex::new(r"^past(\d+)").unwrap();
+        let key_value_re = Regex::new(r"^([a-z_]+):(\S+)(\s+|$)").unwrap();
+        // NOTE: "help me ..."などの質問と区別するため、"!"を付けるか、単語だけの場合に限り指定とみなす
+        let flag_re = Regex::new(r"^!(fresh|help|continue)(\s+|$)").unwrap();
+        let bare_flag_re = Regex::new(r"^(fresh|help|continue)$").unwrap();
+
+        let mut directives = Self::default();
+        let mut rest = mention_re.replace(text, "").trim_start().to_string();
+        loop {
+            let consumed = if let Some(c) = past_re.captures(&rest) {
+                directives.past = Some(c[1].parse::<i32>().unwrap_or(i32::MAX));
+                c[0].len()
+            } else if let Some(c) = flag_re
+                .captures(&rest)
+                .or_else(|| bare_flag_re.captures(rest.trim_end()))
+            {
+                directives.apply_flag(&c[1]);
+                c[0].len()
+            } else if let Some(c) = key_value_re.captures(&rest) {
+                // NOTE: "https://..."などのURLは本文とみなす
+                if c[2].starts_with("//") {
+                    break;
+                }
+                // NOTE: 不明な指定は本文の可能性もあるため本文に残す
+                //       "note:"などの本文と区別するため、指定の書き間違いとみられる場合のみ報告する
+                if !KNOWN_KEYS.contains(&&c[1]) {
+                    if KNOWN_KEYS.iter().any(|key| edit_distance(key, &c[1]) <= 2) {
+                        directives.unknown.push(c[0].trim().to_string());
+                    }
+                    break;
+                }
+                directives.apply_key_value(&c[1], &c[2]);
+                c[0].len()
+            } else {
+                break;
+            };
+            rest = rest[consumed..].trim_start().to_string();
+        }
+
+        directives.body = rest.trim().to_string();
+        directives
+    }
+
+    fn apply_flag(&mut self, flag: &str) {
+        match flag {
+            "fresh" => self.fresh = true,
+            "continue" => self.continue_generation = true,
+            _ => self.help = true,
+        }
+    }
+
+    fn apply_key_value(&mut self, key: &str, value: &str) {
+        match key {
+            "model" => self.model = Some(value.to_string()),
+            "persona" => self.persona = Some(value.to_string()),
+            _ => match value.parse::<f32>() {
+                Ok(temperature) if (0.0..=2.0).contains(&temperature) => {
+                    self.temperature = Some(temperature)
+                }
+                _ => self.unknown.push(format!("{}:{}", key, value)),
+            },
+        }
+    }
+
+    // past(数字)の指定を上限の範囲に収めて、最新のメッセージの分を+1した数を返す
+    pub fn limit(&self, default: i32, max_past: i32) -> i32 {
+        let past_num = self.past.map(|p| p.clamp(0, max_past)).unwrap_or(default);
+        past_num + 1
+    }
+}
+
+// 2つの文字列の編集距離(レーベンシュタイン距離)
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let cost = usize::from(ca != *cb);
+            current.push((prev[j] + cost).min(prev[j + 1] + 1).min(current[j] + 1));
+        }
+        prev = current;
+    }
+    prev[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let directives =
+            MessageDirectives::parse("<@UBOT> temp:0.8 !fresh model:gpt-4o-mini past3 こんにちは");
+        assert_eq!(
+            directives,
+            MessageDirectives {
+                past: Some(3),
+                model: Some("gpt-4o-mini".into()),
+                temperature: Some(0.8),
+                fresh: true,
+                body: "こんにちは".into(),
+                ..Default::default()
+            }
+        );
+
+        // 範囲外の値は報告する
+        let directives = MessageDirectives::parse("temp:3 persona:reviewer help");
+        assert_eq!(directives.unknown, vec!["temp:3"]);
+        assert_eq!(directives.persona.as_deref(), Some("reviewer"));
+        assert!(directives.help);
+        assert_eq!(directives.body, "");
+
+        let directives = MessageDirectives::parse("<@UBOT> continue");
+        assert!(directives.continue_generation);
+        assert_eq!(directives.body, "");
+
+        // 本文が続く場合は"!"がなければ本文とみなす
+        let directives = MessageDirectives::parse("<@UBOT> help me fix this");
+        assert!(!directives.help);
+        assert_eq!(directives.body, "help me fix this");
+        let directives = MessageDirectives::parse("past3 fresh ideas for dinner");
+        assert!(!directives.fresh);
+        assert_eq!(directives.body, "fresh ideas for dinner");
+        let directives = MessageDirectives::parse("continue the story");
+        assert!(!directives.continue_generation);
+        assert_eq!(directives.body, "continue the story");
+        let directives = MessageDirectives::parse("!fresh ideas for dinner");
+        assert!(directives.fresh);
+        assert_eq!(directives.body, "ideas for dinner");
+
+        // 不明な指定は報告し、本文に残す
+        let directives = MessageDirectives::parse("past3 modle:gpt-4o hello");
+        assert_eq!(directives.past, Some(3));
+        assert_eq!(directives.unknown, vec!["modle:gpt-4o"]);
+        assert_eq!(directives.body, "modle:gpt-4o hello");
+
+        // 指定の書き間違いとみられない場合は報告せず本文とみなす
+        let directives = MessageDirectives::parse("note:foo bar");
+        assert!(directives.unknown.is_empty());
+        assert_eq!(directives.body, "note:foo bar");
+        let directives = MessageDirectives::parse("error:timeout が出ます");
+        assert!(directives.unknown.is_empty());
+        assert_eq!(directives.body, "error:timeout が出ます");
+
+        // 本文の途中の指定やURLは本文とみなす
+        let directives = MessageDirectives::parse("helpful https://example.com past3");
+        assert_eq!(
+            directives,
+            MessageDirectives {
+                body: "helpful https://example.com past3".into(),
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_edit_distance() {
+        assert_eq!(edit_distance("model", "model"), 0);
+        assert_eq!(edit_distance("model", "modle"), 2);
+        assert_eq!(edit_distance("temp", "tmp"), 1);
+        assert_eq!(edit_distance("model", "note"), 3);
+    }
+
+    #[test]
+    fn test_limit() {
+        let directives = MessageDirectives::parse("past100 hello");
+        assert_eq!(directives.limit(5, 10), 11);
+        let directives = MessageDirectives::parse("hello");
+        assert_eq!(directives.limit(5, 10), 6);
+    }
+}
@@ -0,0 +1,145 @@
+const CODE_FENCE: &str = "```";
+
+// Slackの1メッセージに収まるよう、textを先頭部分と残りに分割する
+// 分割不要の場合はNoneを返す
+// NOTE: なるべくコードブロックの外の改行で分割し、コードブロック内で分割する場合は閉じてから続きで開き直す
+pub fn split_message(text: &str, limit: usize) -> Option<(String, String)> {
+    if text.chars().count() <= limit {
+        return None;
+    }
+    // コードブロックを閉じる分の余裕を残す
+    let limit_bytes = byte_index_of_char(text, limit.saturating_sub(CODE_FENCE.len() + 1));
+
+    // 改行の直後の位置と、その位置がコードブロック内の場合はコードブロックの開始行
+    let mut boundaries: Vec<(usize, Option<&str>)> = Vec::new();
+    let mut open_fence: Option<&str> = None;
+    let mut line_start = 0;
+    for line in text.split_inclusive('\n') {
+        let line_end = line_start + line.len();
+        if line_end > limit_bytes {
+            break;
+        }
+        if line.ends_with('\n') {
+            open_fence = toggle_fence(open_fence, line);
+            boundaries.push((line_end, open_fence));
+        }
+        line_start = line_end;
+    }
+
+    // コードブロック外の最後の改行で分割する
+    if let Some((index, _)) = boundaries.iter().rev().find(|(_, fence)| fence.is_none()) {
+        if *index > 0 {
+            let (head, rest) = text.split_at(*index);
+            return Some((head.trim_end().to_string(), rest.to_string()));
+        }
+    }
+
+    // コードブロック内でしか分割できない場合は、コードブロックを閉じて続きで開き直す
+    // NOTE: 言語の指定が引き継がれるよう、開始行をそのまま使って開き直す
+    //       開き直した後の方が長くなる(コードブロックの開始行でしか分割できない)場合は除く
+    if let Some((index, Some(fence))) = boundaries.last() {
+        let (head, rest) = text.split_at(*index);
+        let fence = fence.trim_end();
+        if *index > fence.len() + 1 {
+            return Some((
+                format!("{}{}", head, CODE_FENCE),
+                format!("{}\n{}", fence, rest),
+            ));
+        }
+    }
+
+    // 改行が無い場合は文字数で分割する
+    // NOTE: コードブロック内で分割する場合は、同様に閉じてから続きで開き直す
+    let (head, rest) = text.split_at(limit_bytes);
+    let fence = head
+        .split_inclusive('\n')
+        .fold(None, |fence, line| toggle_fence(fence, line));
+    match fence {
+        Some(fence) => {
+            let fence = if fence.ends_with('\n') && fence.len() + 1 < head.len() {
+                fence.trim_end()
+            } else {
+                CODE_FENCE
+            };
+            Some((
+                format!("{}\n{}", head, CODE_FENCE),
+                format!("{}\n{}", fence, rest),
+            ))
+        }
+        None => Some((head.to_string(), rest.to_string())),
+    }
+}
+
+// 行の中のコードブロックの区切りの数から、行の後でコードブロック内かどうかを求める
+// コードブロック内の場合は開始行(開始の区切り以降、改行を含む)を返す
+fn toggle_fence<'a>(open_fence: Option<&'a str>, line: &'a str) -> Option<&'a str> {
+    if line.matches(CODE_FENCE).count() % 2 != 1 {
+        return open_fence;
+    }
+    match open_fence {
+        Some(_) => None,
+        None => line.rfind(CODE_FENCE).map(|i| &line[i..]),
+    }
+}
+
+// n文字目のbyte位置
+fn byte_index_of_char(text: &str, n: usize) -> usize {
+    text.char_indices()
+        .nth(n)
+        .map(|(i, _)| i)
+        .unwrap_or(text.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_message_not_required() {
+        assert_eq!(split_message("にゃーん", 10), None);
+    }
+
+    #[test]
+    fn test_split_message_outside_code_block() {
+        let text = "line1\nline2\n```\ncode1\ncode2\n```\nline3";
+        let (head, rest) = split_message(text, 24).unwrap();
+        assert_eq!(head, "line1\nline2");
+        assert_eq!(rest, "```\ncode1\ncode2\n```\nline3");
+    }
+
+    #[test]
+    fn test_split_message_inside_code_block() {
+        let text = "```\ncode1\ncode2\ncode3\ncode4\n```";
+        let (head, rest) = split_message(text, 20).unwrap();
+        assert_eq!(head, "```\ncode1\ncode2\n```");
+        assert_eq!(rest, "```\ncode3\ncode4\n```");
+    }
+
+    #[test]
+    fn test_split_message_keeps_fence_language() {
+        let text = "```rust\nlet a = 1;\nlet b = 2;\nlet c = 3;\n```";
+        let (head, rest) = split_message(text, 34).unwrap();
+        assert_eq!(head, "```rust\nlet a = 1;\nlet b = 2;\n```");
+        assert_eq!(rest, "```rust\nlet c = 3;\n```");
+    }
+
+    #[test]
+    fn test_split_message_without_newline_inside_code_block() {
+        let text = "```rust\nabcdefghijklmnopqrstuvwxyz\n```";
+        let (head, rest) = split_message(text, 20).unwrap();
+        assert_eq!(head, "```rust\nabcdefgh\n```");
+        assert_eq!(rest, "```rust\nijklmnopqrstuvwxyz\n```");
+
+        // 開始行の途中で分割する場合は言語の指定を引き継がない
+        let (head, rest) = split_message("```abcdefghijklmnopqrstuvwxyz", 10).unwrap();
+        assert_eq!(head, "```abc\n```");
+        assert_eq!(rest, "```\ndefghijklmnopqrstuvwxyz");
+    }
+
+    #[test]
+    fn test_split_message_without_newline() {
+        let (head, rest) = split_message("あいうえおかきくけこ", 8).unwrap();
+        assert_eq!(head, "あいうえ");
+        assert_eq!(rest, "おかきくけこ");
+    }
+}
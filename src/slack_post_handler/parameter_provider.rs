@@ -0,0 +1,243 @@
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use aws_config::{BehaviorVersion, Region};
+use aws_sdk_ssm::Client;
+use thiserror::Error;
+use tokio::sync::RwLock;
+
+use super::handle_request::{get_enviroment_variable, Parameters};
+
+// パラメータをキャッシュする秒数のデフォルト値
+const DEFAULT_CACHE_TTL_SECS: u64 = 300;
+// 環境変数からパラメータを読み込む場合のprefix
+const ENV_PARAMETER_PREFIX: &str = "CATGPT_";
+
+#[derive(Error, Debug)]
+pub enum ParameterError {
+    #[error("Failed to get enviroment variable: {0}")]
+    EnviromentVariableError(String),
+    #[error("Missing setting: {0}")]
+    MissingSetting(&'static str),
+    #[error("Unknown parameter source: {0}")]
+    UnknownSource(String),
+    #[error("Failed to get parameter from SSM: {0}")]
+    SsmError(String),
+    #[error("Parameter has no value: {0}")]
+    EmptyValue(String),
+    #[error("Failed to read parameter file: {0}, {1}")]
+    ReadFileError(String, std::io::Error),
+    #[error("Failed to parse parameters: {0}")]
+    ParseError(String),
+}
+
+// Parametersの取得元
+#[async_trait]
+pub trait ParameterProvider: Send + Sync {
+    async fn get_parameters(&self) -> Result<Parameters, ParameterError>;
+}
+
+// SSM ParameterStoreからJSON形式のパラメータを取得する
+pub struct SsmParameterProvider {
+    name: String,
+    region: Option<String>,
+}
+
+impl SsmParameterProvider {
+    // regionを指定しない場合はAWS_REGIONなどのデフォルト設定に従う
+    pub fn new(name: &str, region: Option<&str>) -> Self {
+        Self {
+            name: name.into(),
+            region: region.map(|r| r.into()),
+        }
+    }
+}
+
+#[async_trait]
+impl ParameterProvider for SsmParameterProvider {
+    async fn get_parameters(&self) -> Result<Parameters, ParameterError> {
+        let mut config_loader = aws_config::defaults(BehaviorVersion::v2023_11_09());
+        if let Some(region) = &self.region {
+            config_loader = config_loader.region(Region::new(region.clone()));
+        }
+        let client = Client::new(&config_loader.load().await);
+
+        let resp = client
+            .get_parameter()
+            .with_decryption(true)
+            .name(&self.name)
+            .send()
+            .await
+            .map_err(|e| ParameterError::SsmError(e.to_string()))?;
+
+        let value = resp
+            .parameter()
+            .and_then(|p| p.value())
+            .ok_or_else(|| ParameterError::EmptyValue(self.name.clone()))?;
+        serde_json::from_str(value).map_err(|e| ParameterError::ParseError(e.to_string()))
+    }
+}
+
+// CATGPT_BOT_MEMBER_IDのような環境変数からパラメータを取得する
+pub struct EnvParameterProvider;
+
+#[async_trait]
+impl ParameterProvider for EnvParameterProvider {
+    async fn get_parameters(&self) -> Result<Parameters, ParameterError> {
+        envy::prefixed(ENV_PARAMETER_PREFIX)
+            .from_env::<Parameters>()
+            .map_err(|e| ParameterError::ParseError(e.to_string()))
+    }
+}
+
+// ローカルのファイルからパラメータを取得する
+// 拡張子が.tomlの場合はTOML、それ以外はJSONとして読み込む
+pub struct FileParameterProvider {
+    path: String,
+}
+
+impl FileParameterProvider {
+    pub fn new(path: &str) -> Self {
+        Self { path: path.into() }
+    }
+}
+
+#[async_trait]
+impl ParameterProvider for FileParameterProvider {
+    async fn get_parameters(&self) -> Result<Parameters, ParameterError> {
+        let content = tokio::fs::read_to_string(&self.path)
+            .await
+            .map_err(|e| ParameterError::ReadFileError(self.path.clone(), e))?;
+
+        if self.path.ends_with(".toml") {
+            toml::from_str(&content).map_err(|e| ParameterError::ParseError(e.to_string()))
+        } else {
+            serde_json::from_str(&content).map_err(|e| ParameterError::ParseError(e.to_string()))
+        }
+    }
+}
+
+// 取得したパラメータを一定時間保持する
+// Lambdaのwarm start時はSSMへの問い合わせを省略できる
+pub struct CachedParameterProvider<P: ParameterProvider> {
+    inner: P,
+    ttl: Duration,
+    cache: RwLock<Option<(Instant, Parameters)>>,
+}
+
+impl<P: ParameterProvider> CachedParameterProvider<P> {
+    pub fn new(inner: P, ttl: Duration) -> Self {
+        Self {
+            inner,
+            ttl,
+            cache: RwLock::new(None),
+        }
+    }
+}
+
+#[async_trait]
+impl<P: ParameterProvider> ParameterProvider for CachedParameterProvider<P> {
+    async fn get_parameters(&self) -> Result<Parameters, ParameterError> {
+        if let Some((fetched_at, parameters)) = self.cache.read().await.as_ref() {
+            if fetched_at.elapsed() < self.ttl {
+                return Ok(parameters.clone());
+            }
+        }
+
+        let parameters = self.inner.get_parameters().await?;
+        *self.cache.write().await = Some((Instant::now(), parameters.clone()));
+        Ok(parameters)
+    }
+}
+
+// 環境変数parameter_sourceに応じてParameterProviderを生成する
+// ssm(デフォルト) | env | file
+pub fn parameter_provider_from_env() -> Result<Box<dyn ParameterProvider>, ParameterError> {
+    let env_vars = get_enviroment_variable()
+        .map_err(|e| ParameterError::EnviromentVariableError(e.to_string()))?;
+    let ttl = Duration::from_secs(
+        env_vars
+            .parameter_cache_ttl_secs
+            .unwrap_or(DEFAULT_CACHE_TTL_SECS),
+    );
+
+    let source = env_vars.parameter_source.as_deref().unwrap_or("ssm");
+    let provider: Box<dyn ParameterProvider> = match source {
+        "ssm" => {
+            let name = env_vars
+                .parameter_store_name
+                .ok_or(ParameterError::MissingSetting("parameter_store_name"))?;
+            let provider =
+                SsmParameterProvider::new(&name, env_vars.parameter_store_region.as_deref());
+            Box::new(CachedParameterProvider::new(provider, ttl))
+        }
+        "env" => Box::new(CachedParameterProvider::new(EnvParameterProvider, ttl)),
+        "file" => {
+            let path = env_vars
+                .parameter_file_path
+                .ok_or(ParameterError::MissingSetting("parameter_file_path"))?;
+            Box::new(CachedParameterProvider::new(
+                FileParameterProvider::new(&path),
+                ttl,
+            ))
+        }
+        _ => return Err(ParameterError::UnknownSource(source.into())),
+    };
+    Ok(provider)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    struct CountingProvider {
+        count: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl ParameterProvider for CountingProvider {
+        async fn get_parameters(&self) -> Result<Parameters, ParameterError> {
+            self.count.fetch_add(1, Ordering::SeqCst);
+            serde_json::from_str(
+                r#"{"bot_member_id": "U1", "slack_auth_token": "xoxb", "openai_secret_key": "sk", "slack_signing_secret": "secret"}"#,
+            )
+            .map_err(|e| ParameterError::ParseError(e.to_string()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_cached_parameter_provider() {
+        let inner = CountingProvider {
+            count: AtomicUsize::new(0),
+        };
+        let provider = CachedParameterProvider::new(inner, Duration::from_secs(60));
+
+        provider.get_parameters().await.unwrap();
+        let parameters = provider.get_parameters().await.unwrap();
+
+        assert_eq!(parameters.bot_member_id, "U1");
+        assert_eq!(provider.inner.count.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_file_parameter_provider_toml() {
+        let path = std::env::temp_dir().join("cat_gpt_test_parameters.toml");
+        std::fs::write(
+            &path,
+            "bot_member_id = \"U1\"\nslack_auth_token = \"xoxb\"\nopenai_secret_key = \"sk\"\nslack_signing_secret = \"secret\"\n",
+        )
+        .unwrap();
+
+        let provider = FileParameterProvider::new(path.to_str().unwrap());
+        let parameters = provider.get_parameters().await.unwrap();
+        assert_eq!(parameters.slack_auth_token, "xoxb");
+    }
+
+    #[tokio::test]
+    async fn test_file_parameter_provider_missing_file() {
+        let provider = FileParameterProvider::new("/not/exists/parameters.json");
+        let result = provider.get_parameters().await;
+        assert!(matches!(result, Err(ParameterError::ReadFileError(_, _))));
+    }
+}
@@ -0,0 +1,142 @@
+use serde_derive::Deserialize;
+
+use crate::constants::{CHAT_GPT_SYSTEM_PROMPT, DEFAULT_PERSONA_NAME};
+
+use super::channel_config::find_channel_config;
+use super::handle_request::{Env, Parameters};
+use super::slack_message::SlackMessage;
+
+// botの人格。system promptと、使用するモデルなどの設定
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub struct Persona {
+    pub name: String,
+    pub system_prompt: String,
+    // 未設定の場合はEnvの値を使う
+    pub model: Option<String>,
+    pub temperature: Option<f32>,
+    // 使用するprovider名
+    pub provider: Option<String>,
+    // モデルのコンテキストウィンドウのトークン数。未設定の場合はモデル名から判断する
+    pub context_window: Option<usize>,
+}
+
+impl Persona {
+    // 組み込みのネコ型の人格
+    pub fn cat() -> Self {
+        Self {
+            name: DEFAULT_PERSONA_NAME.to_string(),
+            system_prompt: CHAT_GPT_SYSTEM_PROMPT.to_string(),
+            model: None,
+            temperature: None,
+            provider: None,
+            context_window: None,
+        }
+    }
+
+    pub fn model(&self, env_vars: &Env) -> String {
+        self.model.clone().unwrap_or(env_vars.gpt_model.clone())
+    }
+
+    pub fn temperature(&self, env_vars: &Env) -> f32 {
+        self.temperature.unwrap_or(env_vars.temperature)
+    }
+}
+
+// 名前から人格を探す
+// NOTE: パラメータで同名の人格が設定されている場合は組み込みの人格より優先する
+pub fn find_persona(parameters: &Parameters, name: &str) -> Option<Persona> {
+    parameters
+        .personas
+        .iter()
+        .find(|p| p.name == name)
+        .cloned()
+        .or_else(|| (name == DEFAULT_PERSONA_NAME).then(Persona::cat))
+}
+
+// 返答に使う人格を決める
+// NOTE: メッセージ(時系列順)の中で最後に指定された人格、チャンネルのデフォルト、組み込みの人格の順に優先する
+pub fn resolve_persona(
+    parameters: &Parameters,
+    channel: Option<&str>,
+    messages: &[SlackMessage],
+) -> Persona {
+    let requested = messages
+        .iter()
+        .rev()
+        .filter(|m| !m.is_from(&parameters.bot_member_id))
+        .find_map(|m| m.get_persona_name());
+    if let Some(name) = requested {
+        match find_persona(parameters, &name) {
+            Some(persona) => return persona,
+            None => eprintln!("Error: Unknown persona: {}", name),
+        }
+    }
+
+    find_channel_config(parameters, channel)
+        .and_then(|config| config.persona.as_deref())
+        .and_then(|name| find_persona(parameters, name))
+        .unwrap_or_else(Persona::cat)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_parameters() -> Parameters {
+        serde_json::from_value(serde_json::json!({
+            "bot_member_id": "UBOT",
+            "slack_auth_token": "xoxb",
+            "openai_secret_key": "sk",
+            "slack_signing_secret": "secret",
+            "personas": [
+                {"name": "reviewer", "system_prompt": "You review code.", "model": "gpt-4o"},
+                {"name": "translator", "system_prompt": "You translate."},
+            ],
+            "channels": {"CDEV": {"persona": "reviewer"}},
+        }))
+        .unwrap()
+    }
+
+    fn message(text: &str, user: &str) -> SlackMessage {
+        SlackMessage {
+            text: text.into(),
+            user: user.into(),
+            ts: "1627777777.000000".into(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_resolve_persona() {
+        let parameters = test_parameters();
+
+        // 指定がない場合はチャンネルのデフォルト、それもない場合は組み込みの人格
+        let messages = vec![message("こんにちは", "U01")];
+        assert_eq!(
+            resolve_persona(&parameters, Some("CDEV"), &messages).name,
+            "reviewer"
+        );
+        assert_eq!(
+            resolve_persona(&parameters, Some("CRANDOM"), &messages),
+            Persona::cat()
+        );
+
+        // スレッド内で最後に指定された人格を使う
+        let messages = vec![
+            message("<@UBOT> persona:translator hello", "U01"),
+            message("persona:cat", "UBOT"),
+            message("ありがとう", "U01"),
+        ];
+        assert_eq!(
+            resolve_persona(&parameters, Some("CDEV"), &messages).name,
+            "translator"
+        );
+
+        // 存在しない人格の場合はデフォルトに戻す
+        let messages = vec![message("persona:unknown hello", "U01")];
+        assert_eq!(
+            resolve_persona(&parameters, None, &messages),
+            Persona::cat()
+        );
+    }
+}
@@ -0,0 +1,259 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use serde_derive::Deserialize;
+
+use crate::constants::{
+    QUOTA_CHANNEL_TOKENS_PER_DAY_MESSAGE, QUOTA_REQUESTS_PER_MINUTE_MESSAGE,
+    QUOTA_USER_TOKENS_PER_DAY_MESSAGE, USAGE_DAY_OFFSET_SECS,
+};
+
+use super::handle_request::Parameters;
+use super::time::{format_time, today};
+use super::usage_ledger::UsageStore;
+
+// パラメータの`quotas`に書く設定。未設定の項目は制限しない
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct QuotaConfig {
+    // ユーザーごとの1分あたりのリクエスト数
+    pub requests_per_minute: Option<usize>,
+    // ユーザーごとの1日あたりのトークン数
+    pub user_tokens_per_day: Option<u64>,
+    // チャンネルごとの1日あたりのトークン数
+    pub channel_tokens_per_day: Option<u64>,
+    // 制限の対象外とするユーザーID
+    #[serde(default)]
+    pub admins: Vec<String>,
+}
+
+// 超過した制限と、制限がリセットされるunix時刻
+#[derive(Debug, PartialEq)]
+pub enum QuotaExceeded {
+    RequestsPerMinute { reset_at: i64 },
+    UserTokensPerDay { reset_at: i64 },
+    ChannelTokensPerDay { reset_at: i64 },
+}
+
+impl QuotaExceeded {
+    // Slackに投稿するメッセージ
+    pub fn message(&self) -> String {
+        let (message, reset_at) = match self {
+            QuotaExceeded::RequestsPerMinute { reset_at } => {
+                (QUOTA_REQUESTS_PER_MINUTE_MESSAGE, reset_at)
+            }
+            QuotaExceeded::UserTokensPerDay { reset_at } => {
+                (QUOTA_USER_TOKENS_PER_DAY_MESSAGE, reset_at)
+            }
+            QuotaExceeded::ChannelTokensPerDay { reset_at } => {
+                (QUOTA_CHANNEL_TOKENS_PER_DAY_MESSAGE, reset_at)
+            }
+        };
+        format!(
+            "{}\n{} (日本時間) にリセットされますにゃ。",
+            message,
+            format_reset_time(*reset_at)
+        )
+    }
+}
+
+fn now_secs() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+// e.g. "03/01 12:34:56"
+fn format_reset_time(unix_secs: i64) -> String {
+    let time = format_time(unix_secs, USAGE_DAY_OFFSET_SECS);
+    format!("{}/{} {}", &time[5..7], &time[8..10], &time[11..19])
+}
+
+// 次に日付が変わるunix時刻
+fn next_day_starts_at(now: i64) -> i64 {
+    let local_days = (now + USAGE_DAY_OFFSET_SECS).div_euclid(86400);
+    (local_days + 1) * 86400 - USAGE_DAY_OFFSET_SECS
+}
+
+// 返答する前に制限を確認する
+// NOTE: 1日あたりのトークン数は使用量の記録から求め、1分あたりのリクエスト数はUsageStoreで数える
+pub async fn check_quota(
+    parameters: &Parameters,
+    user: &str,
+    channel: &str,
+    usage_store: &dyn UsageStore,
+) -> Result<Option<QuotaExceeded>> {
+    let quotas = &parameters.quotas;
+    if quotas.admins.iter().any(|admin| admin == user) {
+        return Ok(None);
+    }
+    let now = now_secs();
+
+    if quotas.user_tokens_per_day.is_some() || quotas.channel_tokens_per_day.is_some() {
+        let records = usage_store.records(&today()).await?;
+        let used_tokens = |matches: &dyn Fn(&str, &str) -> bool| -> u64 {
+            records
+                .iter()
+                .filter(|r| matches(&r.user, &r.channel))
+                .map(|r| r.total_tokens())
+                .sum()
+        };
+        let reset_at = next_day_starts_at(now);
+        if let Some(limit) = quotas.user_tokens_per_day {
+            if used_tokens(&|u, _| u == user) >= limit {
+                return Ok(Some(QuotaExceeded::UserTokensPerDay { reset_at }));
+            }
+        }
+        if let Some(limit) = quotas.channel_tokens_per_day {
+            if used_tokens(&|_, c| c == channel) >= limit {
+                return Ok(Some(QuotaExceeded::ChannelTokensPerDay { reset_at }));
+            }
+        }
+    }
+
+    if let Some(limit) = quotas.requests_per_minute {
+        let minute = now.div_euclid(60);
+        if !usage_store.acquire_request(user, minute, limit).await? {
+            let reset_at = (minute + 1) * 60;
+            return Ok(Some(QuotaExceeded::RequestsPerMinute { reset_at }));
+        }
+    }
+    Ok(None)
+}
+
+// 制限を超えている場合は知らせる文を返す
+// NOTE: 確認に失敗した場合は制限せずに返答する
+pub async fn quota_exceeded_message(
+    parameters: &Parameters,
+    user: &str,
+    channel: &str,
+    usage_store: &dyn UsageStore,
+) -> Option<String> {
+    match check_quota(parameters, user, channel, usage_store).await {
+        Ok(Some(exceeded)) => {
+            #[cfg(debug_assertions)]
+            {
+                println!(
+                    "Quota exceeded: user={}, channel={}, {:?}",
+                    user, channel, exceeded
+                );
+            }
+            Some(exceeded.message())
+        }
+        Ok(None) => None,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::slack_post_handler::chat_gpt_res_body::ChatGptUsage;
+    use crate::slack_post_handler::usage_ledger::{InMemoryUsageStore, UsageRecord};
+
+    #[tokio::test]
+    async fn test_requests_per_minute() {
+        let parameters: Parameters = serde_json::from_value(serde_json::json!({
+            "bot_member_id": "UBOT",
+            "slack_auth_token": "xoxb",
+            "openai_secret_key": "sk",
+            "slack_signing_secret": "secret",
+            "quotas": {"requests_per_minute": 2},
+        }))
+        .unwrap();
+        let usage_store = InMemoryUsageStore::default();
+        assert!(usage_store.acquire_request("U01", 16, 2).await.unwrap());
+        assert!(usage_store.acquire_request("U01", 16, 2).await.unwrap());
+        assert!(!usage_store.acquire_request("U01", 16, 2).await.unwrap());
+        assert!(usage_store.acquire_request("U02", 16, 2).await.unwrap());
+        // 次の分は数え直す
+        assert!(usage_store.acquire_request("U01", 17, 2).await.unwrap());
+
+        // 記録はUsageStoreで共有する
+        assert_eq!(
+            check_quota(&parameters, "U03", "C01", &usage_store)
+                .await
+                .unwrap(),
+            None
+        );
+        assert_eq!(
+            check_quota(&parameters, "U03", "C01", &usage_store)
+                .await
+                .unwrap(),
+            None
+        );
+        let exceeded = check_quota(&parameters, "U03", "C01", &usage_store)
+            .await
+            .unwrap();
+        assert!(matches!(
+            exceeded,
+            Some(QuotaExceeded::RequestsPerMinute { .. })
+        ));
+        assert!(
+            quota_exceeded_message(&parameters, "U03", "C01", &usage_store)
+                .await
+                .is_some_and(|message| message.starts_with(QUOTA_REQUESTS_PER_MINUTE_MESSAGE))
+        );
+    }
+
+    #[test]
+    fn test_next_day_starts_at() {
+        // 2024-03-01T12:00:00+09:00 -> 2024-03-02T00:00:00+09:00
+        assert_eq!(next_day_starts_at(1709262000), 1709305200);
+        assert_eq!(format_reset_time(1709305200), "03/02 00:00:00");
+    }
+
+    #[tokio::test]
+    async fn test_check_quota() {
+        let parameters: Parameters = serde_json::from_value(serde_json::json!({
+            "bot_member_id": "UBOT",
+            "slack_auth_token": "xoxb",
+            "openai_secret_key": "sk",
+            "slack_signing_secret": "secret",
+            "quotas": {"user_tokens_per_day": 1000, "channel_tokens_per_day": 1500, "admins": ["UADMIN"]},
+        }))
+        .unwrap();
+        let usage_store = InMemoryUsageStore::default();
+        let usage = ChatGptUsage {
+            prompt_tokens: 900,
+            completion_tokens: 100,
+        };
+        for user in ["U01", "UADMIN"] {
+            usage_store
+                .record(&UsageRecord::new(user, "C01", "gpt-4o", &usage))
+                .await
+                .unwrap();
+        }
+
+        let exceeded = check_quota(&parameters, "U01", "C02", &usage_store)
+            .await
+            .unwrap();
+        assert!(matches!(
+            exceeded,
+            Some(QuotaExceeded::UserTokensPerDay { .. })
+        ));
+        let exceeded = check_quota(&parameters, "U02", "C01", &usage_store)
+            .await
+            .unwrap();
+        assert!(matches!(
+            exceeded,
+            Some(QuotaExceeded::ChannelTokensPerDay { .. })
+        ));
+        assert_eq!(
+            check_quota(&parameters, "U02", "C02", &usage_store)
+                .await
+                .unwrap(),
+            None
+        );
+        // 管理者は制限しない
+        assert_eq!(
+            check_quota(&parameters, "UADMIN", "C01", &usage_store)
+                .await
+                .unwrap(),
+            None
+        );
+    }
+}
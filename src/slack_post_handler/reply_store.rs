@@ -0,0 +1,283 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use aws_config::BehaviorVersion;
+use aws_sdk_dynamodb::error::SdkError;
+use aws_sdk_dynamodb::types::AttributeValue;
+use aws_sdk_dynamodb::Client;
+use thiserror::Error;
+
+use super::handle_request::get_enviroment_variable;
+
+// 返答を書き換えられるよう対応を保持する秒数
+const REPLY_TTL_SECS: i64 = 60 * 60 * 24 * 30;
+// 返答の生成中とみなす秒数
+// NOTE: workerが途中で止まった場合でも、Lambdaのタイムアウト(最大15分)を過ぎれば書き換えられるようにする
+const REPLY_IN_PROGRESS_SECS: i64 = 60 * 15;
+
+#[derive(Error, Debug)]
+pub enum ReplyStoreError {
+    #[error("DynamoDB error: {0}")]
+    DynamoDbError(String),
+}
+
+// 返答のきっかけとなったメッセージのtsと、botの返答のtsの対応を記録する
+// NOTE: メッセージが編集された場合に、以前の返答を書き換えるために使う
+//       返答が長く複数のメッセージに分かれた場合は、すべてのtsを投稿順に記録する
+#[async_trait]
+pub trait ReplyStore: Send + Sync {
+    // 返答の生成を始める。同じメッセージへの返答を生成中の場合は記録せずにfalseを返す
+    async fn begin(&self, channel: &str, trigger_ts: &str, reply_ts: &[String]) -> Result<bool>;
+    // 返答の生成を終え、投稿したすべての返答のtsを記録する
+    async fn finish(&self, channel: &str, trigger_ts: &str, reply_ts: &[String]) -> Result<()>;
+    // 返答のts。記録がない場合はNone
+    async fn find(&self, channel: &str, trigger_ts: &str) -> Result<Option<Vec<String>>>;
+}
+
+fn now_secs() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+fn reply_key(channel: &str, trigger_ts: &str) -> String {
+    format!("{}:{}", channel, trigger_ts)
+}
+
+// 記録した返答
+struct ReplyRecord {
+    reply_ts: Vec<String>,
+    in_progress: bool,
+    updated_at: i64,
+}
+
+// プロセス内のメモリに記録する(ローカル実行・テスト用)
+#[derive(Default)]
+pub struct InMemoryReplyStore {
+    replies: Mutex<HashMap<String, ReplyRecord>>,
+}
+
+impl InMemoryReplyStore {
+    fn insert(&self, channel: &str, trigger_ts: &str, reply_ts: &[String], in_progress: bool) {
+        let mut replies = self.replies.lock().unwrap();
+        // 古い記録は削除する
+        let expired_before = now_secs() - REPLY_TTL_SECS;
+        replies.retain(|_, record| record.updated_at > expired_before);
+        replies.insert(
+            reply_key(channel, trigger_ts),
+            ReplyRecord {
+                reply_ts: reply_ts.to_vec(),
+                in_progress,
+                updated_at: now_secs(),
+            },
+        );
+    }
+}
+
+#[async_trait]
+impl ReplyStore for InMemoryReplyStore {
+    async fn begin(&self, channel: &str, trigger_ts: &str, reply_ts: &[String]) -> Result<bool> {
+        let is_replying = self
+            .replies
+            .lock()
+            .unwrap()
+            .get(&reply_key(channel, trigger_ts))
+            .is_some_and(|record| {
+                record.in_progress && record.updated_at > now_secs() - REPLY_IN_PROGRESS_SECS
+            });
+        if is_replying {
+            return Ok(false);
+        }
+        self.insert(channel, trigger_ts, reply_ts, true);
+        Ok(true)
+    }
+
+    async fn finish(&self, channel: &str, trigger_ts: &str, reply_ts: &[String]) -> Result<()> {
+        self.insert(channel, trigger_ts, reply_ts, false);
+        Ok(())
+    }
+
+    async fn find(&self, channel: &str, trigger_ts: &str) -> Result<Option<Vec<String>>> {
+        let replies = self.replies.lock().unwrap();
+        Ok(replies
+            .get(&reply_key(channel, trigger_ts))
+            .map(|record| record.reply_ts.clone()))
+    }
+}
+
+// DynamoDBのテーブルに記録する
+// パーティションキーはtrigger(S)に"チャンネル:ts"、TTL属性はexpires_at(N)
+// 返答のtsは、最初のメッセージをreply_ts(S)に、続きのメッセージをoverflow_ts(L)に記録する
+// endpoint_urlを指定するとDynamoDB Localなどに接続できる
+pub struct DynamoDbReplyStore {
+    client: Client,
+    table_name: String,
+}
+
+impl DynamoDbReplyStore {
+    pub async fn new(table_name: &str, endpoint_url: Option<&str>) -> Self {
+        let shared_config = aws_config::load_defaults(BehaviorVersion::v2023_11_09()).await;
+        let mut config_builder = aws_sdk_dynamodb::config::Builder::from(&shared_config);
+        if let Some(endpoint_url) = endpoint_url {
+            config_builder = config_builder.endpoint_url(endpoint_url);
+        }
+        Self {
+            client: Client::from_conf(config_builder.build()),
+            table_name: table_name.into(),
+        }
+    }
+
+    fn put_reply(
+        &self,
+        channel: &str,
+        trigger_ts: &str,
+        reply_ts: &[String],
+        in_progress: bool,
+    ) -> aws_sdk_dynamodb::operation::put_item::builders::PutItemFluentBuilder {
+        let now = now_secs();
+        let overflow_ts = reply_ts
+            .iter()
+            .skip(1)
+            .map(|ts| AttributeValue::S(ts.clone()))
+            .collect();
+        self.client
+            .put_item()
+            .table_name(&self.table_name)
+            .item("trigger", AttributeValue::S(reply_key(channel, trigger_ts)))
+            .item(
+                "reply_ts",
+                AttributeValue::S(reply_ts.first().cloned().unwrap_or_default()),
+            )
+            .item("overflow_ts", AttributeValue::L(overflow_ts))
+            .item("in_progress", AttributeValue::Bool(in_progress))
+            .item("updated_at", AttributeValue::N(now.to_string()))
+            .item(
+                "expires_at",
+                AttributeValue::N((now + REPLY_TTL_SECS).to_string()),
+            )
+    }
+}
+
+#[async_trait]
+impl ReplyStore for DynamoDbReplyStore {
+    async fn begin(&self, channel: &str, trigger_ts: &str, reply_ts: &[String]) -> Result<bool> {
+        // NOTE: 生成中の記録がない場合のみ書き込む条件付き書き込みのため、同時に編集されても1つだけ始まる
+        let res = self
+            .put_reply(channel, trigger_ts, reply_ts, true)
+            .condition_expression(
+                "attribute_not_exists(#trigger) OR in_progress <> :true OR updated_at < :stale_before",
+            )
+            // NOTE: triggerは予約語のため名前を置き換える
+            .expression_attribute_names("#trigger", "trigger")
+            .expression_attribute_values(":true", AttributeValue::Bool(true))
+            .expression_attribute_values(
+                ":stale_before",
+                AttributeValue::N((now_secs() - REPLY_IN_PROGRESS_SECS).to_string()),
+            )
+            .send()
+            .await;
+
+        match res {
+            Ok(_) => Ok(true),
+            // 生成中の場合は条件付き書き込みが失敗する
+            Err(SdkError::ServiceError(e)) if e.err().is_conditional_check_failed_exception() => {
+                Ok(false)
+            }
+            Err(e) => Err(ReplyStoreError::DynamoDbError(e.to_string()).into()),
+        }
+    }
+
+    async fn finish(&self, channel: &str, trigger_ts: &str, reply_ts: &[String]) -> Result<()> {
+        self.put_reply(channel, trigger_ts, reply_ts, false)
+            .send()
+            .await
+            .map_err(|e| ReplyStoreError::DynamoDbError(e.to_string()))?;
+        Ok(())
+    }
+
+    async fn find(&self, channel: &str, trigger_ts: &str) -> Result<Option<Vec<String>>> {
+        let res = self
+            .client
+            .get_item()
+            .table_name(&self.table_name)
+            .key("trigger", AttributeValue::S(reply_key(channel, trigger_ts)))
+            .send()
+            .await
+            .map_err(|e| ReplyStoreError::DynamoDbError(e.to_string()))?;
+        let Some(item) = res.item() else {
+            return Ok(None);
+        };
+        let reply_ts = item
+            .get("reply_ts")
+            .and_then(|v| v.as_s().ok())
+            .into_iter()
+            .chain(
+                item.get("overflow_ts")
+                    .and_then(|v| v.as_l().ok())
+                    .into_iter()
+                    .flatten()
+                    .filter_map(|v| v.as_s().ok()),
+            )
+            .cloned()
+            .collect();
+        Ok(Some(reply_ts))
+    }
+}
+
+// 環境変数reply_table_nameがあればDynamoDB、なければメモリに記録する
+pub async fn reply_store_from_env() -> Result<Box<dyn ReplyStore>> {
+    let env_vars = get_enviroment_variable()?;
+    let store: Box<dyn ReplyStore> = match env_vars.reply_table_name {
+        Some(table_name) => Box::new(
+            DynamoDbReplyStore::new(&table_name, env_vars.reply_table_endpoint.as_deref()).await,
+        ),
+        None => Box::<InMemoryReplyStore>::default(),
+    };
+    Ok(store)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_in_memory_reply_store() {
+        let store = InMemoryReplyStore::default();
+        let first = vec!["1627777778.000200".to_string()];
+        assert!(store
+            .begin("C01", "1627777777.000100", &first)
+            .await
+            .unwrap());
+
+        assert_eq!(
+            store.find("C01", "1627777777.000100").await.unwrap(),
+            Some(first.clone())
+        );
+        assert_eq!(store.find("C02", "1627777777.000100").await.unwrap(), None);
+
+        // 生成中は始めない
+        assert!(!store
+            .begin("C01", "1627777777.000100", &first)
+            .await
+            .unwrap());
+
+        // 続きのメッセージのtsもすべて記録する
+        let all = vec![
+            "1627777778.000200".to_string(),
+            "1627777779.000300".to_string(),
+        ];
+        store
+            .finish("C01", "1627777777.000100", &all)
+            .await
+            .unwrap();
+        assert_eq!(
+            store.find("C01", "1627777777.000100").await.unwrap(),
+            Some(all.clone())
+        );
+        assert!(store.begin("C01", "1627777777.000100", &all).await.unwrap());
+    }
+}
@@ -1,24 +1,35 @@
 use std::fmt;
 
-use regex::Regex;
-use serde_derive::Deserialize;
+use serde_derive::{Deserialize, Serialize};
 
-#[derive(Deserialize, Clone, Debug)]
+use super::message_directives::MessageDirectives;
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
 pub struct SlackMessage {
+    // NOTE: subtypeがmessage_changedの場合は、編集後のmessageの方に本文がある
+    #[serde(default)]
     pub text: String,
     pub thread_ts: Option<String>,
 
     #[serde(rename = "type")]
     pub type_name: String,
     pub subtype: Option<String>,
+    // NOTE: subtypeがbot_messageの場合などはuserが無い
+    #[serde(default)]
     pub user: String,
+    // botやアプリからのメッセージの場合に付く
+    pub bot_id: Option<String>,
+    pub app_id: Option<String>,
     pub channel: Option<String>,
     pub ts: String,
     pub channel_type: Option<String>,
     pub files: Option<Vec<SharedFile>>,
+    // subtypeがmessage_changedの場合の、編集後と編集前のメッセージ
+    pub message: Option<Box<SlackMessage>>,
+    pub previous_message: Option<Box<SlackMessage>>,
 }
 
-#[derive(Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct SharedFile {
     pub filetype: String,
     pub mimetype: String,
@@ -28,7 +39,7 @@ pub struct SharedFile {
 impl SlackMessage {
     // 指定したユーザーへのメンションかどうか
     pub fn is_mention_to(&self, user_id: &str) -> bool {
-        self.text.contains(&user_id)
+        self.text.contains(user_id)
     }
 
     // bot以外へのメンションかどうか
@@ -36,6 +47,17 @@ impl SlackMessage {
         !self.is_mention_to(bot_id) && self.text.contains("<@")
     }
 
+    // botへのメンションとして送られたapp_mentionイベントかどうか
+    pub fn is_app_mention(&self) -> bool {
+        self.type_name == "app_mention"
+    }
+
+    // app_mentionイベントでも届くメッセージかどうか
+    // NOTE: DM以外でbotへメンションしたメッセージは、messageとapp_mentionの両方のイベントが届く
+    pub fn is_delivered_as_app_mention(&self, bot_id: &str) -> bool {
+        self.type_name == "message" && !self.is_direct_message() && self.is_mention_to(bot_id)
+    }
+
     // スレッド内のメッセージかどうか
     pub fn is_in_thread(&self) -> bool {
         self.thread_ts.is_some()
@@ -51,41 +73,64 @@ impl SlackMessage {
         self.user == user_id
     }
 
-    // メンション文字列とコマンドを削除したメッセージ本文
+    // 使用量の制限や記録に使う送信者のID
+    // NOTE: bot_messageなどuserが空のメッセージは、bot_idかapp_idを使う
+    pub fn sender_id(&self) -> &str {
+        if !self.user.is_empty() {
+            return &self.user;
+        }
+        self.bot_id
+            .as_deref()
+            .or(self.app_id.as_deref())
+            .unwrap_or_default()
+    }
+
+    // 他のbotやアプリ連携からのメッセージかどうか
+    pub fn is_from_bot(&self) -> bool {
+        self.bot_id.is_some()
+            || self.app_id.is_some()
+            || self.subtype.as_deref() == Some("bot_message")
+    }
+
+    // 編集されたメッセージの場合は、チャンネルの情報を補った編集後のメッセージを返す
+    pub fn edited_message(&self) -> Option<SlackMessage> {
+        if self.subtype.as_deref() != Some("message_changed") {
+            return None;
+        }
+        self.message.as_ref().map(|message| SlackMessage {
+            channel: self.channel.clone(),
+            channel_type: self.channel_type.clone(),
+            ..*message.clone()
+        })
+    }
+
+    // 編集で本文が変わったかどうか
+    // NOTE: URLの展開やbot自身の返答の更新などでもmessage_changedが届くため、本文を比べる
+    pub fn is_text_edited(&self) -> bool {
+        match (&self.message, &self.previous_message) {
+            (Some(message), Some(previous_message)) => message.text != previous_message.text,
+            _ => false,
+        }
+    }
+
+    // メッセージの先頭に書かれた指定
+    pub fn directives(&self) -> MessageDirectives {
+        MessageDirectives::parse(&self.text)
+    }
+
+    // メンション文字列と指定を削除したメッセージ本文
     pub fn pure_text(&self) -> String {
-        // メンション文字列
-        let re = Regex::new(r"^<.+> ").unwrap();
-        // past(数字)(過去のメッセージを参照するコマンド)
-        let command_re = Regex::new(r"^past(\d+)").unwrap();
-        let result = re.replace(&self.text, "").to_string();
-        command_re.replace(&result, "").trim().to_string()
+        self.directives().body
+    }
+
+    // persona:(名前)の指定を取得する
+    pub fn get_persona_name(&self) -> Option<String> {
+        self.directives().persona
     }
 
-    // past(数字)コマンドの数字を取得する
+    // past(数字)の指定から、取得するメッセージの数を決める
     pub fn get_limit(&self, default: i32, max_past: i32) -> i32 {
-        let re: Regex = Regex::new(r"^past(\d+)").unwrap();
-        let past = re
-            .captures(&self.text)
-            .and_then(|c| c.get(1))
-            .map(|m| m.as_str().to_string());
-
-        let past_num = match past {
-            Some(past) => match past.parse::<i32>() {
-                Ok(num) => {
-                    if num > max_past {
-                        max_past
-                    } else if num < 0 {
-                        0
-                    } else {
-                        num
-                    }
-                }
-                Err(_) => default,
-            },
-            None => default,
-        };
-        // 最新のメッセージの分を+1する
-        past_num + 1
+        self.directives().limit(default, max_past)
     }
 
     // 新規メッセージのthread_tsを決定する
@@ -103,13 +148,16 @@ impl SlackMessage {
     }
 
     pub fn reply_required(&self, bot_id: &str) -> bool {
-        // typeがメッセージで、subtype無しかfile_share、Bot自身のメッセージでない場合、処理を続行する
-        let is_message_type = self.type_name == "message";
-        let is_file_share_or_no_subtype =
-            self.subtype.is_none() || self.subtype.as_ref().is_some_and(|s| s == "file_share");
+        // typeがメッセージかapp_mentionで、subtype無しかfile_shareかbot_message、Bot自身のメッセージでない場合、処理を続行する
+        // NOTE: 他のbotからのメッセージに返答するかどうかはAccessPolicyで判断する
+        let is_message_type = self.type_name == "message" || self.is_app_mention();
+        let is_valid_subtype = match self.subtype.as_deref() {
+            None => true,
+            Some(subtype) => ["file_share", "bot_message"].contains(&subtype),
+        };
         let is_not_from_bot = !self.is_from(bot_id);
 
-        is_message_type && is_file_share_or_no_subtype && is_not_from_bot
+        is_message_type && is_valid_subtype && is_not_from_bot
     }
 }
 
@@ -135,14 +183,103 @@ mod tests {
             type_name: "message".into(),
             subtype: None,
             user: "U01J9QZQZ9Z".into(),
+            bot_id: None,
+            app_id: None,
             channel: Some("D024BE91L".into()),
             ts: "1627777777.000000".into(),
             channel_type: None,
             files: None,
+            message: None,
+            previous_message: None,
         };
         assert_eq!(message.pure_text(), "こんにちはpast3");
     }
 
+    #[test]
+    fn test_reply_required_app_mention() {
+        let message = SlackMessage {
+            text: "<@UBOT> こんにちは".into(),
+            type_name: "app_mention".into(),
+            user: "U01J9QZQZ9Z".into(),
+            channel: Some("C024BE91L".into()),
+            ts: "1627777777.000000".into(),
+            ..Default::default()
+        };
+        assert!(message.reply_required("UBOT"));
+        assert!(!message.is_delivered_as_app_mention("UBOT"));
+
+        let message_event = SlackMessage {
+            type_name: "message".into(),
+            ..message
+        };
+        assert!(message_event.is_delivered_as_app_mention("UBOT"));
+    }
+
+    #[test]
+    fn test_is_from_bot() {
+        // bot_messageにはuserが無い
+        let message: SlackMessage = serde_json::from_value(serde_json::json!({
+            "type": "message",
+            "subtype": "bot_message",
+            "text": "hello",
+            "bot_id": "B01",
+            "ts": "1627777777.000000",
+        }))
+        .unwrap();
+        assert!(message.is_from_bot());
+        assert!(message.reply_required("UBOT"));
+        // 使用量はbot_idごとに数える
+        assert_eq!(message.sender_id(), "B01");
+
+        let message: SlackMessage = serde_json::from_value(serde_json::json!({
+            "type": "message",
+            "text": "hello",
+            "user": "U01",
+            "ts": "1627777777.000000",
+        }))
+        .unwrap();
+        assert!(!message.is_from_bot());
+        assert_eq!(message.sender_id(), "U01");
+    }
+
+    #[test]
+    fn test_edited_message() {
+        let event: SlackMessage = serde_json::from_value(serde_json::json!({
+            "type": "message",
+            "subtype": "message_changed",
+            "hidden": true,
+            "channel": "C01",
+            "channel_type": "channel",
+            "ts": "1627777779.000000",
+            "message": {
+                "type": "message",
+                "user": "U01",
+                "text": "<@UBOT> こんにちは",
+                "ts": "1627777777.000000",
+                "edited": {"user": "U01", "ts": "1627777779.000000"},
+            },
+            "previous_message": {
+                "type": "message",
+                "user": "U01",
+                "text": "<@UBOT> こんんちは",
+                "ts": "1627777777.000000",
+            },
+        }))
+        .unwrap();
+        assert!(event.is_text_edited());
+        let edited = event.edited_message().unwrap();
+        assert_eq!(edited.ts, "1627777777.000000");
+        assert_eq!(edited.channel.as_deref(), Some("C01"));
+        assert_eq!(edited.pure_text(), "こんにちは");
+        assert!(edited.reply_required("UBOT"));
+
+        let event = SlackMessage {
+            previous_message: event.message.clone(),
+            ..event
+        };
+        assert!(!event.is_text_edited());
+    }
+
     #[test]
     fn test_get_limit() {
         let message = SlackMessage {
@@ -151,10 +288,14 @@ mod tests {
             type_name: "message".into(),
             subtype: None,
             user: "U01J9QZQZ9Z".into(),
+            bot_id: None,
+            app_id: None,
             channel: Some("D024BE91L".into()),
             ts: "1627777777.000000".into(),
             channel_type: None,
             files: None,
+            message: None,
+            previous_message: None,
         };
         assert_eq!(message.get_limit(5, 10), 11);
     }
@@ -0,0 +1,309 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::constants::{
+    ERROR_MESSAGE, LOADING_EMOJI, POLICY_DENIED_MESSAGE, SLASH_COMMAND_ACCEPTED_MESSAGE,
+    SLASH_COMMAND_HELP_MESSAGE, SLASH_COMMAND_QUESTION_HEADER,
+};
+
+use super::api_client::ApiClient;
+use super::builtin_tools::tools_from_env;
+use super::chat_gpt_res_body::ChatGptUsage;
+use super::event_queue::{EventQueue, QueuedJob};
+use super::handle_chat_gpt_response::handle_chat_gpt_response;
+use super::handle_request::{
+    create_request_body_from_contexts, get_channel_enviroment_variable, Parameters,
+};
+use super::persona::resolve_persona;
+use super::quota::quota_exceeded_message;
+use super::slack_message::SlackMessage;
+use super::time::today;
+use super::usage_ledger::{format_usage_report, record_usage, UsageStore};
+
+// https://api.slack.com/interactivity/slash-commands#app_command_handling
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SlashCommand {
+    pub command: String,
+    #[serde(default)]
+    pub text: String,
+    pub user_id: String,
+    pub channel_id: String,
+    pub response_url: Option<String>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum SlashCommandAction {
+    Help,
+    Model,
+    Usage,
+    Prompt(String),
+}
+
+impl SlashCommand {
+    // `/catgpt help`、`/catgpt model`、`/catgpt usage`、`/catgpt <prompt>` を判別する
+    pub fn action(&self) -> SlashCommandAction {
+        let text = self.text.trim();
+        match text {
+            "" | "help" => SlashCommandAction::Help,
+            "model" => SlashCommandAction::Model,
+            "usage" => SlashCommandAction::Usage,
+            _ => SlashCommandAction::Prompt(text.to_string()),
+        }
+    }
+
+    // 返答の前にチャンネルに投稿する、質問者と質問の引用
+    fn question_text(&self, prompt: &str) -> String {
+        let quote: Vec<String> = prompt.lines().map(|line| format!("> {}", line)).collect();
+        format!(
+            "<@{}>{}\n{}",
+            self.user_id,
+            SLASH_COMMAND_QUESTION_HEADER,
+            quote.join("\n")
+        )
+    }
+
+    // ChatGPTのクエリを組み立てるためにSlackメッセージの形式にする
+    fn to_slack_message(&self, prompt: &str) -> SlackMessage {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        SlackMessage {
+            text: prompt.to_string(),
+            type_name: "message".into(),
+            user: self.user_id.clone(),
+            channel: Some(self.channel_id.clone()),
+            ts: format!("{}.{:06}", now.as_secs(), now.subsec_micros()),
+            ..Default::default()
+        }
+    }
+}
+
+// スラッシュコマンドのリクエストを処理し、コマンド実行者にのみ表示されるレスポンスを返す
+pub async fn handle_slash_command_request(
+    body: &str,
+    parameters: &Parameters,
+    event_queue: &dyn EventQueue,
+    usage_store: &dyn UsageStore,
+) -> String {
+    let slash_command: SlashCommand = match serde_urlencoded::from_str(body) {
+        Ok(val) => val,
+        Err(_) => return "NG".to_string(),
+    };
+
+    // 返答しないチャンネルやユーザーの場合は、理由を記録して断る
+    // NOTE: DMのチャンネルIDはDで始まる。質問の本文は記録しない
+    let is_direct_message = slash_command.channel_id.starts_with('D');
+    if let Err(reason) = parameters.policy.check(
+        &[&slash_command.user_id],
+        &slash_command.channel_id,
+        is_direct_message,
+    ) {
+        println!(
+            "Denied by policy: {}, channel={}, user={}",
+            reason, slash_command.channel_id, slash_command.user_id
+        );
+        return POLICY_DENIED_MESSAGE.to_string();
+    }
+
+    match slash_command.action() {
+        SlashCommandAction::Help => SLASH_COMMAND_HELP_MESSAGE.to_string(),
+        // チャンネルの設定とデフォルトの人格を反映したモデル
+        SlashCommandAction::Model => {
+            match get_channel_enviroment_variable(parameters, Some(&slash_command.channel_id)) {
+                Ok(env_vars) => {
+                    let persona = resolve_persona(parameters, Some(&slash_command.channel_id), &[]);
+                    format!("使用中のモデルは `{}` ですにゃ。", persona.model(&env_vars))
+                }
+                Err(e) => {
+                    eprintln!("Error: {}", e);
+                    ERROR_MESSAGE.to_string()
+                }
+            }
+        }
+        // 今月のユーザーごとの使用量と料金
+        SlashCommandAction::Usage => {
+            let month = today()[..7].to_string();
+            match usage_store.records(&month).await {
+                Ok(records) => format_usage_report(&records, parameters, &month),
+                Err(e) => {
+                    eprintln!("Error: {}", e);
+                    ERROR_MESSAGE.to_string()
+                }
+            }
+        }
+        SlashCommandAction::Prompt(_) => {
+            // 使用量の制限を超えている場合は、チャンネルに投稿せず実行者にのみ知らせる
+            // NOTE: 制限はここで確認し、workerでは確認しない
+            if let Some(message) = quota_exceeded_message(
+                parameters,
+                &slash_command.user_id,
+                &slash_command.channel_id,
+                usage_store,
+            )
+            .await
+            {
+                return message;
+            }
+            // 返答の生成はworkerに任せる
+            match event_queue
+                .enqueue(QueuedJob::SlashCommand(slash_command))
+                .await
+            {
+                Ok(_) => SLASH_COMMAND_ACCEPTED_MESSAGE.to_string(),
+                Err(e) => {
+                    eprintln!("Error: {}", e);
+                    ERROR_MESSAGE.to_string()
+                }
+            }
+        }
+    }
+}
+
+// スラッシュコマンドの質問への返答をチャンネルに投稿する
+// NOTE: 受け付けた旨は返しているため、失敗した場合はresponse_urlで実行者にのみ知らせる
+pub async fn handle_slash_command(
+    slash_command: SlashCommand,
+    parameters: Parameters,
+    usage_store: &dyn UsageStore,
+) -> Result<()> {
+    let prompt = match slash_command.action() {
+        SlashCommandAction::Prompt(prompt) => prompt,
+        _ => return Ok(()),
+    };
+    let result = reply_to_slash_command(&slash_command, &prompt, &parameters, usage_store).await;
+    if let (Err(_), Some(response_url)) = (&result, &slash_command.response_url) {
+        ApiClient::new(&parameters, &slash_command.channel_id)
+            .post_ephemeral_response(response_url, ERROR_MESSAGE)
+            .await
+            .unwrap_or_else(|e| {
+                eprintln!("Error: {}", e);
+            });
+    }
+    result
+}
+
+async fn reply_to_slash_command(
+    slash_command: &SlashCommand,
+    prompt: &str,
+    parameters: &Parameters,
+    usage_store: &dyn UsageStore,
+) -> Result<()> {
+    let channel = slash_command.channel_id.clone();
+    let trigger_message = slash_command.to_slack_message(prompt);
+
+    let latest_ts = trigger_message.ts.clone();
+    let (request_body, _) =
+        create_request_body_from_contexts(vec![trigger_message], &latest_ts, None, parameters)
+            .await?;
+
+    let api_client = ApiClient::new(parameters, &channel);
+
+    // 何への返答か分かるよう、質問を投稿してそのスレッドに返答する
+    let question_ts = api_client
+        .post_message(&channel, &slash_command.question_text(prompt), None)
+        .await?;
+    let bot_message_ts = api_client
+        .post_message(&channel, LOADING_EMOJI, Some(&question_ts))
+        .await?;
+
+    // ChatGPTからのresponseを取得し、ストリームを処理
+    // NOTE: 長い返答の続きも質問のスレッドに投稿する
+    let tools = tools_from_env(&api_client, &slash_command.user_id, &parameters.policy)?;
+    let mut request_body = request_body;
+    request_body.set_tools(tools.definitions());
+    let model = request_body.model().to_string();
+    // NOTE: エラーで中断した場合も、それまでの使用量を記録する
+    let mut usage = ChatGptUsage::default();
+    let result = handle_chat_gpt_response(
+        request_body,
+        api_client,
+        &mut vec![bot_message_ts],
+        Some(question_ts.as_str()),
+        &tools,
+        &mut usage,
+    )
+    .await;
+    record_usage(
+        usage_store,
+        &slash_command.user_id,
+        &channel,
+        &model,
+        &usage,
+    )
+    .await;
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::slack_post_handler::event_queue::LocalEventQueue;
+    use crate::slack_post_handler::usage_ledger::InMemoryUsageStore;
+
+    #[test]
+    fn test_slash_command_action() {
+        let body = "command=%2Fcatgpt&text=%E7%8C%AB%E3%81%A8%E3%81%AF%EF%BC%9F&user_id=U2147483697&channel_id=C2147483705&response_url=https%3A%2F%2Fhooks.slack.com%2Fcommands%2F1234%2F5678";
+        let slash_command: SlashCommand = serde_urlencoded::from_str(body).unwrap();
+        assert_eq!(
+            slash_command.action(),
+            SlashCommandAction::Prompt("猫とは？".into())
+        );
+
+        let help = SlashCommand {
+            text: " help ".into(),
+            ..slash_command.clone()
+        };
+        assert_eq!(help.action(), SlashCommandAction::Help);
+
+        let model = SlashCommand {
+            text: "model".into(),
+            ..slash_command.clone()
+        };
+        assert_eq!(model.action(), SlashCommandAction::Model);
+
+        let usage = SlashCommand {
+            text: "usage".into(),
+            ..slash_command
+        };
+        assert_eq!(usage.action(), SlashCommandAction::Usage);
+    }
+
+    #[test]
+    fn test_question_text() {
+        let slash_command = SlashCommand {
+            command: "/catgpt".into(),
+            text: "猫とは？\n簡潔に".into(),
+            user_id: "U01".into(),
+            channel_id: "C01".into(),
+            response_url: None,
+        };
+        assert_eq!(
+            slash_command.question_text(&slash_command.text),
+            "<@U01>さんからの質問ですにゃ。\n> 猫とは？\n> 簡潔に"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_policy_applies_to_all_actions() {
+        let parameters: Parameters = serde_json::from_value(serde_json::json!({
+            "bot_member_id": "UBOT",
+            "slack_auth_token": "xoxb",
+            "openai_secret_key": "sk",
+            "slack_signing_secret": "secret",
+            "policy": {"denied_channels": ["C01"]},
+        }))
+        .unwrap();
+        let (event_queue, mut receiver) = LocalEventQueue::new();
+        let usage_store = InMemoryUsageStore::default();
+
+        for text in ["help", "model", "usage", "%E7%8C%AB%E3%81%A8%E3%81%AF"] {
+            let body = format!("command=%2Fcatgpt&text={}&user_id=U01&channel_id=C01", text);
+            let res =
+                handle_slash_command_request(&body, &parameters, &event_queue, &usage_store).await;
+            assert_eq!(res, POLICY_DENIED_MESSAGE);
+        }
+        assert!(receiver.try_recv().is_err());
+    }
+}
@@ -0,0 +1,259 @@
+use std::collections::VecDeque;
+
+use futures::{stream, Stream, StreamExt};
+
+// https://html.spec.whatwg.org/multipage/server-sent-events.html#event-stream-interpretation
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct SseEvent {
+    pub event: Option<String>,
+    pub data: String,
+    pub id: Option<String>,
+}
+
+// chunk単位で届くバイト列をServer-Sent Eventsのイベントに分割する
+// NOTE: 行やUTF-8の文字がchunkの途中で途切れていても、次のchunkと結合してから解釈する
+#[derive(Debug, Default)]
+pub struct SseDecoder {
+    buffer: Vec<u8>,
+    event: Option<String>,
+    data_lines: Vec<String>,
+    id: Option<String>,
+}
+
+impl SseDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // chunkを追加し、完成したイベントを返す
+    pub fn push(&mut self, chunk: &[u8]) -> Vec<SseEvent> {
+        self.buffer.extend_from_slice(chunk);
+
+        let mut events = Vec::new();
+        let mut start = 0;
+        let mut i = 0;
+        while i < self.buffer.len() {
+            let line_end = match self.buffer[i] {
+                b'\n' => i + 1,
+                b'\r' => {
+                    // \r\nが次のchunkにまたがる可能性があるため、次の文字が届くまで待つ
+                    if i + 1 == self.buffer.len() {
+                        break;
+                    }
+                    if self.buffer[i + 1] == b'\n' {
+                        i + 2
+                    } else {
+                        i + 1
+                    }
+                }
+                _ => {
+                    i += 1;
+                    continue;
+                }
+            };
+            let line = String::from_utf8_lossy(&self.buffer[start..i]).into_owned();
+            if let Some(event) = self.process_line(&line) {
+                events.push(event);
+            }
+            start = line_end;
+            i = line_end;
+        }
+        self.buffer.drain(..start);
+        events
+    }
+
+    // ストリームの終端で、\rの次の文字を待っていた行を確定する
+    // NOTE: 空行で終わっていない末尾のイベントは不完全なため破棄する
+    pub fn finish(&mut self) -> Option<SseEvent> {
+        let rest = std::mem::take(&mut self.buffer);
+        let event = match rest.strip_suffix(b"\r") {
+            Some(line) => self.process_line(&String::from_utf8_lossy(line)),
+            None => None,
+        };
+        self.event = None;
+        self.data_lines.clear();
+        event
+    }
+
+    fn process_line(&mut self, line: &str) -> Option<SseEvent> {
+        // 空行でイベントを確定する
+        if line.is_empty() {
+            return self.dispatch();
+        }
+        // :から始まる行はコメント
+        if line.starts_with(':') {
+            return None;
+        }
+
+        let (field, value) = match line.split_once(':') {
+            Some((field, value)) => (field, value.strip_prefix(' ').unwrap_or(value)),
+            None => (line, ""),
+        };
+        match field {
+            "event" => self.event = Some(value.to_string()),
+            "data" => self.data_lines.push(value.to_string()),
+            "id" if !value.contains('\0') => self.id = Some(value.to_string()),
+            // retryやその他のフィールドは無視する
+            _ => {}
+        }
+        None
+    }
+
+    fn dispatch(&mut self) -> Option<SseEvent> {
+        let event = self.event.take();
+        if self.data_lines.is_empty() {
+            return None;
+        }
+        Some(SseEvent {
+            event,
+            data: std::mem::take(&mut self.data_lines).join("\n"),
+            id: self.id.clone(),
+        })
+    }
+}
+
+// バイト列のストリームをSseEventのストリームに変換する
+pub fn sse_events<S, B, E>(byte_stream: S) -> impl Stream<Item = Result<SseEvent, E>>
+where
+    S: Stream<Item = Result<B, E>>,
+    B: AsRef<[u8]>,
+{
+    let state = (
+        Box::pin(byte_stream),
+        SseDecoder::new(),
+        VecDeque::new(),
+        false,
+    );
+    stream::unfold(
+        state,
+        |(mut byte_stream, mut decoder, mut pending, mut finished)| async move {
+            loop {
+                if let Some(event) = pending.pop_front() {
+                    return Some((Ok(event), (byte_stream, decoder, pending, finished)));
+                }
+                if finished {
+                    return None;
+                }
+                match byte_stream.next().await {
+                    Some(Ok(chunk)) => pending.extend(decoder.push(chunk.as_ref())),
+                    Some(Err(e)) => {
+                        finished = true;
+                        return Some((Err(e), (byte_stream, decoder, pending, finished)));
+                    }
+                    None => {
+                        finished = true;
+                        pending.extend(decoder.finish());
+                    }
+                }
+            }
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn decode_all(chunks: &[&[u8]]) -> Vec<SseEvent> {
+        let mut decoder = SseDecoder::new();
+        let mut events: Vec<SseEvent> = chunks.iter().flat_map(|c| decoder.push(c)).collect();
+        events.extend(decoder.finish());
+        events
+    }
+
+    #[test]
+    fn test_decode_fields() {
+        let input = b": comment\nevent: message_start\ndata: line1\ndata:line2\nid: 1\nretry: 1000\n\ndata: [DONE]\n\n";
+        let events = decode_all(&[input]);
+        assert_eq!(
+            events,
+            vec![
+                SseEvent {
+                    event: Some("message_start".into()),
+                    data: "line1\nline2".into(),
+                    id: Some("1".into()),
+                },
+                SseEvent {
+                    event: None,
+                    data: "[DONE]".into(),
+                    id: Some("1".into()),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_decode_split_utf8_and_crlf() {
+        let input = "data: にゃ\r\n\r\n".as_bytes();
+        // マルチバイト文字と\r\nの途中で分割する
+        let events = decode_all(&[&input[..8], &input[8..13], &input[13..]]);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].data, "にゃ");
+    }
+
+    #[test]
+    fn test_discard_incomplete_event() {
+        // 空行で終わっていない末尾のイベントは破棄する
+        let events = decode_all(&[b"data: first\n\ndata: second\n"]);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].data, "first");
+        let events = decode_all(&[b"data: first\n\ndata: second"]);
+        assert_eq!(events.len(), 1);
+
+        // \rで終わる場合は空行として扱う
+        let events = decode_all(&[b"data: first\r\r"]);
+        assert_eq!(events.len(), 1);
+    }
+
+    fn encode(events: &[(Option<String>, Vec<String>)], newline: &str) -> Vec<u8> {
+        let mut out = String::new();
+        for (event, data_lines) in events {
+            if let Some(event) = event {
+                out.push_str(&format!("event: {}{}", event, newline));
+            }
+            for line in data_lines {
+                out.push_str(&format!("data: {}{}", line, newline));
+            }
+            out.push_str(newline);
+        }
+        out.into_bytes()
+    }
+
+    proptest! {
+        // どこでchunkが分割されても同じイベント列になる
+        #[test]
+        fn test_decode_arbitrary_chunk_splits(
+            events in prop::collection::vec(
+                (
+                    prop::option::of("[a-z_]{1,10}"),
+                    prop::collection::vec("[^\r\n]{0,20}", 1..4),
+                ),
+                0..8,
+            ),
+            newline in prop::sample::select(vec!["\n", "\r\n", "\r"]),
+            splits in prop::collection::vec(any::<prop::sample::Index>(), 0..10),
+        ) {
+            let input = encode(&events, newline);
+            let mut split_points: Vec<usize> = splits.iter().map(|s| s.index(input.len() + 1)).collect();
+            split_points.sort();
+            let mut chunks: Vec<&[u8]> = Vec::new();
+            let mut start = 0;
+            for point in split_points {
+                chunks.push(&input[start..point]);
+                start = point;
+            }
+            chunks.push(&input[start..]);
+
+            let expected: Vec<SseEvent> = events
+                .iter()
+                .map(|(event, data_lines)| SseEvent {
+                    event: event.clone(),
+                    data: data_lines.join("\n"),
+                    id: None,
+                })
+                .collect();
+            prop_assert_eq!(decode_all(&chunks), expected);
+        }
+    }
+}
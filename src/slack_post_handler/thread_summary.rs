@@ -0,0 +1,181 @@
+use std::collections::HashMap;
+use std::sync::{Mutex, OnceLock};
+
+use anyhow::Result;
+
+use crate::constants::{SUMMARY_CACHE_MAX_ENTRIES, SUMMARY_MAX_TOKENS};
+
+use super::api_client::ApiClient;
+use super::chat_gpt_query::ChatGptQuery;
+use super::chat_gpt_res_body::ChatGptUsage;
+use super::context_budget::ContextBudget;
+use super::handle_request::{ChatGptReqBody, Parameters};
+use super::slack_message::SlackMessage;
+
+// 要約の対象となるスレッド
+pub struct SummaryTarget {
+    pub channel: String,
+    pub thread_ts: String,
+    // 件数の上限(past)を超えた古いメッセージ
+    pub older_messages: Vec<SlackMessage>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct CachedSummary {
+    // どのメッセージまで要約したか
+    summarized_until: String,
+    summary: String,
+}
+
+// "チャンネル:thread_ts"ごとの要約
+// NOTE: workerのプロセスが起動している間だけ保持する
+// Lambdaのコールドスタート時や、別のプロセスで処理した場合は要約し直す
+fn summary_cache() -> &'static Mutex<HashMap<String, CachedSummary>> {
+    static CACHE: OnceLock<Mutex<HashMap<String, CachedSummary>>> = OnceLock::new();
+    CACHE.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+// NOTE: tsはチャンネル内でのみ一意のため、チャンネルと組み合わせる
+fn summary_cache_key(channel: &str, thread_ts: &str) -> String {
+    format!("{}:{}", channel, thread_ts)
+}
+
+fn ts_to_f64(ts: &str) -> f64 {
+    ts.parse::<f64>().unwrap_or(0.0)
+}
+
+// 要約済みの部分を除いた、新たに要約するメッセージを返す
+// NOTE: キャッシュが今回の範囲より先まで要約している場合は使わずに要約し直す
+fn split_by_cache<'a>(
+    cached: Option<&'a CachedSummary>,
+    messages: &'a [SlackMessage],
+) -> (Option<&'a str>, Vec<&'a SlackMessage>) {
+    let last_ts = messages.last().map(|m| ts_to_f64(&m.ts)).unwrap_or(0.0);
+    match cached {
+        Some(cached) if ts_to_f64(&cached.summarized_until) <= last_ts => {
+            let until = ts_to_f64(&cached.summarized_until);
+            let new_messages = messages
+                .iter()
+                .filter(|m| ts_to_f64(&m.ts) > until)
+                .collect();
+            (Some(cached.summary.as_str()), new_messages)
+        }
+        _ => (None, messages.iter().collect()),
+    }
+}
+
+// 要約に渡す会話の書き起こし
+fn transcript(
+    previous_summary: Option<&str>,
+    messages: &[&SlackMessage],
+    bot_member_id: &str,
+) -> String {
+    let mut lines = vec![];
+    if let Some(summary) = previous_summary {
+        lines.push(format!("Previous summary:\n{}\n", summary));
+    }
+    lines.push("Messages:".to_string());
+    for message in messages {
+        let speaker = if message.is_from(bot_member_id) {
+            "assistant"
+        } else {
+            "user"
+        };
+        lines.push(format!("{}: {}", speaker, message.pure_text()));
+    }
+    lines.join("\n")
+}
+
+// スレッドの古いメッセージ(時系列順)を要約し、要約と要約に使った使用量を返す
+// NOTE: 前回の要約がある場合は、その後のメッセージだけを追加して要約し直す
+//       キャッシュした要約をそのまま使う場合、使用量は0
+pub async fn summarize_thread(
+    channel: &str,
+    thread_ts: &str,
+    messages: &[SlackMessage],
+    parameters: &Parameters,
+    budget: &ContextBudget,
+    model: &str,
+    provider: Option<&str>,
+) -> Result<Option<(String, ChatGptUsage)>> {
+    let Some(last) = messages.last() else {
+        return Ok(None);
+    };
+
+    let cache_key = summary_cache_key(channel, thread_ts);
+    let cached = summary_cache().lock().unwrap().get(&cache_key).cloned();
+    let (previous_summary, new_messages) = split_by_cache(cached.as_ref(), messages);
+    if new_messages.is_empty() {
+        return Ok(previous_summary.map(|s| (s.to_string(), ChatGptUsage::default())));
+    }
+
+    // 書き起こしが長すぎる場合は切り詰める
+    let request_messages = budget.fit(
+        ChatGptQuery::new_summary_prompt(),
+        vec![ChatGptQuery::new_user_text(transcript(
+            previous_summary,
+            &new_messages,
+            &parameters.bot_member_id,
+        ))],
+    );
+    let mut request_body = ChatGptReqBody::new(request_messages, model.to_string(), 0.0, false);
+    request_body.set_provider(provider.map(|p| p.to_string()));
+    let api_client = ApiClient::new(parameters, "");
+    let (summary, usage) = api_client.get_chat_gpt_completion(&request_body).await?;
+    let summary = budget.truncate_text(&summary, SUMMARY_MAX_TOKENS);
+
+    let mut cache = summary_cache().lock().unwrap();
+    if cache.len() >= SUMMARY_CACHE_MAX_ENTRIES && !cache.contains_key(&cache_key) {
+        cache.clear();
+    }
+    cache.insert(
+        cache_key,
+        CachedSummary {
+            summarized_until: last.ts.clone(),
+            summary: summary.clone(),
+        },
+    );
+    Ok(Some((summary, usage)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(ts: &str, text: &str) -> SlackMessage {
+        SlackMessage {
+            ts: ts.to_string(),
+            text: text.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_split_by_cache() {
+        let messages = vec![
+            message("1.0", "a"),
+            message("2.0", "b"),
+            message("3.0", "c"),
+        ];
+
+        // キャッシュがない場合はすべて要約する
+        let (previous, new_messages) = split_by_cache(None, &messages);
+        assert_eq!(previous, None);
+        assert_eq!(new_messages.len(), 3);
+
+        // 要約済みの部分は除く
+        let cached = CachedSummary {
+            summarized_until: "2.0".to_string(),
+            summary: "summary".to_string(),
+        };
+        let (previous, new_messages) = split_by_cache(Some(&cached), &messages);
+        assert_eq!(previous, Some("summary"));
+        assert_eq!(new_messages.len(), 1);
+        assert_eq!(new_messages[0].text, "c");
+
+        // キャッシュが今回の範囲より先まで要約している場合は使わない
+        let (previous, new_messages) = split_by_cache(Some(&cached), &messages[..1]);
+        assert_eq!(previous, None);
+        assert_eq!(new_messages.len(), 1);
+    }
+}
@@ -0,0 +1,63 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::constants::USAGE_DAY_OFFSET_SECS;
+
+// unix時刻をISO 8601形式にする
+// NOTE: 日付の計算は https://howardhinnant.github.io/date_algorithms.html#civil_from_days
+pub fn format_time(unix_secs: i64, offset_secs: i64) -> String {
+    let local_secs = unix_secs + offset_secs;
+    let days = local_secs.div_euclid(86400);
+    let secs_of_day = local_secs.rem_euclid(86400);
+
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    // 1970-01-01は木曜日
+    let weekday = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"][days.rem_euclid(7) as usize];
+
+    let offset_sign = if offset_secs < 0 { '-' } else { '+' };
+    let offset_abs = offset_secs.abs();
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}{}{:02}:{:02} ({})",
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        secs_of_day % 3600 / 60,
+        secs_of_day % 60,
+        offset_sign,
+        offset_abs / 3600,
+        offset_abs % 3600 / 60,
+        weekday
+    )
+}
+
+// 使用量を集計する日付
+// NOTE: 日本時間の0時で日付を区切る
+pub fn today() -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0);
+    format_time(now, USAGE_DAY_OFFSET_SECS)[..10].to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_time() {
+        assert_eq!(format_time(0, 0), "1970-01-01T00:00:00+00:00 (Thu)");
+        assert_eq!(
+            format_time(1709218800, 9 * 3600),
+            "2024-03-01T00:00:00+09:00 (Fri)"
+        );
+    }
+}
@@ -0,0 +1,174 @@
+use std::collections::BTreeMap;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::constants::TOOL_RESULT_MAX_CHARS;
+
+use super::chat_gpt_res_body::ToolCallDelta;
+
+// ChatGPTから呼び出せる機能
+#[async_trait]
+pub trait Tool: Send + Sync {
+    fn name(&self) -> &str;
+    fn description(&self) -> &str;
+    // 引数のJSON Schema
+    fn parameters(&self) -> Value;
+    async fn execute(&self, arguments: Value) -> Result<String>;
+}
+
+// リクエストに含めるtoolの定義
+#[derive(Serialize, Debug, Clone)]
+pub struct ToolDefinition {
+    #[serde(rename = "type")]
+    type_name: &'static str,
+    function: FunctionDefinition,
+}
+
+#[derive(Serialize, Debug, Clone)]
+struct FunctionDefinition {
+    name: String,
+    description: String,
+    parameters: Value,
+}
+
+// ChatGPTからのtoolの呼び出し
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct ToolCall {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub type_name: String,
+    pub function: FunctionCall,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct FunctionCall {
+    pub name: String,
+    // JSON文字列
+    pub arguments: String,
+}
+
+// ストリーミングで断片的に届くtool_callsを組み立てる
+#[derive(Default)]
+pub struct ToolCallAccumulator {
+    calls: BTreeMap<usize, ToolCall>,
+}
+
+impl ToolCallAccumulator {
+    pub fn push(&mut self, deltas: Vec<ToolCallDelta>) {
+        for delta in deltas {
+            let call = self.calls.entry(delta.index).or_insert_with(|| ToolCall {
+                type_name: "function".into(),
+                ..Default::default()
+            });
+            if let Some(id) = delta.id {
+                call.id.push_str(&id);
+            }
+            if let Some(function) = delta.function {
+                if let Some(name) = function.name {
+                    call.function.name.push_str(&name);
+                }
+                if let Some(arguments) = function.arguments {
+                    call.function.arguments.push_str(&arguments);
+                }
+            }
+        }
+    }
+
+    pub fn finish(self) -> Vec<ToolCall> {
+        self.calls.into_values().collect()
+    }
+}
+
+#[derive(Default)]
+pub struct ToolRegistry {
+    tools: Vec<Box<dyn Tool>>,
+}
+
+impl ToolRegistry {
+    pub fn register(&mut self, tool: Box<dyn Tool>) {
+        self.tools.push(tool);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tools.is_empty()
+    }
+
+    pub fn definitions(&self) -> Vec<ToolDefinition> {
+        self.tools
+            .iter()
+            .map(|tool| ToolDefinition {
+                type_name: "function",
+                function: FunctionDefinition {
+                    name: tool.name().to_string(),
+                    description: tool.description().to_string(),
+                    parameters: tool.parameters(),
+                },
+            })
+            .collect()
+    }
+
+    // toolを実行して結果を返す
+    // NOTE: 失敗した場合もエラー内容をChatGPTに返して、返答を続けさせる
+    pub async fn execute(&self, call: &ToolCall) -> String {
+        let Some(tool) = self.tools.iter().find(|t| t.name() == call.function.name) else {
+            return format!("Error: unknown tool `{}`", call.function.name);
+        };
+        let arguments = if call.function.arguments.trim().is_empty() {
+            Value::Object(Default::default())
+        } else {
+            match serde_json::from_str(&call.function.arguments) {
+                Ok(val) => val,
+                Err(e) => return format!("Error: invalid arguments: {}", e),
+            }
+        };
+
+        let result = match tool.execute(arguments).await {
+            Ok(val) => val,
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                format!("Error: {}", e)
+            }
+        };
+        // 長すぎる結果はコンテキストを圧迫するため切り詰める
+        match result.char_indices().nth(TOOL_RESULT_MAX_CHARS) {
+            Some((i, _)) => format!("{}...(truncated)", &result[..i]),
+            None => result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::slack_post_handler::chat_gpt_res_body::FunctionCallDelta;
+
+    fn delta(index: usize, id: Option<&str>, name: Option<&str>, arguments: &str) -> ToolCallDelta {
+        ToolCallDelta {
+            index,
+            id: id.map(|s| s.to_string()),
+            function: Some(FunctionCallDelta {
+                name: name.map(|s| s.to_string()),
+                arguments: Some(arguments.to_string()),
+            }),
+        }
+    }
+
+    #[test]
+    fn test_tool_call_accumulator() {
+        let mut accumulator = ToolCallAccumulator::default();
+        accumulator.push(vec![delta(0, Some("call_1"), Some("calculator"), "")]);
+        accumulator.push(vec![delta(0, None, None, "{\"expression\":")]);
+        accumulator.push(vec![delta(1, Some("call_2"), Some("current_time"), "{}")]);
+        accumulator.push(vec![delta(0, None, None, "\"1+2\"}")]);
+
+        let calls = accumulator.finish();
+        assert_eq!(calls.len(), 2);
+        assert_eq!(calls[0].id, "call_1");
+        assert_eq!(calls[0].function.name, "calculator");
+        assert_eq!(calls[0].function.arguments, "{\"expression\":\"1+2\"}");
+        assert_eq!(calls[1].function.name, "current_time");
+    }
+}
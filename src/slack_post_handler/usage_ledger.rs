@@ -0,0 +1,561 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use aws_config::BehaviorVersion;
+use aws_sdk_dynamodb::error::SdkError;
+use aws_sdk_dynamodb::types::AttributeValue;
+use aws_sdk_dynamodb::Client;
+use serde_derive::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::constants::{DEFAULT_MODEL_PRICES, USAGE_REPORT_MAX_USERS};
+
+use super::chat_gpt_res_body::ChatGptUsage;
+use super::handle_request::{get_enviroment_variable, Parameters};
+use super::time::today;
+
+// 1分あたりのリクエスト数の記録を保持する分数
+const REQUEST_COUNT_TTL_MINUTES: i64 = 10;
+
+#[derive(Error, Debug)]
+pub enum UsageStoreError {
+    #[error("DynamoDB error: {0}")]
+    DynamoDbError(String),
+    #[error("Invalid usage item: {0}")]
+    InvalidItem(String),
+}
+
+// ユーザー、チャンネル、モデル、日付ごとの使用量
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct UsageRecord {
+    // YYYY-MM-DD
+    pub date: String,
+    pub user: String,
+    pub channel: String,
+    pub model: String,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub requests: u64,
+}
+
+impl UsageRecord {
+    // 今日の1回分の使用量
+    pub fn new(user: &str, channel: &str, model: &str, usage: &ChatGptUsage) -> Self {
+        Self {
+            date: today(),
+            user: user.to_string(),
+            channel: channel.to_string(),
+            model: model.to_string(),
+            prompt_tokens: usage.prompt_tokens,
+            completion_tokens: usage.completion_tokens,
+            requests: 1,
+        }
+    }
+
+    fn is_same_key(&self, other: &UsageRecord) -> bool {
+        self.date == other.date
+            && self.user == other.user
+            && self.channel == other.channel
+            && self.model == other.model
+    }
+
+    fn add(&mut self, other: &UsageRecord) {
+        self.prompt_tokens += other.prompt_tokens;
+        self.completion_tokens += other.completion_tokens;
+        self.requests += other.requests;
+    }
+
+    pub fn total_tokens(&self) -> u64 {
+        self.prompt_tokens + self.completion_tokens
+    }
+
+    // 料金(USD)。料金が分からないモデルの場合はNone
+    pub fn cost(&self, parameters: &Parameters) -> Option<f64> {
+        model_price(parameters, &self.model).map(|price| {
+            (self.prompt_tokens as f64 * price.input_per_million
+                + self.completion_tokens as f64 * price.output_per_million)
+                / 1_000_000.0
+        })
+    }
+}
+
+// 100万トークンあたりの料金(USD)
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct ModelPrice {
+    pub input_per_million: f64,
+    pub output_per_million: f64,
+}
+
+// モデルの料金を探す
+// NOTE: "gpt-4o-2024-08-06"などの日付付きのモデルにも当てはまるよう、最も長く前方一致するものを使う
+//       パラメータのpricesを組み込みの料金より優先する
+pub fn model_price(parameters: &Parameters, model: &str) -> Option<ModelPrice> {
+    let defaults = DEFAULT_MODEL_PRICES.iter().map(|(name, input, output)| {
+        (
+            *name,
+            ModelPrice {
+                input_per_million: *input,
+                output_per_million: *output,
+            },
+        )
+    });
+    let configured = parameters
+        .prices
+        .iter()
+        .map(|(name, price)| (name.as_str(), *price));
+    defaults
+        .chain(configured)
+        .filter(|(name, _)| model.starts_with(name))
+        .max_by_key(|(name, _)| name.len())
+        .map(|(_, price)| price)
+}
+
+// 使用量を記録する
+#[async_trait]
+pub trait UsageStore: Send + Sync {
+    // 同じユーザー、チャンネル、モデル、日付の記録に加算する
+    async fn record(&self, record: &UsageRecord) -> Result<()>;
+    // 期間内の記録を返す。periodは月(YYYY-MM)か日(YYYY-MM-DD)
+    async fn records(&self, period: &str) -> Result<Vec<UsageRecord>>;
+    // ユーザーのminute(unix時刻/60)のリクエストを1回数える。上限に達している場合は数えずにfalseを返す
+    async fn acquire_request(&self, user: &str, minute: i64, limit: usize) -> Result<bool>;
+}
+
+// ユーザーごとの1分あたりのリクエスト数をプロセス内のメモリで数える
+// NOTE: ローカル実行・テスト用。Lambdaではworkerが複数起動するため、DynamoDBで数える
+#[derive(Default)]
+struct RequestCounter {
+    // ユーザーごとの数えている分とリクエスト数
+    counts: Mutex<HashMap<String, (i64, usize)>>,
+}
+
+impl RequestCounter {
+    fn acquire(&self, user: &str, minute: i64, limit: usize) -> bool {
+        let mut counts = self.counts.lock().unwrap();
+        // 過ぎた分の記録は削除する
+        counts.retain(|_, (counted_minute, _)| *counted_minute >= minute);
+        let (_, count) = counts.entry(user.to_string()).or_insert((minute, 0));
+        if *count >= limit {
+            return false;
+        }
+        *count += 1;
+        true
+    }
+}
+
+fn merge_record(records: &mut Vec<UsageRecord>, record: &UsageRecord) {
+    match records.iter_mut().find(|r| r.is_same_key(record)) {
+        Some(existing) => existing.add(record),
+        None => records.push(record.clone()),
+    }
+}
+
+// プロセス内のメモリに記録する(ローカル実行・テスト用)
+#[derive(Default)]
+pub struct InMemoryUsageStore {
+    records: Mutex<Vec<UsageRecord>>,
+    requests: RequestCounter,
+}
+
+#[async_trait]
+impl UsageStore for InMemoryUsageStore {
+    async fn record(&self, record: &UsageRecord) -> Result<()> {
+        merge_record(&mut self.records.lock().unwrap(), record);
+        Ok(())
+    }
+
+    async fn records(&self, period: &str) -> Result<Vec<UsageRecord>> {
+        let records = self.records.lock().unwrap();
+        Ok(records
+            .iter()
+            .filter(|r| r.date.starts_with(period))
+            .cloned()
+            .collect())
+    }
+
+    async fn acquire_request(&self, user: &str, minute: i64, limit: usize) -> Result<bool> {
+        Ok(self.requests.acquire(user, minute, limit))
+    }
+}
+
+// JSONファイルに記録する(ローカル実行用)
+pub struct FileUsageStore {
+    path: PathBuf,
+    // NOTE: 同じプロセス内での読み書きが重ならないようにする
+    lock: tokio::sync::Mutex<()>,
+    requests: RequestCounter,
+}
+
+impl FileUsageStore {
+    pub fn new(path: &str) -> Self {
+        Self {
+            path: path.into(),
+            lock: tokio::sync::Mutex::new(()),
+            requests: RequestCounter::default(),
+        }
+    }
+
+    async fn load(&self) -> Result<Vec<UsageRecord>> {
+        match tokio::fs::read_to_string(&self.path).await {
+            Ok(content) => Ok(serde_json::from_str(&content)?),
+            // ファイルがない場合は記録なし
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(vec![]),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+#[async_trait]
+impl UsageStore for FileUsageStore {
+    async fn record(&self, record: &UsageRecord) -> Result<()> {
+        let _guard = self.lock.lock().await;
+        let mut records = self.load().await?;
+        merge_record(&mut records, record);
+        tokio::fs::write(&self.path, serde_json::to_string_pretty(&records)?).await?;
+        Ok(())
+    }
+
+    async fn records(&self, period: &str) -> Result<Vec<UsageRecord>> {
+        let _guard = self.lock.lock().await;
+        Ok(self
+            .load()
+            .await?
+            .into_iter()
+            .filter(|r| r.date.starts_with(period))
+            .collect())
+    }
+
+    async fn acquire_request(&self, user: &str, minute: i64, limit: usize) -> Result<bool> {
+        Ok(self.requests.acquire(user, minute, limit))
+    }
+}
+
+// DynamoDBのテーブルに記録する
+// パーティションキーはpk(S)に月(YYYY-MM)、ソートキーはsk(S)に"日付#ユーザー#チャンネル#モデル"
+// 1分あたりのリクエスト数は、pkに"requests#分"、skにユーザーを記録し、TTL属性expires_at(N)で削除する
+// endpoint_urlを指定するとDynamoDB Localなどに接続できる
+pub struct DynamoDbUsageStore {
+    client: Client,
+    table_name: String,
+}
+
+impl DynamoDbUsageStore {
+    pub async fn new(table_name: &str, endpoint_url: Option<&str>) -> Self {
+        let shared_config = aws_config::load_defaults(BehaviorVersion::v2023_11_09()).await;
+        let mut config_builder = aws_sdk_dynamodb::config::Builder::from(&shared_config);
+        if let Some(endpoint_url) = endpoint_url {
+            config_builder = config_builder.endpoint_url(endpoint_url);
+        }
+        Self {
+            client: Client::from_conf(config_builder.build()),
+            table_name: table_name.into(),
+        }
+    }
+}
+
+fn string_attribute(item: &HashMap<String, AttributeValue>, name: &str) -> Result<String> {
+    item.get(name)
+        .and_then(|v| v.as_s().ok())
+        .cloned()
+        .ok_or(UsageStoreError::InvalidItem(name.to_string()).into())
+}
+
+fn number_attribute(item: &HashMap<String, AttributeValue>, name: &str) -> Result<u64> {
+    item.get(name)
+        .and_then(|v| v.as_n().ok())
+        .and_then(|n| n.parse().ok())
+        .ok_or(UsageStoreError::InvalidItem(name.to_string()).into())
+}
+
+#[async_trait]
+impl UsageStore for DynamoDbUsageStore {
+    async fn record(&self, record: &UsageRecord) -> Result<()> {
+        let sort_key = format!(
+            "{}#{}#{}#{}",
+            record.date, record.user, record.channel, record.model
+        );
+        // NOTE: ADDで加算するため、同時に記録しても失われない
+        self.client
+            .update_item()
+            .table_name(&self.table_name)
+            .key("pk", AttributeValue::S(record.date[..7].to_string()))
+            .key("sk", AttributeValue::S(sort_key))
+            .update_expression(
+                "SET #date = :date, #user = :user, channel = :channel, model = :model \
+                 ADD prompt_tokens :prompt_tokens, completion_tokens :completion_tokens, requests :requests",
+            )
+            // NOTE: dateとuserは予約語のため名前を置き換える
+            .expression_attribute_names("#date", "date")
+            .expression_attribute_names("#user", "user")
+            .expression_attribute_values(":date", AttributeValue::S(record.date.clone()))
+            .expression_attribute_values(":user", AttributeValue::S(record.user.clone()))
+            .expression_attribute_values(":channel", AttributeValue::S(record.channel.clone()))
+            .expression_attribute_values(":model", AttributeValue::S(record.model.clone()))
+            .expression_attribute_values(
+                ":prompt_tokens",
+                AttributeValue::N(record.prompt_tokens.to_string()),
+            )
+            .expression_attribute_values(
+                ":completion_tokens",
+                AttributeValue::N(record.completion_tokens.to_string()),
+            )
+            .expression_attribute_values(":requests", AttributeValue::N(record.requests.to_string()))
+            .send()
+            .await
+            .map_err(|e| UsageStoreError::DynamoDbError(e.to_string()))?;
+        Ok(())
+    }
+
+    async fn records(&self, period: &str) -> Result<Vec<UsageRecord>> {
+        let mut records = vec![];
+        let mut exclusive_start_key = None;
+        loop {
+            let mut query = self
+                .client
+                .query()
+                .table_name(&self.table_name)
+                .expression_attribute_values(":pk", AttributeValue::S(period[..7].to_string()))
+                .set_exclusive_start_key(exclusive_start_key);
+            // 日の指定がある場合はソートキーで絞り込む
+            query = if period.len() > 7 {
+                query
+                    .key_condition_expression("pk = :pk AND begins_with(sk, :date)")
+                    .expression_attribute_values(":date", AttributeValue::S(period.to_string()))
+            } else {
+                query.key_condition_expression("pk = :pk")
+            };
+            let res = query
+                .send()
+                .await
+                .map_err(|e| UsageStoreError::DynamoDbError(e.to_string()))?;
+
+            for item in res.items() {
+                records.push(UsageRecord {
+                    date: string_attribute(item, "date")?,
+                    user: string_attribute(item, "user")?,
+                    channel: string_attribute(item, "channel")?,
+                    model: string_attribute(item, "model")?,
+                    prompt_tokens: number_attribute(item, "prompt_tokens")?,
+                    completion_tokens: number_attribute(item, "completion_tokens")?,
+                    requests: number_attribute(item, "requests")?,
+                });
+            }
+            exclusive_start_key = res.last_evaluated_key().cloned();
+            if exclusive_start_key.is_none() {
+                break;
+            }
+        }
+        Ok(records)
+    }
+
+    async fn acquire_request(&self, user: &str, minute: i64, limit: usize) -> Result<bool> {
+        // NOTE: 上限未満の場合のみ加算する条件付き書き込みのため、workerが複数あっても上限を超えない
+        let res = self
+            .client
+            .update_item()
+            .table_name(&self.table_name)
+            .key("pk", AttributeValue::S(format!("requests#{}", minute)))
+            .key("sk", AttributeValue::S(user.to_string()))
+            .update_expression("ADD requests :one SET expires_at = :expires_at")
+            .condition_expression("attribute_not_exists(requests) OR requests < :limit")
+            .expression_attribute_values(":one", AttributeValue::N("1".into()))
+            .expression_attribute_values(":limit", AttributeValue::N(limit.to_string()))
+            .expression_attribute_values(
+                ":expires_at",
+                AttributeValue::N(((minute + REQUEST_COUNT_TTL_MINUTES) * 60).to_string()),
+            )
+            .send()
+            .await;
+
+        match res {
+            Ok(_) => Ok(true),
+            // 上限に達している場合は条件付き書き込みが失敗する
+            Err(SdkError::ServiceError(e)) if e.err().is_conditional_check_failed_exception() => {
+                Ok(false)
+            }
+            Err(e) => Err(UsageStoreError::DynamoDbError(e.to_string()).into()),
+        }
+    }
+}
+
+// 環境変数usage_table_nameがあればDynamoDB、usage_file_pathがあればファイル、なければメモリに記録する
+pub async fn usage_store_from_env() -> Result<Box<dyn UsageStore>> {
+    let env_vars = get_enviroment_variable()?;
+    let store: Box<dyn UsageStore> = match (env_vars.usage_table_name, env_vars.usage_file_path) {
+        (Some(table_name), _) => Box::new(
+            DynamoDbUsageStore::new(&table_name, env_vars.usage_table_endpoint.as_deref()).await,
+        ),
+        (None, Some(file_path)) => Box::new(FileUsageStore::new(&file_path)),
+        (None, None) => Box::<InMemoryUsageStore>::default(),
+    };
+    Ok(store)
+}
+
+// 返答1回分の使用量を記録する
+// NOTE: 記録に失敗しても返答には影響させない
+pub async fn record_usage(
+    usage_store: &dyn UsageStore,
+    user: &str,
+    channel: &str,
+    model: &str,
+    usage: &ChatGptUsage,
+) {
+    let record = UsageRecord::new(user, channel, model, usage);
+    if let Err(e) = usage_store.record(&record).await {
+        eprintln!("Error: {}", e);
+    }
+}
+
+// `/catgpt usage`の集計結果
+pub fn format_usage_report(
+    records: &[UsageRecord],
+    parameters: &Parameters,
+    month: &str,
+) -> String {
+    if records.is_empty() {
+        return format!("{}はまだ誰も使っていませんにゃ。", month);
+    }
+
+    // ユーザーごとに集計する
+    let mut users: Vec<(String, f64, u64, u64)> = vec![];
+    let mut unknown_models: Vec<&str> = vec![];
+    for record in records {
+        let cost = record.cost(parameters).unwrap_or_else(|| {
+            if !unknown_models.contains(&record.model.as_str()) {
+                unknown_models.push(&record.model);
+            }
+            0.0
+        });
+        match users.iter_mut().find(|(user, ..)| user == &record.user) {
+            Some((_, total_cost, tokens, requests)) => {
+                *total_cost += cost;
+                *tokens += record.total_tokens();
+                *requests += record.requests;
+            }
+            None => users.push((
+                record.user.clone(),
+                cost,
+                record.total_tokens(),
+                record.requests,
+            )),
+        }
+    }
+    users.sort_by(|a, b| b.1.total_cmp(&a.1).then(b.2.cmp(&a.2)));
+
+    let total_cost: f64 = users.iter().map(|u| u.1).sum();
+    let total_tokens: u64 = users.iter().map(|u| u.2).sum();
+    let total_requests: u64 = users.iter().map(|u| u.3).sum();
+    let mut lines = vec![
+        format!("{}の使用量ですにゃ。", month),
+        format!(
+            "合計: ${:.2} ({} tokens, {}回)",
+            total_cost, total_tokens, total_requests
+        ),
+    ];
+    lines.extend(users.iter().take(USAGE_REPORT_MAX_USERS).map(
+        |(user, cost, tokens, requests)| {
+            format!(
+                "• <@{}>: ${:.2} ({} tokens, {}回)",
+                user, cost, tokens, requests
+            )
+        },
+    ));
+    if !unknown_models.is_empty() {
+        lines.push(format!(
+            "料金が分からないモデルは合計に含めていませんにゃ: {}",
+            unknown_models.join(", ")
+        ));
+    }
+    lines.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_parameters() -> Parameters {
+        serde_json::from_value(serde_json::json!({
+            "bot_member_id": "UBOT",
+            "slack_auth_token": "xoxb",
+            "openai_secret_key": "sk",
+            "slack_signing_secret": "secret",
+            "prices": {
+                "gpt-4o": {"input_per_million": 5.0, "output_per_million": 20.0},
+            },
+        }))
+        .unwrap()
+    }
+
+    fn record(user: &str, model: &str, prompt_tokens: u64, completion_tokens: u64) -> UsageRecord {
+        UsageRecord {
+            date: "2024-03-01".into(),
+            user: user.into(),
+            channel: "C01".into(),
+            model: model.into(),
+            prompt_tokens,
+            completion_tokens,
+            requests: 1,
+        }
+    }
+
+    #[test]
+    fn test_model_price() {
+        let parameters = test_parameters();
+        // パラメータの料金を優先する
+        assert_eq!(
+            model_price(&parameters, "gpt-4o-2024-08-06").map(|p| p.input_per_million),
+            Some(5.0)
+        );
+        // 最も長く前方一致するものを使う
+        assert_eq!(
+            model_price(&parameters, "gpt-4o-mini-2024-07-18").map(|p| p.input_per_million),
+            Some(0.15)
+        );
+        assert_eq!(model_price(&parameters, "llama3.1"), None);
+    }
+
+    #[tokio::test]
+    async fn test_file_usage_store() {
+        let path = std::env::temp_dir().join(format!("cat-gpt-usage-{}.json", std::process::id()));
+        let store = FileUsageStore::new(path.to_str().unwrap());
+
+        store
+            .record(&record("U01", "gpt-4o", 100, 10))
+            .await
+            .unwrap();
+        store.record(&record("U01", "gpt-4o", 50, 5)).await.unwrap();
+        store.record(&record("U02", "gpt-4o", 1, 1)).await.unwrap();
+
+        let records = store.records("2024-03").await.unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].total_tokens(), 165);
+        assert_eq!(records[0].requests, 2);
+        assert!(store.records("2024-04").await.unwrap().is_empty());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_format_usage_report() {
+        let parameters = test_parameters();
+        let records = vec![
+            record("U01", "gpt-4o", 100_000, 0),
+            record("U02", "gpt-4o", 1_000_000, 100_000),
+            record("U01", "llama3.1", 1000, 1000),
+        ];
+        assert_eq!(
+            format_usage_report(&records, &parameters, "2024-03"),
+            "2024-03の使用量ですにゃ。\n\
+合計: $7.50 (1202000 tokens, 3回)\n\
+• <@U02>: $7.00 (1100000 tokens, 1回)\n\
+• <@U01>: $0.50 (102000 tokens, 2回)\n\
+料金が分からないモデルは合計に含めていませんにゃ: llama3.1"
+        );
+        assert_eq!(
+            format_usage_report(&[], &parameters, "2024-03"),
+            "2024-03はまだ誰も使っていませんにゃ。"
+        );
+    }
+}
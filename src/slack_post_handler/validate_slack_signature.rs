@@ -1,38 +1,91 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
 use hmac::{Hmac, Mac};
 use lambda_http::http::header::HeaderMap;
 use sha2::Sha256;
+use thiserror::Error;
+
+const SIGNATURE_HEADER: &str = "X-Slack-Signature";
+const TIMESTAMP_HEADER: &str = "X-Slack-Request-Timestamp";
+
+#[derive(Error, Debug, PartialEq)]
+pub enum SignatureError {
+    #[error("{0} missing")]
+    MissingHeader(&'static str),
+    #[error("{0} parse error")]
+    InvalidHeader(&'static str),
+    #[error("Request timestamp is out of range: {0}")]
+    StaleTimestamp(i64),
+    #[error("Invalid signature")]
+    InvalidSignature,
+}
 
 // https://api.slack.com/authentication/verifying-requests-from-slack
 pub fn validate_slack_signature(
     headers: &HeaderMap,
     body: &str,
-    slack_signing_secret: &str,
-) -> bool {
+    slack_signing_secrets: &[&str],
+    tolerance_secs: i64,
+) -> Result<(), SignatureError> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0);
+    validate_slack_signature_at(headers, body, slack_signing_secrets, tolerance_secs, now)
+}
+
+// 現在時刻(UNIX秒)を指定して検証する
+pub fn validate_slack_signature_at(
+    headers: &HeaderMap,
+    body: &str,
+    slack_signing_secrets: &[&str],
+    tolerance_secs: i64,
+    now: i64,
+) -> Result<(), SignatureError> {
     type HmacSha256 = Hmac<Sha256>;
-    let signature_header = "X-Slack-Signature";
-    let timestamp_header = "X-Slack-Request-Timestamp";
 
-    let signature = headers
-        .get(signature_header)
-        .expect(format!("{} missing", signature_header).as_str())
-        .to_str()
-        .expect(format!("{} parse error", signature_header).as_str());
-    let timestamp = headers
-        .get(timestamp_header)
-        .expect(format!("{} missing", timestamp_header).as_str())
-        .to_str()
-        .expect(format!("{} parse error", timestamp_header).as_str());
+    let signature = get_header(headers, SIGNATURE_HEADER)?;
+    let timestamp = get_header(headers, TIMESTAMP_HEADER)?;
+
+    // リプレイ攻撃を防ぐため、古いリクエストは拒否する
+    let timestamp_secs = timestamp
+        .parse::<i64>()
+        .map_err(|_| SignatureError::InvalidHeader(TIMESTAMP_HEADER))?;
+    // NOTE: 極端な値でも引き算が溢れないよう、符号なしで差を求める
+    if now.abs_diff(timestamp_secs) > u64::try_from(tolerance_secs).unwrap_or(0) {
+        return Err(SignatureError::StaleTimestamp(timestamp_secs));
+    }
+
+    let signature_bytes = signature
+        .strip_prefix("v0=")
+        .and_then(|s| hex::decode(s).ok())
+        .ok_or(SignatureError::InvalidSignature)?;
     let basestring = format!("v0:{}:{}", timestamp, body);
 
-    // Slack Signing SecretをkeyとしてbasestringをHMAC SHA256でhashにする
-    let mut mac = HmacSha256::new_from_slice(slack_signing_secret.as_bytes())
-        .expect("Invalid Slack Signing Secret");
-    mac.update(basestring.as_bytes());
-    let expected_signature = mac.finalize();
+    // Slack Signing SecretをkeyとしてbasestringをHMAC SHA256でhashにし、signatureと比較する
+    // NOTE: secretのローテーション中は新旧どちらのsecretでも受け付ける
+    let is_valid = slack_signing_secrets.iter().any(|secret| {
+        let Ok(mut mac) = HmacSha256::new_from_slice(secret.as_bytes()) else {
+            return false;
+        };
+        mac.update(basestring.as_bytes());
+        // verify_sliceは定数時間で比較する
+        mac.verify_slice(&signature_bytes).is_ok()
+    });
+
+    if is_valid {
+        Ok(())
+    } else {
+        Err(SignatureError::InvalidSignature)
+    }
+}
 
-    // expected_signatureとsignatureが一致するか確認する
-    let expected_signature_str = hex::encode(expected_signature.into_bytes());
-    return format!("v0={}", expected_signature_str) == signature;
+fn get_header<'a>(headers: &'a HeaderMap, name: &'static str) -> Result<&'a str, SignatureError> {
+    headers
+        .get(name)
+        .ok_or(SignatureError::MissingHeader(name))?
+        .to_str()
+        .map_err(|_| SignatureError::InvalidHeader(name))
 }
 
 #[cfg(test)]
@@ -40,28 +93,78 @@ mod tests {
     use super::*;
     use lambda_http::http::header::HeaderValue;
 
+    const SLACK_SIGNING_SECRET: &str = "1234567890abcdef1234567890abcdef";
+
+    fn test_headers() -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            SIGNATURE_HEADER,
+            HeaderValue::from_static(
+                "v0=32d48c53b8c4a93a2b3fc57d6b40b003650da2536b519015b670ac091eec00df",
+            ),
+        );
+        headers.insert(TIMESTAMP_HEADER, HeaderValue::from_static("1234567890"));
+        headers
+    }
+
     #[test]
     fn test_validate_slack_signature() {
-        let headers = {
-            let mut headers = HeaderMap::new();
-            headers.insert(
-                "X-Slack-Signature",
-                HeaderValue::from_static(
-                    "v0=32d48c53b8c4a93a2b3fc57d6b40b003650da2536b519015b670ac091eec00df",
-                ),
-            );
-            headers.insert(
-                "X-Slack-Request-Timestamp",
-                HeaderValue::from_static("1234567890"),
-            );
-            headers
-        };
+        let headers = test_headers();
         let body = "test";
-        let slack_signing_secret = "1234567890abcdef1234567890abcdef";
 
         assert_eq!(
-            validate_slack_signature(&headers, body, slack_signing_secret),
-            true
+            validate_slack_signature_at(&headers, body, &[SLACK_SIGNING_SECRET], 300, 1234567900),
+            Ok(())
+        );
+        // ローテーション中は新旧どちらのsecretでも受け付ける
+        assert_eq!(
+            validate_slack_signature_at(
+                &headers,
+                body,
+                &["new_secret", SLACK_SIGNING_SECRET],
+                300,
+                1234567900
+            ),
+            Ok(())
+        );
+        assert_eq!(
+            validate_slack_signature_at(&headers, body, &["wrong_secret"], 300, 1234567900),
+            Err(SignatureError::InvalidSignature)
+        );
+    }
+
+    #[test]
+    fn test_validate_slack_signature_stale_timestamp() {
+        let headers = test_headers();
+
+        assert_eq!(
+            validate_slack_signature_at(&headers, "test", &[SLACK_SIGNING_SECRET], 300, 1234568191),
+            Err(SignatureError::StaleTimestamp(1234567890))
+        );
+    }
+
+    #[test]
+    fn test_validate_slack_signature_extreme_timestamp() {
+        let mut headers = test_headers();
+        headers.insert(
+            TIMESTAMP_HEADER,
+            HeaderValue::from_str(&i64::MIN.to_string()).unwrap(),
+        );
+
+        assert_eq!(
+            validate_slack_signature_at(&headers, "test", &[SLACK_SIGNING_SECRET], 300, 1234567900),
+            Err(SignatureError::StaleTimestamp(i64::MIN))
+        );
+    }
+
+    #[test]
+    fn test_validate_slack_signature_missing_header() {
+        let mut headers = test_headers();
+        headers.remove(SIGNATURE_HEADER);
+
+        assert_eq!(
+            validate_slack_signature_at(&headers, "test", &[SLACK_SIGNING_SECRET], 300, 1234567900),
+            Err(SignatureError::MissingHeader(SIGNATURE_HEADER))
         );
     }
 }